@@ -1,70 +1,375 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Deref;
+use serde::{Deserialize, Serialize};
 use crate::token::Token;
 
-pub type Program = Vec<Statement>;
-pub type Block = Vec<Statement>;
+pub mod fold;
+pub mod print;
+pub mod visit;
+
+pub type Program = Vec<Spanned<Statement>>;
+pub type Block = Vec<Spanned<Statement>>;
 pub type Identifier = String;
 
-#[derive(Debug, Clone, PartialEq)]
+/// A range of bytes in the source, along with the line and column it starts at.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// The span running from the start of this one to the end of `end`.
+    pub fn to(self, end: Span) -> Self {
+        Self { end: end.end.max(self.end), ..self }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// A node along with where it came from in the source. Spans are ignored when
+/// comparing nodes, so ASTs built by hand compare equal to parsed ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Spanned<T> {
+    pub node: T,
+    /// Tools producing ASTs can leave spans out.
+    #[serde(default)]
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Self { node, span }
+    }
+
+    pub fn some(self) -> Option<Self> {
+        Some(self)
+    }
+
+    pub fn boxed(self) -> Box<Self> {
+        Box::new(self)
+    }
+}
+
+impl<T> From<T> for Spanned<T> {
+    fn from(node: T) -> Self {
+        Self::new(node, Span::default())
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+impl<T: PartialEq> PartialEq for Spanned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.node == other.node
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Parameter {
     pub name: String,
+    pub annotation: Option<Type>,
+    pub default: Option<Spanned<Expression>>,
+    pub variadic: bool,
+}
+
+impl Parameter {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            annotation: None,
+            default: None,
+            variadic: false,
+        }
+    }
+}
+
+impl Display for Parameter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.variadic {
+            write!(f, "...{}", self.name)
+        } else if self.default.is_some() {
+            write!(f, "[{}]", self.name)
+        } else {
+            write!(f, "{}", self.name)
+        }
+    }
+}
+
+/// `@name` or `@name(arguments)` before a function declaration. The function
+/// is passed to `name`, or to what `name(arguments)` returns, and replaced by
+/// the result.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attribute {
+    pub name: Identifier,
+    pub arguments: Vec<Argument>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Type {
+    Named(Identifier, Vec<Type>),
+    Union(Vec<Type>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Argument {
+    Positional(Spanned<Expression>),
+    Named(Identifier, Spanned<Expression>),
+    Spread(Spanned<Expression>),
+}
+
+/// A `for pattern in iterable` or `if condition` clause of a comprehension.
+/// Each clause runs inside the ones before it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Clause {
+    For(Pattern, Spanned<Expression>),
+    If(Spanned<Expression>),
+}
+
+/// What a `for` clause binds each item to: a name, or a parenthesised list of
+/// patterns that takes a list apart, as in `(key, value)`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Pattern {
+    Name(Identifier),
+    Tuple(Vec<Pattern>),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl Pattern {
+    /// Every name the pattern binds, from left to right.
+    pub fn names(&self) -> Vec<&Identifier> {
+        match self {
+            Pattern::Name(name) => vec![name],
+            Pattern::Tuple(patterns) => patterns.iter().flat_map(|pattern| pattern.names()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Statement {
     FunctionDeclaration {
+        /// The `///` comment written before the declaration.
+        doc: Option<String>,
+        attributes: Vec<Spanned<Attribute>>,
         name: Identifier,
-        params: Vec<Parameter>,
+        params: Vec<Spanned<Parameter>>,
+        return_type: Option<Type>,
         body: Block,
+        kind: FunctionKind,
     },
     LetDeclaration {
         name: Identifier,
-        initial: Option<Expression>,
+        annotation: Option<Type>,
+        initial: Option<Spanned<Expression>>,
+    },
+    ConstDeclaration {
+        doc: Option<String>,
+        name: Identifier,
+        annotation: Option<Type>,
+        value: Spanned<Expression>,
+    },
+    Import {
+        path: String,
+        binding: ImportBinding,
+    },
+    Export {
+        declaration: Box<Spanned<Statement>>,
+    },
+    For {
+        binding: Identifier,
+        iterable: Spanned<Expression>,
+        body: Block,
+    },
+    /// Runs the body for as long as the condition holds.
+    While {
+        condition: Spanned<Expression>,
+        body: Block,
+    },
+    Yield {
+        value: Spanned<Expression>,
+    },
+    /// Leaves the enclosing function with a value, or nil without one.
+    Return {
+        value: Option<Spanned<Expression>>,
+    },
+    /// Leaves the enclosing loop.
+    Break,
+    Throw {
+        value: Spanned<Expression>,
+    },
+    Try {
+        body: Block,
+        binding: Option<Identifier>,
+        catch: Option<Block>,
+        finally: Option<Block>,
+    },
+    /// `defer value`, which evaluates the value when the enclosing block exits,
+    /// however it exits. Deferred values run in the reverse order they were deferred.
+    Defer {
+        value: Spanned<Expression>,
+    },
+    /// `with resource as binding { body }`, which calls the resource's `close`
+    /// method once the body exits, however it exits.
+    With {
+        resource: Spanned<Expression>,
+        binding: Option<Identifier>,
+        body: Block,
     },
     If {
-        condition: Expression,
+        condition: Spanned<Expression>,
         then: Block,
         otherwise: Option<Block>
     },
     Expression {
-        expression: Expression
-    }
+        expression: Spanned<Expression>
+    },
+    /// `struct Name { fields }`, whose instances are made by calling `Name(fields)`.
+    Struct {
+        doc: Option<String>,
+        name: Identifier,
+        fields: Vec<Identifier>,
+    },
+    /// `impl Name { methods }` or `impl Trait for Name { methods }`, adding
+    /// methods to a struct. Operators on its instances call the methods named
+    /// `add`, `sub`, `mul`, `div`, `rem`, `eq`, `cmp` and `index`, and `in` calls
+    /// `contains` on its right operand.
+    Impl {
+        trait_name: Option<Identifier>,
+        target: Identifier,
+        methods: Block,
+    },
+    /// `trait Name { methods }`, the methods a struct implementing it must have.
+    Trait {
+        name: Identifier,
+        methods: Vec<Spanned<TraitMethod>>,
+    },
+    /// `macro name!(pattern) { template }`, removed again by macro expansion.
+    Macro {
+        name: Identifier,
+        pattern: Vec<Spanned<Token>>,
+        template: Vec<Spanned<Token>>,
+    },
+}
+
+/// A method declared by a trait. Impls without a method of their own get the
+/// default body, and must define the method when there is none.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraitMethod {
+    pub name: Identifier,
+    pub params: Vec<Spanned<Parameter>>,
+    pub return_type: Option<Type>,
+    pub default: Option<Block>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FunctionKind {
+    Normal,
+    Generator,
+    Async,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ImportBinding {
+    Namespace(Identifier),
+    Names(Vec<Identifier>),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Expression {
     Number(f64),
     String(String),
     InterpolatedString(String),
     Bool(bool),
+    Nil,
     Identifier(Identifier),
-    Infix(Box<Expression>, Op, Box<Expression>),
-    Prefix(Op, Box<Expression>),
-    Call(Box<Expression>, Vec<Expression>),
-    Closure(Vec<Parameter>, Vec<Statement>)
+    Infix(Box<Spanned<Expression>>, Op, Box<Spanned<Expression>>),
+    Prefix(Op, Box<Spanned<Expression>>),
+    List(Vec<Spanned<Expression>>),
+    /// `[item for x in xs if condition]`, whose clauses bind their names in a scope of their own.
+    ListComprehension(Box<Spanned<Expression>>, Vec<Clause>),
+    /// `{key: value for x in xs}`, where a later value for the same key replaces an earlier one.
+    MapComprehension(Box<Spanned<Expression>>, Box<Spanned<Expression>>, Vec<Clause>),
+    Call(Box<Spanned<Expression>>, Vec<Argument>),
+    Get(Box<Spanned<Expression>>, Identifier),
+    Index(Box<Spanned<Expression>>, Box<Spanned<Expression>>),
+    /// `start..end`, or `start..=end` when the end is inclusive.
+    Range(Box<Spanned<Expression>>, Box<Spanned<Expression>>, bool),
+    /// `value?.name`, which is nil rather than an error when the value is nil.
+    /// Calling it, as in `value?.name()`, skips the call too.
+    OptionalGet(Box<Spanned<Expression>>, Identifier),
+    /// `value?.[index]`, which is nil without evaluating the index when the value is nil.
+    OptionalIndex(Box<Spanned<Expression>>, Box<Spanned<Expression>>),
+    /// `function?.(arguments)`, which is nil without evaluating the arguments when the function is nil.
+    OptionalCall(Box<Spanned<Expression>>, Vec<Argument>),
+    Propagate(Box<Spanned<Expression>>),
+    Await(Box<Spanned<Expression>>),
+    Closure(Vec<Spanned<Parameter>>, Block),
+    /// `name!(tokens)`, replaced by what the macro expands to before the program runs.
+    MacroCall(Identifier, Vec<Spanned<Token>>),
 }
 
+/// Shorthands for wrapping an expression without a span, for building ASTs by hand in tests.
+#[cfg(test)]
 impl Expression {
-    pub fn some(self) -> Option<Self> {
-        Some(self)
-    }
-
-    pub fn boxed(self) -> Box<Self> {
-        Box::new(self)
+    pub fn some(self) -> Option<Spanned<Self>> {
+        Some(self.into())
     }
 
-    pub fn string(self) -> String {
-        match self {
-            Expression::Identifier(s) | Expression::String(s) => s,
-            _ => unreachable!()
-        }
+    pub fn boxed(self) -> Box<Spanned<Self>> {
+        Box::new(self.into())
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Op {
     Add,
     Subtract,
     Multiply,
     Divide,
     Modulo,
+    Bang,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Implements,
+    In,
+    /// `a ?? b`, which only evaluates `b` when `a` is nil.
+    Coalesce,
+}
+
+impl Op {
+    pub fn token(token: Token) -> Self {
+        match token {
+            Token::Plus => Self::Add,
+            Token::Minus => Self::Subtract,
+            Token::Asterisk => Self::Multiply,
+            Token::Slash => Self::Divide,
+            Token::Percent => Self::Modulo,
+            Token::Bang => Self::Bang,
+            Token::Equal => Self::Equal,
+            Token::NotEqual => Self::NotEqual,
+            Token::LessThan => Self::Less,
+            Token::LessEqual => Self::LessEqual,
+            Token::GreaterThan => Self::Greater,
+            Token::GreaterEqual => Self::GreaterEqual,
+            Token::Implements => Self::Implements,
+            Token::In => Self::In,
+            Token::QuestionQuestion => Self::Coalesce,
+            _ => unreachable!("{:?}", token)
+        }
+    }
 }
\ No newline at end of file
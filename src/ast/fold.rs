@@ -0,0 +1,246 @@
+//! Rewriting an AST by value. Override the `fold_*` methods for the nodes you
+//! want to change and call the matching `walk_*` function to rebuild the rest.
+//! Spans are carried over from the node being folded.
+
+use super::*;
+
+pub trait Folder: Sized {
+    fn fold_block(&mut self, block: Block) -> Block {
+        walk_block(self, block)
+    }
+
+    fn fold_statement(&mut self, statement: Spanned<Statement>) -> Spanned<Statement> {
+        walk_statement(self, statement)
+    }
+
+    fn fold_expression(&mut self, expression: Spanned<Expression>) -> Spanned<Expression> {
+        walk_expression(self, expression)
+    }
+
+    fn fold_parameter(&mut self, parameter: Spanned<Parameter>) -> Spanned<Parameter> {
+        walk_parameter(self, parameter)
+    }
+
+    fn fold_argument(&mut self, argument: Argument) -> Argument {
+        walk_argument(self, argument)
+    }
+
+    fn fold_clause(&mut self, clause: Clause) -> Clause {
+        walk_clause(self, clause)
+    }
+
+    fn fold_attribute(&mut self, attribute: Spanned<Attribute>) -> Spanned<Attribute> {
+        walk_attribute(self, attribute)
+    }
+
+    fn fold_trait_method(&mut self, method: Spanned<TraitMethod>) -> Spanned<TraitMethod> {
+        walk_trait_method(self, method)
+    }
+}
+
+pub fn walk_block<F: Folder>(folder: &mut F, block: Block) -> Block {
+    block.into_iter().map(|statement| folder.fold_statement(statement)).collect()
+}
+
+pub fn walk_statement<F: Folder>(folder: &mut F, statement: Spanned<Statement>) -> Spanned<Statement> {
+    let Spanned { node, span } = statement;
+    let node = match node {
+        Statement::FunctionDeclaration { doc, attributes, name, params, return_type, body, kind } => Statement::FunctionDeclaration {
+            doc,
+            attributes: attributes.into_iter().map(|attribute| folder.fold_attribute(attribute)).collect(),
+            name,
+            params: params.into_iter().map(|param| folder.fold_parameter(param)).collect(),
+            return_type,
+            body: folder.fold_block(body),
+            kind,
+        },
+        Statement::LetDeclaration { name, annotation, initial } => Statement::LetDeclaration {
+            name,
+            annotation,
+            initial: initial.map(|initial| folder.fold_expression(initial)),
+        },
+        Statement::ConstDeclaration { doc, name, annotation, value } => Statement::ConstDeclaration {
+            doc,
+            name,
+            annotation,
+            value: folder.fold_expression(value),
+        },
+        leaf @ (Statement::Import { .. } | Statement::Struct { .. } | Statement::Macro { .. } | Statement::Break) => leaf,
+        Statement::Impl { trait_name, target, methods } => Statement::Impl { trait_name, target, methods: folder.fold_block(methods) },
+        Statement::Trait { name, methods } => Statement::Trait {
+            name,
+            methods: methods.into_iter().map(|method| folder.fold_trait_method(method)).collect(),
+        },
+        Statement::Export { declaration } => Statement::Export {
+            declaration: folder.fold_statement(*declaration).boxed(),
+        },
+        Statement::For { binding, iterable, body } => Statement::For {
+            binding,
+            iterable: folder.fold_expression(iterable),
+            body: folder.fold_block(body),
+        },
+        Statement::While { condition, body } => Statement::While {
+            condition: folder.fold_expression(condition),
+            body: folder.fold_block(body),
+        },
+        Statement::Yield { value } => Statement::Yield { value: folder.fold_expression(value) },
+        Statement::Return { value } => Statement::Return { value: value.map(|value| folder.fold_expression(value)) },
+        Statement::Throw { value } => Statement::Throw { value: folder.fold_expression(value) },
+        Statement::Defer { value } => Statement::Defer { value: folder.fold_expression(value) },
+        Statement::With { resource, binding, body } => Statement::With {
+            resource: folder.fold_expression(resource),
+            binding,
+            body: folder.fold_block(body),
+        },
+        Statement::Try { body, binding, catch, finally } => Statement::Try {
+            body: folder.fold_block(body),
+            binding,
+            catch: catch.map(|catch| folder.fold_block(catch)),
+            finally: finally.map(|finally| folder.fold_block(finally)),
+        },
+        Statement::If { condition, then, otherwise } => Statement::If {
+            condition: folder.fold_expression(condition),
+            then: folder.fold_block(then),
+            otherwise: otherwise.map(|otherwise| folder.fold_block(otherwise)),
+        },
+        Statement::Expression { expression } => Statement::Expression {
+            expression: folder.fold_expression(expression),
+        },
+    };
+
+    Spanned::new(node, span)
+}
+
+pub fn walk_expression<F: Folder>(folder: &mut F, expression: Spanned<Expression>) -> Spanned<Expression> {
+    let Spanned { node, span } = expression;
+    let node = match node {
+        literal @ (Expression::Number(_)
+            | Expression::String(_)
+            | Expression::InterpolatedString(_)
+            | Expression::Bool(_)
+            | Expression::Nil
+            | Expression::Identifier(_)
+            | Expression::MacroCall(..)) => literal,
+        Expression::Infix(left, op, right) => {
+            let left = folder.fold_expression(*left);
+            Expression::Infix(left.boxed(), op, folder.fold_expression(*right).boxed())
+        },
+        Expression::Prefix(op, value) => Expression::Prefix(op, folder.fold_expression(*value).boxed()),
+        Expression::List(items) => Expression::List(items.into_iter().map(|item| folder.fold_expression(item)).collect()),
+        Expression::ListComprehension(item, clauses) => {
+            let clauses = clauses.into_iter().map(|clause| folder.fold_clause(clause)).collect();
+            Expression::ListComprehension(folder.fold_expression(*item).boxed(), clauses)
+        },
+        Expression::MapComprehension(key, value, clauses) => {
+            let clauses = clauses.into_iter().map(|clause| folder.fold_clause(clause)).collect();
+            let key = folder.fold_expression(*key);
+            Expression::MapComprehension(key.boxed(), folder.fold_expression(*value).boxed(), clauses)
+        },
+        Expression::Call(callable, arguments) => {
+            let callable = folder.fold_expression(*callable);
+            Expression::Call(callable.boxed(), arguments.into_iter().map(|argument| folder.fold_argument(argument)).collect())
+        },
+        Expression::Get(receiver, name) => Expression::Get(folder.fold_expression(*receiver).boxed(), name),
+        Expression::OptionalGet(receiver, name) => Expression::OptionalGet(folder.fold_expression(*receiver).boxed(), name),
+        Expression::OptionalIndex(value, index) => {
+            let value = folder.fold_expression(*value);
+            Expression::OptionalIndex(value.boxed(), folder.fold_expression(*index).boxed())
+        },
+        Expression::OptionalCall(callable, arguments) => {
+            let callable = folder.fold_expression(*callable);
+            Expression::OptionalCall(callable.boxed(), arguments.into_iter().map(|argument| folder.fold_argument(argument)).collect())
+        },
+        Expression::Index(value, index) => {
+            let value = folder.fold_expression(*value);
+            Expression::Index(value.boxed(), folder.fold_expression(*index).boxed())
+        },
+        Expression::Range(start, end, inclusive) => {
+            let start = folder.fold_expression(*start);
+            Expression::Range(start.boxed(), folder.fold_expression(*end).boxed(), inclusive)
+        },
+        Expression::Propagate(value) => Expression::Propagate(folder.fold_expression(*value).boxed()),
+        Expression::Await(value) => Expression::Await(folder.fold_expression(*value).boxed()),
+        Expression::Closure(params, body) => {
+            let params = params.into_iter().map(|param| folder.fold_parameter(param)).collect();
+            Expression::Closure(params, folder.fold_block(body))
+        },
+    };
+
+    Spanned::new(node, span)
+}
+
+pub fn walk_clause<F: Folder>(folder: &mut F, clause: Clause) -> Clause {
+    match clause {
+        Clause::For(pattern, iterable) => Clause::For(pattern, folder.fold_expression(iterable)),
+        Clause::If(condition) => Clause::If(folder.fold_expression(condition)),
+    }
+}
+
+pub fn walk_parameter<F: Folder>(folder: &mut F, parameter: Spanned<Parameter>) -> Spanned<Parameter> {
+    let Spanned { mut node, span } = parameter;
+    node.default = node.default.map(|default| folder.fold_expression(default));
+    Spanned::new(node, span)
+}
+
+pub fn walk_argument<F: Folder>(folder: &mut F, argument: Argument) -> Argument {
+    match argument {
+        Argument::Positional(value) => Argument::Positional(folder.fold_expression(value)),
+        Argument::Named(name, value) => Argument::Named(name, folder.fold_expression(value)),
+        Argument::Spread(value) => Argument::Spread(folder.fold_expression(value)),
+    }
+}
+
+pub fn walk_attribute<F: Folder>(folder: &mut F, attribute: Spanned<Attribute>) -> Spanned<Attribute> {
+    let Spanned { mut node, span } = attribute;
+    node.arguments = node.arguments.into_iter().map(|argument| folder.fold_argument(argument)).collect();
+    Spanned::new(node, span)
+}
+
+pub fn walk_trait_method<F: Folder>(folder: &mut F, method: Spanned<TraitMethod>) -> Spanned<TraitMethod> {
+    let Spanned { mut node, span } = method;
+    node.params = node.params.into_iter().map(|param| folder.fold_parameter(param)).collect();
+    node.default = node.default.map(|default| folder.fold_block(default));
+    Spanned::new(node, span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renames every identifier that is read.
+    struct Rename(&'static str, &'static str);
+
+    impl Folder for Rename {
+        fn fold_expression(&mut self, expression: Spanned<Expression>) -> Spanned<Expression> {
+            match &expression.node {
+                Expression::Identifier(name) if name == self.0 => Spanned::new(Expression::Identifier(self.1.to_string()), expression.span),
+                _ => walk_expression(self, expression),
+            }
+        }
+    }
+
+    fn parse_source(source: &str) -> Program {
+        let tokens = crate::token::generate(source).into_iter().map(|t| t.unwrap()).collect();
+        crate::parser::parse(tokens).unwrap()
+    }
+
+    #[test]
+    fn it_rewrites_every_nested_expression() {
+        let source = "fn f(a = old) { for x in old { g(old, ...old) } }\ntry { old()?.i } catch e { [|y| y + old] }";
+        let folded = Rename("old", "new").fold_block(parse_source(source));
+
+        assert_eq!(folded, parse_source(&source.replace("old", "new")));
+    }
+
+    #[test]
+    fn it_keeps_the_spans_of_rewritten_nodes() {
+        let folded = Rename("a", "b").fold_block(parse_source("let x = [1, a]"));
+
+        match &folded[0].node {
+            Statement::LetDeclaration { initial: Some(Spanned { node: Expression::List(items), .. }), .. } => {
+                assert_eq!(items[1].span, Span { start: 12, end: 13, line: 1, column: 13 });
+            },
+            s => panic!("expected a let declaration, found {:?}", s),
+        }
+    }
+}
@@ -0,0 +1,651 @@
+//! Printing an AST back into canonical source. Parsing the printed source
+//! gives back the same AST, apart from spans and the sugar the parser removes
+//! (pipes become calls, and `x => e` becomes `|x| e`).
+//!
+//! Statements have no terminator, so an expression statement that starts
+//! with `(`, `-`, `|` or `!` reads as a continuation of the statement before it.
+//! Such programs can't be printed unambiguously.
+
+use std::fmt::{Display, Formatter};
+
+use super::*;
+
+const INDENT: &str = "    ";
+
+pub fn print(program: &Program) -> String {
+    let mut printer = Printer::default();
+    program.iter().map(|statement| printer.statement(statement) + "\n").collect()
+}
+
+/// A parameter list as written between the parentheses of a declaration.
+pub fn signature(params: &[Spanned<Parameter>]) -> String {
+    Printer::default().parameters(params)
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", Printer::default().expression(self))
+    }
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", op_symbol(self))
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Named(name, arguments) if arguments.is_empty() => write!(f, "{}", name),
+            Type::Named(name, arguments) => write!(f, "{}<{}>", name, join(arguments.iter().map(|a| a.to_string()))),
+            Type::Union(members) => write!(f, "{}", members.iter().map(|m| match m {
+                Type::Union(_) => format!("({})", m),
+                m => m.to_string(),
+            }).collect::<Vec<_>>().join(" | ")),
+        }
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Name(name) => write!(f, "{}", name),
+            Pattern::Tuple(patterns) => write!(f, "({})", join(patterns.iter().map(|p| p.to_string()))),
+        }
+    }
+}
+
+/// How tightly an expression binds, matching the parser's precedence. An
+/// operand that binds more loosely than its position allows is parenthesised.
+#[derive(PartialEq, PartialOrd)]
+enum Binding {
+    Closure,
+    Coalesce,
+    Comparison,
+    Range,
+    Sum,
+    Product,
+    Prefix,
+    Postfix,
+}
+
+impl Binding {
+    fn of(expression: &Expression) -> Self {
+        match expression {
+            Expression::Closure(..) => Binding::Closure,
+            Expression::Infix(_, op, _) => Binding::infix(op),
+            Expression::Range(..) => Binding::Range,
+            Expression::Prefix(..) | Expression::Await(_) => Binding::Prefix,
+            _ => Binding::Postfix,
+        }
+    }
+
+    fn infix(op: &Op) -> Self {
+        match op {
+            Op::Multiply | Op::Divide | Op::Modulo => Binding::Product,
+            Op::Add | Op::Subtract | Op::Bang => Binding::Sum,
+            Op::Coalesce => Binding::Coalesce,
+            _ => Binding::Comparison,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Printer {
+    indent: usize,
+}
+
+impl Printer {
+    /// A statement, after the doc comment of the declaration it is or exports.
+    fn statement(&mut self, statement: &Statement) -> String {
+        let doc: String = doc_of(statement).into_iter()
+            .flat_map(|doc| doc.split('\n'))
+            .map(|line| format!("/// {}\n{}", line, INDENT.repeat(self.indent)))
+            .collect();
+        doc + &self.statement_kind(statement)
+    }
+
+    fn statement_kind(&mut self, statement: &Statement) -> String {
+        match statement {
+            Statement::FunctionDeclaration { attributes, name, params, return_type, body, kind, .. } => {
+                let attributes: String = attributes.iter().map(|attribute| {
+                    let arguments = if attribute.arguments.is_empty() { String::new() } else { format!("({})", self.arguments(&attribute.arguments)) };
+                    format!("@{}{}\n{}", attribute.name, arguments, INDENT.repeat(self.indent))
+                }).collect();
+                let keyword = match kind {
+                    FunctionKind::Normal => "fn",
+                    FunctionKind::Generator => "fn*",
+                    FunctionKind::Async => "async fn",
+                };
+                let return_type = return_type.as_ref().map(|t| format!(" -> {}", t)).unwrap_or_default();
+                format!("{}{} {}({}){} {}", attributes, keyword, name, self.parameters(params), return_type, self.block(body))
+            },
+            Statement::LetDeclaration { name, annotation, initial } => {
+                let initial = initial.as_ref().map(|e| format!(" = {}", self.expression(e))).unwrap_or_default();
+                format!("let {}{}{}", name, annotation_of(annotation), initial)
+            },
+            Statement::ConstDeclaration { name, annotation, value, .. } => {
+                format!("const {}{} = {}", name, annotation_of(annotation), self.expression(value))
+            },
+            Statement::Import { path, binding: ImportBinding::Namespace(alias) } => {
+                format!("import {} as {}", quote(path), alias)
+            },
+            Statement::Import { path, binding: ImportBinding::Names(names) } => {
+                format!("import {{ {} }} from {}", names.join(", "), quote(path))
+            },
+            Statement::Export { declaration } => format!("export {}", self.statement_kind(declaration)),
+            Statement::For { binding, iterable, body } => {
+                format!("for {} in {} {}", binding, self.expression(iterable), self.block(body))
+            },
+            Statement::While { condition, body } => format!("while {} {}", self.expression(condition), self.block(body)),
+            Statement::Yield { value } => format!("yield {}", self.expression(value)),
+            Statement::Return { value: Some(value) } => format!("return {}", self.expression(value)),
+            Statement::Return { value: None } => "return".to_string(),
+            Statement::Break => "break".to_string(),
+            Statement::Throw { value } => format!("throw {}", self.expression(value)),
+            Statement::Defer { value } => format!("defer {}", self.expression(value)),
+            Statement::With { resource, binding, body } => {
+                let binding = binding.as_ref().map(|b| format!(" as {}", b)).unwrap_or_default();
+                format!("with {}{} {}", self.expression(resource), binding, self.block(body))
+            },
+            Statement::Try { body, binding, catch, finally } => {
+                let mut source = format!("try {}", self.block(body));
+                if let Some(catch) = catch {
+                    let binding = binding.as_ref().map(|b| format!("{} ", b)).unwrap_or_default();
+                    source += &format!(" catch {}{}", binding, self.block(catch));
+                }
+                if let Some(finally) = finally {
+                    source += &format!(" finally {}", self.block(finally));
+                }
+                source
+            },
+            Statement::If { condition, then, otherwise } => {
+                let mut source = format!("if {} {}", self.expression(condition), self.block(then));
+                if let Some(otherwise) = otherwise {
+                    source += &format!(" else {}", self.block(otherwise));
+                }
+                source
+            },
+            // A statement starting with `fn` would be read as a declaration.
+            Statement::Expression { expression } => match &expression.node {
+                Expression::Closure(params, body) if !is_short_lambda(params, body) => format!("({})", self.expression(expression)),
+                _ => self.expression(expression),
+            },
+            Statement::Struct { name, fields, .. } if fields.is_empty() => format!("struct {} {{}}", name),
+            Statement::Struct { name, fields, .. } => format!("struct {} {{ {} }}", name, fields.join(", ")),
+            Statement::Impl { trait_name, target, methods } => {
+                let trait_name = trait_name.as_ref().map(|t| format!("{} for ", t)).unwrap_or_default();
+                format!("impl {}{} {}", trait_name, target, self.block(methods))
+            },
+            Statement::Trait { name, methods } if methods.is_empty() => format!("trait {} {{}}", name),
+            Statement::Trait { name, methods } => {
+                self.indent += 1;
+                let methods: String = methods.iter().map(|method| {
+                    let return_type = method.return_type.as_ref().map(|t| format!(" -> {}", t)).unwrap_or_default();
+                    let default = method.default.as_ref().map(|d| format!(" {}", self.block(d))).unwrap_or_default();
+                    format!("{}fn {}({}){}{}\n", INDENT.repeat(self.indent), method.name, self.parameters(&method.params), return_type, default)
+                }).collect();
+                self.indent -= 1;
+                format!("trait {} {{\n{}{}}}", name, methods, INDENT.repeat(self.indent))
+            },
+            Statement::Macro { name, pattern, template } => {
+                format!("macro {}!({}) {{ {} }}", name, tokens(pattern), tokens(template))
+            },
+        }
+    }
+
+    fn block(&mut self, block: &[Spanned<Statement>]) -> String {
+        if block.is_empty() {
+            return "{}".to_string()
+        }
+
+        self.indent += 1;
+        let statements: String = block.iter()
+            .map(|statement| format!("{}{}\n", INDENT.repeat(self.indent), self.statement(statement)))
+            .collect();
+        self.indent -= 1;
+
+        format!("{{\n{}{}}}", statements, INDENT.repeat(self.indent))
+    }
+
+    fn expression(&mut self, expression: &Expression) -> String {
+        match expression {
+            Expression::Number(n) => n.to_string(),
+            // There is no syntax for interpolation yet, so these print as plain strings.
+            Expression::String(s) | Expression::InterpolatedString(s) => quote(s),
+            Expression::Bool(b) => b.to_string(),
+            Expression::Nil => String::from("nil"),
+            Expression::Identifier(name) => name.clone(),
+            Expression::Infix(left, op, right) => {
+                let binding = Binding::infix(op);
+                let left = self.operand(left, |b| b >= binding);
+                let right = self.operand(right, |b| b > binding);
+                format!("{} {} {}", left, op_symbol(op), right)
+            },
+            Expression::Prefix(op, value) => format!("{}{}", op_symbol(op), self.operand(value, |b| b >= Binding::Prefix)),
+            Expression::Await(value) => format!("await {}", self.operand(value, |b| b >= Binding::Prefix)),
+            Expression::List(items) => format!("[{}]", join(items.iter().map(|item| self.expression(item)))),
+            Expression::ListComprehension(item, clauses) => format!("[{}{}]", self.expression(item), self.clauses(clauses)),
+            Expression::MapComprehension(key, value, clauses) => {
+                format!("{{{}: {}{}}}", self.expression(key), self.expression(value), self.clauses(clauses))
+            },
+            Expression::Call(callable, arguments) => {
+                let callable = self.operand(callable, |b| b == Binding::Postfix);
+                format!("{}({})", callable, self.arguments(arguments))
+            },
+            Expression::Get(receiver, name) => format!("{}.{}", self.receiver(receiver, |b| b == Binding::Postfix), name),
+            Expression::Index(value, index) => format!("{}[{}]", self.operand(value, |b| b == Binding::Postfix), self.expression(index)),
+            Expression::OptionalGet(receiver, name) => format!("{}?.{}", self.receiver(receiver, |b| b == Binding::Postfix), name),
+            Expression::OptionalIndex(value, index) => {
+                format!("{}?.[{}]", self.receiver(value, |b| b == Binding::Postfix), self.expression(index))
+            },
+            Expression::OptionalCall(callable, arguments) => {
+                format!("{}?.({})", self.receiver(callable, |b| b == Binding::Postfix), self.arguments(arguments))
+            },
+            Expression::Range(start, end, inclusive) => {
+                let start = self.receiver(start, |b| b >= Binding::Range);
+                let end = self.operand(end, |b| b > Binding::Range);
+                format!("{}{}{}", start, if *inclusive { "..=" } else { ".." }, end)
+            },
+            Expression::Propagate(value) => match &value.node {
+                // `??` lexes as coalescing.
+                Expression::Propagate(_) => format!("({})?", self.expression(value)),
+                _ => format!("{}?", self.operand(value, |b| b == Binding::Postfix)),
+            },
+            Expression::Closure(params, body) if is_short_lambda(params, body) => match &body[0].node {
+                Statement::Expression { expression } => format!("|{}| {}", self.parameters(params), self.expression(expression)),
+                _ => unreachable!(),
+            },
+            Expression::Closure(params, body) => format!("fn({}) {}", self.parameters(params), self.block(body)),
+            Expression::MacroCall(name, arguments) => format!("{}!({})", name, tokens(arguments)),
+        }
+    }
+
+    fn operand(&mut self, operand: &Expression, fits: impl Fn(Binding) -> bool) -> String {
+        if fits(Binding::of(operand)) {
+            self.expression(operand)
+        } else {
+            format!("({})", self.expression(operand))
+        }
+    }
+
+    /// An operand followed by a `.`, which after a `?` would lex as `?.` instead.
+    fn receiver(&mut self, operand: &Expression, fits: impl Fn(Binding) -> bool) -> String {
+        match self.operand(operand, fits) {
+            receiver if receiver.ends_with('?') => format!("({})", receiver),
+            receiver => receiver,
+        }
+    }
+
+    fn clauses(&mut self, clauses: &[Clause]) -> String {
+        clauses.iter().map(|clause| match clause {
+            Clause::For(pattern, iterable) => format!(" for {} in {}", pattern, self.expression(iterable)),
+            Clause::If(condition) => format!(" if {}", self.expression(condition)),
+        }).collect()
+    }
+
+    fn arguments(&mut self, arguments: &[Argument]) -> String {
+        join(arguments.iter().map(|argument| match argument {
+            Argument::Positional(value) => self.expression(value),
+            Argument::Named(name, value) => format!("{}: {}", name, self.expression(value)),
+            Argument::Spread(value) => format!("...{}", self.expression(value)),
+        }))
+    }
+
+    pub fn parameters(&mut self, params: &[Spanned<Parameter>]) -> String {
+        join(params.iter().map(|param| {
+            let spread = if param.variadic { "..." } else { "" };
+            let default = param.default.as_ref().map(|d| format!(" = {}", self.expression(d))).unwrap_or_default();
+            format!("{}{}{}{}", spread, param.name, annotation_of(&param.annotation), default)
+        }))
+    }
+}
+
+/// The doc comment of a declaration, or of the declaration being exported.
+fn doc_of(statement: &Statement) -> Option<&String> {
+    match statement {
+        Statement::FunctionDeclaration { doc, .. }
+        | Statement::ConstDeclaration { doc, .. }
+        | Statement::Struct { doc, .. } => doc.as_ref(),
+        Statement::Export { declaration } => doc_of(declaration),
+        _ => None,
+    }
+}
+
+/// Whether a closure can be written as `|params| expression`, which needs a
+/// lone expression body and no unions in the parameter annotations, since the
+/// `|` of a union would close the parameter list. A body starting with a map
+/// comprehension would be read as a block.
+fn is_short_lambda(params: &[Spanned<Parameter>], body: &[Spanned<Statement>]) -> bool {
+    matches!(body, [Spanned { node: Statement::Expression { expression }, .. }] if !expression.node.to_string().starts_with('{'))
+        && !params.iter().any(|p| matches!(p.annotation, Some(Type::Union(_))))
+}
+
+/// Writes tokens back out as source, spaced the way they're usually written.
+pub fn tokens(tokens: &[Spanned<Token>]) -> String {
+    let mut source = String::new();
+    let mut previous: Option<&Token> = None;
+
+    for token in tokens.iter().map(|t| &t.node) {
+        let attached = matches!((previous, token),
+            (None, _)
+            | (Some(Token::LeftParen | Token::LeftBracket | Token::Dot | Token::Ellipsis | Token::Dollar), _)
+            | (_, Token::RightParen | Token::RightBracket | Token::Comma | Token::Dot | Token::Question | Token::Colon)
+            | (Some(Token::Identifier(_)), Token::Bang)
+            | (Some(Token::Identifier(_) | Token::RightParen | Token::RightBracket | Token::Bang), Token::LeftParen)
+        );
+        if !attached {
+            source.push(' ');
+        }
+        source += &token.to_string();
+        previous = Some(token);
+    }
+
+    source
+}
+
+fn annotation_of(annotation: &Option<Type>) -> String {
+    annotation.as_ref().map(|t| format!(": {}", t)).unwrap_or_default()
+}
+
+fn op_symbol(op: &Op) -> &'static str {
+    match op {
+        Op::Add => "+",
+        Op::Subtract => "-",
+        Op::Multiply => "*",
+        Op::Divide => "/",
+        Op::Modulo => "%",
+        Op::Bang => "!",
+        Op::Equal => "==",
+        Op::NotEqual => "!=",
+        Op::Less => "<",
+        Op::LessEqual => "<=",
+        Op::Greater => ">",
+        Op::GreaterEqual => ">=",
+        Op::Implements => "implements",
+        Op::In => "in",
+        Op::Coalesce => "??",
+    }
+}
+
+pub fn quote(string: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn join(items: impl Iterator<Item = String>) -> String {
+    items.collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use proptest::strategy::{BoxedStrategy, Just};
+
+    fn parse_source(source: &str) -> Program {
+        let tokens = crate::token::generate(source).into_iter().map(|t| t.unwrap()).collect();
+        crate::parser::parse(tokens).unwrap()
+    }
+
+    #[test]
+    fn it_prints_canonical_source() {
+        let source = "async fn fetch(url: String, retries = 3, ...rest) -> List<Number | String> {\n    try {\n        await get(url, timeout: 1)\n    } catch e {\n        throw e\n    }\n}\nlet double = |x| x * 2\n";
+
+        assert_eq!(print(&parse_source(source)), source);
+        assert_eq!(print(&parse_source("xs |> map(|x: Number|x)")), "map(xs, |x: Number| x)\n");
+    }
+
+    #[test]
+    fn it_only_parenthesises_where_precedence_requires() {
+        let printed = |source: &str| print(&parse_source(source)).trim_end().to_string();
+
+        assert_eq!(printed("(1 + 2) * 3"), "(1 + 2) * 3");
+        assert_eq!(printed("1 + (2 * 3)"), "1 + 2 * 3");
+        assert_eq!(printed("1 - (2 - 3)"), "1 - (2 - 3)");
+        assert_eq!(printed("(1 - 2) - 3"), "1 - 2 - 3");
+        assert_eq!(printed("f(-(a + b)).c"), "f(-(a + b)).c");
+        assert_eq!(printed("(x)?"), "x?");
+        assert_eq!(printed("(x?)?"), "(x?)?");
+        assert_eq!(printed("(a < b) == (c + 1)"), "a < b == c + 1");
+        assert_eq!(printed("a < (b == c)"), "a < (b == c)");
+        assert_eq!(printed("(a + b)[i]"), "(a + b)[i]");
+        assert_eq!(printed("(a?).b"), "(a?).b");
+        assert_eq!(printed("[(x) * 2 for x in (xs) if x > 0 for (a, (b, c)) in x]"), "[x * 2 for x in xs if x > 0 for (a, (b, c)) in x]");
+        assert_eq!(printed("{ k : [v for v in vs] for (k, vs) in m }"), "{k: [v for v in vs] for (k, vs) in m}");
+        assert_eq!(printed("(f()?).b"), "(f()?).b");
+        assert_eq!(printed("(a?)?.b?.[0]?.(1) ?? (nil ?? c == d)"), "(a?)?.b?.[0]?.(1) ?? (nil ?? c == d)");
+        assert_eq!(printed("x in (a + 1)..=(b * 2)"), "x in a + 1..=b * 2");
+        assert_eq!(printed("(a..b).step(2)[0..(n < 1)]"), "(a..b).step(2)[0..(n < 1)]");
+        assert_eq!(printed("(fn(a: A | B) { a })(1)"), "(fn(a: A | B) {\n    a\n})(1)");
+        assert_eq!(printed("f(|x| { {k: x for k in x} == x })"), "f(fn(x) {\n    {k: x for k in x} == x\n})");
+    }
+
+    #[test]
+    fn it_prints_doc_comments_before_the_declaration() {
+        let source = "/// Adds.\n///\n///  Indented.\nexport @memo\nfn add(a, b) {\n    /// Inner.\n    const c = a\n}\n/// A point.\nstruct Point { x, y }\n";
+
+        assert_eq!(
+            print(&parse_source(source)),
+            "/// Adds.\n/// \n///  Indented.\nexport @memo\nfn add(a, b) {\n    /// Inner.\n    const c = a\n}\n/// A point.\nstruct Point { x, y }\n"
+        );
+    }
+
+    #[test]
+    fn it_escapes_strings() {
+        let string = Expression::String("say \"hi\"\\n\n".to_string());
+
+        assert_eq!(string.to_string(), r#""say \"hi\"\\n\n""#);
+    }
+
+    fn identifier() -> impl Strategy<Value = Identifier> {
+        "[a-z][a-z0-9]{0,3}".prop_filter("keywords are not identifiers", |name| {
+            matches!(crate::token::generate(name).as_slice(), [Ok((crate::token::Token::Identifier(_), _))])
+        })
+    }
+
+    fn ty(depth: u32) -> BoxedStrategy<Type> {
+        let named = (identifier(), Just(Vec::new())).prop_map(|(name, args)| Type::Named(name, args));
+        if depth == 0 {
+            return named.boxed()
+        }
+
+        prop_oneof![
+            2 => named,
+            1 => (identifier(), prop::collection::vec(ty(depth - 1), 1..3)).prop_map(|(name, args)| Type::Named(name, args)),
+            1 => prop::collection::vec(ty(depth - 1), 2..4).prop_map(Type::Union),
+        ].boxed()
+    }
+
+    /// Statements and expressions valid inside a function of the given kind, where `None` is the top level.
+    fn expression(depth: u32, context: Option<FunctionKind>) -> BoxedStrategy<Expression> {
+        let leaf = prop_oneof![
+            (0u32..1000).prop_map(|n| Expression::Number(n as f64)),
+            (0.0f64..1e6).prop_map(Expression::Number),
+            "[ -~\n\t]{0,6}".prop_map(Expression::String),
+            any::<bool>().prop_map(Expression::Bool),
+            Just(Expression::Nil),
+            identifier().prop_map(Expression::Identifier),
+        ];
+        if depth == 0 {
+            return leaf.boxed()
+        }
+
+        let inner = || expression(depth - 1, context).prop_map(Spanned::from);
+        let op = prop_oneof![
+            Just(Op::Add), Just(Op::Subtract), Just(Op::Multiply), Just(Op::Divide), Just(Op::Modulo),
+            Just(Op::Equal), Just(Op::NotEqual), Just(Op::Less), Just(Op::LessEqual), Just(Op::Greater), Just(Op::GreaterEqual),
+            Just(Op::Implements), Just(Op::In), Just(Op::Coalesce),
+        ];
+        let argument = || prop_oneof![
+            inner().prop_map(Argument::Positional),
+            (identifier(), inner()).prop_map(|(name, value)| Argument::Named(name, value)),
+            inner().prop_map(Argument::Spread),
+        ];
+        let awaited = if context.is_none() || context == Some(FunctionKind::Async) { 1 } else { 0 };
+
+        prop_oneof![
+            3 => leaf,
+            2 => (inner(), op, inner()).prop_map(|(l, op, r)| Expression::Infix(l.boxed(), op, r.boxed())),
+            1 => (prop_oneof![Just(Op::Subtract), Just(Op::Bang)], inner()).prop_map(|(op, e)| Expression::Prefix(op, e.boxed())),
+            1 => prop_oneof![
+                prop::collection::vec(inner(), 0..3).prop_map(Expression::List),
+                (inner(), clauses(depth - 1, context)).prop_map(|(item, clauses)| Expression::ListComprehension(item.boxed(), clauses)),
+                (inner(), inner(), clauses(depth - 1, context))
+                    .prop_map(|(key, value, clauses)| Expression::MapComprehension(key.boxed(), value.boxed(), clauses)),
+            ],
+            2 => (inner(), prop::collection::vec(argument(), 0..3)).prop_map(|(c, args)| Expression::Call(c.boxed(), args)),
+            2 => prop_oneof![
+                (inner(), identifier()).prop_map(|(r, name)| Expression::Get(r.boxed(), name)),
+                (inner(), identifier()).prop_map(|(r, name)| Expression::OptionalGet(r.boxed(), name)),
+                (inner(), inner()).prop_map(|(value, index)| Expression::OptionalIndex(value.boxed(), index.boxed())),
+                (inner(), prop::collection::vec(argument(), 0..3)).prop_map(|(c, args)| Expression::OptionalCall(c.boxed(), args)),
+            ],
+            2 => prop_oneof![
+                (inner(), inner()).prop_map(|(value, index)| Expression::Index(value.boxed(), index.boxed())),
+                (inner(), inner(), any::<bool>()).prop_map(|(start, end, inclusive)| Expression::Range(start.boxed(), end.boxed(), inclusive)),
+            ],
+            1 => inner().prop_map(|e| Expression::Propagate(e.boxed())),
+            awaited => inner().prop_map(|e| Expression::Await(e.boxed())),
+            1 => (parameters(depth - 1, context), block(depth - 1, Some(FunctionKind::Normal)))
+                .prop_map(|(params, body)| Expression::Closure(params, body)),
+        ].boxed()
+    }
+
+    fn pattern() -> impl Strategy<Value = Pattern> {
+        let name = identifier().prop_map(Pattern::Name);
+        name.prop_recursive(2, 6, 3, |inner| prop_oneof![
+            identifier().prop_map(Pattern::Name),
+            prop::collection::vec(inner, 0..3).prop_map(Pattern::Tuple),
+        ])
+    }
+
+    /// A comprehension's clauses, which start with a `for`.
+    fn clauses(depth: u32, context: Option<FunctionKind>) -> BoxedStrategy<Vec<Clause>> {
+        let inner = || expression(depth, context).prop_map(Spanned::from);
+        let first = (pattern(), inner()).prop_map(|(pattern, iterable)| Clause::For(pattern, iterable));
+        let rest = prop_oneof![
+            (pattern(), inner()).prop_map(|(pattern, iterable)| Clause::For(pattern, iterable)),
+            inner().prop_map(Clause::If),
+        ];
+
+        (first, prop::collection::vec(rest, 0..2)).prop_map(|(first, rest)| std::iter::once(first).chain(rest).collect()).boxed()
+    }
+
+    /// Parameter defaults are evaluated, and parsed, in the context around the function.
+    fn parameters(depth: u32, context: Option<FunctionKind>) -> BoxedStrategy<Vec<Spanned<Parameter>>> {
+        let param = (identifier(), prop::option::of(ty(1)), prop::option::of(expression(depth, context)))
+            .prop_map(|(name, annotation, default)| Parameter { name, annotation, default: default.map(Spanned::from), variadic: false });
+
+        (prop::collection::vec(param, 0..3), any::<bool>()).prop_map(|(mut params, variadic)| {
+            if let (Some(last), true) = (params.last_mut(), variadic) {
+                last.default = None;
+                last.variadic = true;
+            }
+            params.into_iter().map(Spanned::from).collect()
+        }).boxed()
+    }
+
+    fn doc() -> impl Strategy<Value = Option<String>> {
+        prop::option::of(prop::collection::vec("[ -~]{0,8}", 1..3).prop_map(|lines| lines.join("\n")))
+    }
+
+    fn block(depth: u32, context: Option<FunctionKind>) -> BoxedStrategy<Block> {
+        prop::collection::vec(statement(depth, context).prop_map(Spanned::from), 0..3).boxed()
+    }
+
+    fn statement(depth: u32, context: Option<FunctionKind>) -> BoxedStrategy<Statement> {
+        let value = || expression(depth, context).prop_map(Spanned::from);
+        let kind = prop_oneof![Just(FunctionKind::Normal), Just(FunctionKind::Generator), Just(FunctionKind::Async)];
+        let attribute = (identifier(), prop::collection::vec(value().prop_map(Argument::Positional), 0..2))
+            .prop_map(|(name, arguments)| Spanned::from(Attribute { name, arguments }));
+        let function = (doc(), prop::collection::vec(attribute, 0..2), identifier(), parameters(depth, context), prop::option::of(ty(1)), kind)
+            .prop_flat_map(move |(doc, attributes, name, params, return_type, kind)| block(depth.saturating_sub(1), Some(kind)).prop_map(move |body| {
+                Statement::FunctionDeclaration { doc: doc.clone(), attributes: attributes.clone(), name: name.clone(), params: params.clone(), return_type: return_type.clone(), body, kind }
+            })).boxed();
+        let declaration = prop_oneof![
+            (identifier(), prop::option::of(ty(1)), prop::option::of(value()))
+                .prop_map(|(name, annotation, initial)| Statement::LetDeclaration { name, annotation, initial }),
+            (doc(), identifier(), prop::option::of(ty(1)), value())
+                .prop_map(|(doc, name, annotation, value)| Statement::ConstDeclaration { doc, name, annotation, value }),
+        ].boxed();
+        let leaf = prop_oneof![
+            // See the module docs: these would run on from the previous statement.
+            value().prop_map(|expression| Statement::Expression { expression })
+                .prop_filter("ambiguous statement start", |s| !print(&vec![s.clone().into()]).starts_with(['(', '-', '|', '!'])),
+            declaration.clone(),
+            value().prop_map(|value| Statement::Throw { value }),
+            value().prop_map(|value| Statement::Defer { value }),
+            ("[a-z./]{1,8}", identifier()).prop_map(|(path, alias)| Statement::Import { path, binding: ImportBinding::Namespace(alias) }),
+            ("[a-z./]{1,8}", prop::collection::vec(identifier(), 1..3)).prop_map(|(path, names)| Statement::Import { path, binding: ImportBinding::Names(names) }),
+        ];
+        // `let x: T` followed by `|...` would read the bar as part of a union.
+        let leaf = leaf.prop_filter("annotated let without a value", |s| {
+            !matches!(s, Statement::LetDeclaration { annotation: Some(_), initial: None, .. })
+        });
+        if depth == 0 {
+            return leaf.boxed()
+        }
+
+        let trait_method = (identifier(), parameters(depth, context), prop::option::of(ty(1)), prop::option::of(block(depth.saturating_sub(1), Some(FunctionKind::Normal))))
+            .prop_map(|(name, params, return_type, default)| Spanned::from(TraitMethod { name, params, return_type, default }));
+        let yielded = if context == Some(FunctionKind::Generator) { 1 } else { 0 };
+        let returned = if context.is_some() { 1 } else { 0 };
+        let nested = || block(depth - 1, context);
+
+        prop_oneof![
+            4 => leaf,
+            1 => function.clone(),
+            1 => prop_oneof![function.clone(), declaration].prop_map(|d| Statement::Export { declaration: Spanned::from(d).boxed() }),
+            1 => (doc(), identifier(), prop::collection::vec(identifier(), 0..3)).prop_map(|(doc, name, fields)| Statement::Struct { doc, name, fields }),
+            1 => (prop::option::of(identifier()), identifier(), prop::collection::vec(function.prop_map(Spanned::from), 0..2))
+                .prop_map(|(trait_name, target, methods)| Statement::Impl { trait_name, target, methods }),
+            1 => (identifier(), prop::collection::vec(trait_method, 0..3)).prop_map(|(name, methods)| Statement::Trait { name, methods }),
+            1 => prop_oneof![
+                (identifier(), value(), nested(), any::<bool>()).prop_map(|(binding, iterable, mut body, breaks)| {
+                    if breaks {
+                        body.push(Statement::Break.into());
+                    }
+                    Statement::For { binding, iterable, body }
+                }),
+                (value(), nested(), any::<bool>()).prop_map(|(condition, mut body, breaks)| {
+                    if breaks {
+                        body.push(Statement::Break.into());
+                    }
+                    Statement::While { condition, body }
+                }),
+                (value(), prop::option::of(identifier()), nested()).prop_map(|(resource, binding, body)| Statement::With { resource, binding, body }),
+            ],
+            returned => prop_oneof![
+                yielded => value().prop_map(|value| Statement::Yield { value }),
+                1 => prop::option::of(value()).prop_map(|value| Statement::Return { value }),
+            ],
+            1 => (nested(), prop::option::of(identifier()), prop::option::of(nested()), prop::option::of(nested()))
+                .prop_filter("try needs a catch or finally", |(_, _, catch, finally)| catch.is_some() || finally.is_some())
+                .prop_map(|(body, binding, catch, finally)| Statement::Try { binding: catch.as_ref().and(binding), body, catch, finally }),
+            1 => (value(), nested(), prop::option::of(nested())).prop_map(|(condition, then, otherwise)| Statement::If { condition, then, otherwise }),
+        ].boxed()
+    }
+
+    proptest! {
+        #[test]
+        fn it_round_trips_through_the_parser(program in block(3, None)) {
+            let printed = print(&program);
+            let tokens = crate::token::generate(&printed).into_iter().map(|t| t.unwrap()).collect();
+
+            prop_assert_eq!(crate::parser::parse(tokens).unwrap(), program, "printed as:\n{}", printed);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Walking an AST by reference. Override the `visit_*` methods for the nodes
+//! you care about and call the matching `walk_*` function to keep descending.
+
+use super::*;
+
+pub trait Visitor: Sized {
+    fn visit_block(&mut self, block: &[Spanned<Statement>]) {
+        walk_block(self, block)
+    }
+
+    fn visit_statement(&mut self, statement: &Spanned<Statement>) {
+        walk_statement(self, statement)
+    }
+
+    fn visit_expression(&mut self, expression: &Spanned<Expression>) {
+        walk_expression(self, expression)
+    }
+
+    fn visit_parameter(&mut self, parameter: &Spanned<Parameter>) {
+        walk_parameter(self, parameter)
+    }
+
+    fn visit_argument(&mut self, argument: &Argument) {
+        walk_argument(self, argument)
+    }
+
+    fn visit_clause(&mut self, clause: &Clause) {
+        walk_clause(self, clause)
+    }
+
+    fn visit_attribute(&mut self, attribute: &Spanned<Attribute>) {
+        walk_attribute(self, attribute)
+    }
+
+    fn visit_trait_method(&mut self, method: &Spanned<TraitMethod>) {
+        walk_trait_method(self, method)
+    }
+}
+
+pub fn walk_block<V: Visitor>(visitor: &mut V, block: &[Spanned<Statement>]) {
+    for statement in block {
+        visitor.visit_statement(statement);
+    }
+}
+
+pub fn walk_statement<V: Visitor>(visitor: &mut V, statement: &Spanned<Statement>) {
+    match &statement.node {
+        Statement::FunctionDeclaration { attributes, params, body, .. } => {
+            for attribute in attributes {
+                visitor.visit_attribute(attribute);
+            }
+            for param in params {
+                visitor.visit_parameter(param);
+            }
+            visitor.visit_block(body);
+        },
+        Statement::LetDeclaration { initial, .. } => {
+            if let Some(initial) = initial {
+                visitor.visit_expression(initial);
+            }
+        },
+        Statement::ConstDeclaration { value, .. } => visitor.visit_expression(value),
+        Statement::Import { .. } | Statement::Struct { .. } | Statement::Macro { .. } | Statement::Break => {},
+        Statement::Impl { methods, .. } => visitor.visit_block(methods),
+        Statement::Trait { methods, .. } => {
+            for method in methods {
+                visitor.visit_trait_method(method);
+            }
+        },
+        Statement::Export { declaration } => visitor.visit_statement(declaration),
+        Statement::For { iterable, body, .. } => {
+            visitor.visit_expression(iterable);
+            visitor.visit_block(body);
+        },
+        Statement::While { condition, body } => {
+            visitor.visit_expression(condition);
+            visitor.visit_block(body);
+        },
+        Statement::Yield { value } | Statement::Throw { value } | Statement::Defer { value } => visitor.visit_expression(value),
+        Statement::Return { value } => {
+            if let Some(value) = value {
+                visitor.visit_expression(value);
+            }
+        },
+        Statement::With { resource, body, .. } => {
+            visitor.visit_expression(resource);
+            visitor.visit_block(body);
+        },
+        Statement::Try { body, catch, finally, .. } => {
+            visitor.visit_block(body);
+            if let Some(catch) = catch {
+                visitor.visit_block(catch);
+            }
+            if let Some(finally) = finally {
+                visitor.visit_block(finally);
+            }
+        },
+        Statement::If { condition, then, otherwise } => {
+            visitor.visit_expression(condition);
+            visitor.visit_block(then);
+            if let Some(otherwise) = otherwise {
+                visitor.visit_block(otherwise);
+            }
+        },
+        Statement::Expression { expression } => visitor.visit_expression(expression),
+    }
+}
+
+pub fn walk_expression<V: Visitor>(visitor: &mut V, expression: &Spanned<Expression>) {
+    match &expression.node {
+        Expression::Number(_)
+        | Expression::String(_)
+        | Expression::InterpolatedString(_)
+        | Expression::Bool(_)
+        | Expression::Nil
+        | Expression::Identifier(_)
+        | Expression::MacroCall(..) => {},
+        Expression::Infix(left, _, right)
+        | Expression::Index(left, right)
+        | Expression::OptionalIndex(left, right)
+        | Expression::Range(left, right, _) => {
+            visitor.visit_expression(left);
+            visitor.visit_expression(right);
+        },
+        Expression::Prefix(_, value)
+        | Expression::Get(value, _)
+        | Expression::OptionalGet(value, _)
+        | Expression::Propagate(value)
+        | Expression::Await(value) => visitor.visit_expression(value),
+        Expression::List(items) => {
+            for item in items {
+                visitor.visit_expression(item);
+            }
+        },
+        Expression::ListComprehension(item, clauses) => {
+            for clause in clauses {
+                visitor.visit_clause(clause);
+            }
+            visitor.visit_expression(item);
+        },
+        Expression::MapComprehension(key, value, clauses) => {
+            for clause in clauses {
+                visitor.visit_clause(clause);
+            }
+            visitor.visit_expression(key);
+            visitor.visit_expression(value);
+        },
+        Expression::Call(callable, arguments) | Expression::OptionalCall(callable, arguments) => {
+            visitor.visit_expression(callable);
+            for argument in arguments {
+                visitor.visit_argument(argument);
+            }
+        },
+        Expression::Closure(params, body) => {
+            for param in params {
+                visitor.visit_parameter(param);
+            }
+            visitor.visit_block(body);
+        },
+    }
+}
+
+pub fn walk_clause<V: Visitor>(visitor: &mut V, clause: &Clause) {
+    match clause {
+        Clause::For(_, iterable) => visitor.visit_expression(iterable),
+        Clause::If(condition) => visitor.visit_expression(condition),
+    }
+}
+
+pub fn walk_parameter<V: Visitor>(visitor: &mut V, parameter: &Spanned<Parameter>) {
+    if let Some(default) = &parameter.default {
+        visitor.visit_expression(default);
+    }
+}
+
+pub fn walk_argument<V: Visitor>(visitor: &mut V, argument: &Argument) {
+    match argument {
+        Argument::Positional(value) | Argument::Named(_, value) | Argument::Spread(value) => visitor.visit_expression(value),
+    }
+}
+
+pub fn walk_attribute<V: Visitor>(visitor: &mut V, attribute: &Spanned<Attribute>) {
+    for argument in &attribute.arguments {
+        visitor.visit_argument(argument);
+    }
+}
+
+pub fn walk_trait_method<V: Visitor>(visitor: &mut V, method: &Spanned<TraitMethod>) {
+    for param in &method.params {
+        visitor.visit_parameter(param);
+    }
+    if let Some(default) = &method.default {
+        visitor.visit_block(default);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Collects every identifier that is read, in source order.
+    struct Reads(Vec<Identifier>);
+
+    impl Visitor for Reads {
+        fn visit_expression(&mut self, expression: &Spanned<Expression>) {
+            if let Expression::Identifier(name) = &expression.node {
+                self.0.push(name.clone());
+            }
+            walk_expression(self, expression)
+        }
+    }
+
+    #[test]
+    fn it_visits_every_nested_expression() {
+        let tokens = crate::token::generate("fn f(a, b = c) { for x in xs { g(a, ...x) } }\ntry { h()?.i } catch e { [|y| y + z] }")
+            .into_iter()
+            .map(|t| t.unwrap())
+            .collect();
+        let program = crate::parser::parse(tokens).unwrap();
+
+        let mut reads = Reads(Vec::new());
+        reads.visit_block(&program);
+        assert_eq!(reads.0, vec!["c", "xs", "g", "a", "x", "h", "y", "z"]);
+    }
+}
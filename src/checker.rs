@@ -0,0 +1,841 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result};
+use thiserror::Error;
+
+use crate::ast::*;
+
+/// Runs the gradual type checker over a program. Anything without an
+/// annotation is typed as `Any`, which is compatible with every other type,
+/// so unannotated code stays dynamically typed.
+pub fn check(program: &Program) -> Vec<Spanned<TypeError>> {
+    let mut checker = Checker::new();
+    checker.check_block(program);
+    checker.errors
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ty {
+    Any,
+    Number,
+    String,
+    Bool,
+    Nil,
+    List(Box<Ty>),
+    Map(Box<Ty>, Box<Ty>),
+    Range,
+    Union(Vec<Ty>),
+    Function(Vec<ParamTy>, Box<Ty>),
+    Module,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamTy {
+    pub name: Identifier,
+    pub ty: Ty,
+    pub optional: bool,
+    pub variadic: bool,
+}
+
+impl Ty {
+    fn union(members: Vec<Ty>) -> Self {
+        let mut unique: Vec<Ty> = Vec::new();
+        for member in members {
+            if !unique.contains(&member) {
+                unique.push(member);
+            }
+        }
+
+        let mut members = unique;
+        if members.len() == 1 {
+            members.remove(0)
+        } else {
+            Ty::Union(members)
+        }
+    }
+
+    /// This type, or nil. Anything may already be nil.
+    fn or_nil(self) -> Self {
+        match self {
+            Ty::Any => Ty::Any,
+            Ty::Union(mut members) => {
+                members.push(Ty::Nil);
+                Ty::union(members)
+            },
+            ty => Ty::union(vec![ty, Ty::Nil]),
+        }
+    }
+
+    /// This type once a value of it is known not to be nil.
+    fn without_nil(self) -> Self {
+        match self {
+            Ty::Union(members) => Ty::union(members.into_iter().filter(|member| *member != Ty::Nil).collect()),
+            ty => ty,
+        }
+    }
+
+    /// The type of each item iterating over a value of this type produces.
+    fn item(self) -> Self {
+        match self {
+            Ty::List(item) => *item,
+            Ty::Range => Ty::Number,
+            // Maps iterate as `[key, value]` pairs.
+            Ty::Map(key, value) => Ty::List(Box::new(Ty::union(vec![*key, *value]))),
+            _ => Ty::Any,
+        }
+    }
+
+    /// Whether a value of type `self` may be a struct instance, which has methods.
+    fn may_be_instance(&self) -> bool {
+        match self {
+            Ty::Any => true,
+            Ty::Union(members) => members.iter().any(Ty::may_be_instance),
+            _ => false,
+        }
+    }
+
+    /// Whether a value of type `self` may be used where `target` is expected.
+    pub fn is_assignable_to(&self, target: &Ty) -> bool {
+        match (self, target) {
+            (Ty::Any, _) | (_, Ty::Any) => true,
+            (Ty::Union(members), _) => members.iter().all(|m| m.is_assignable_to(target)),
+            (_, Ty::Union(members)) => members.iter().any(|m| self.is_assignable_to(m)),
+            (Ty::List(from), Ty::List(to)) => from.is_assignable_to(to),
+            (Ty::Map(from_key, from_value), Ty::Map(to_key, to_value)) => {
+                from_key.is_assignable_to(to_key) && from_value.is_assignable_to(to_value)
+            },
+            (Ty::Function(from_params, from_return), Ty::Function(to_params, to_return)) => {
+                from_params.len() == to_params.len()
+                    && from_params.iter().zip(to_params).all(|(from, to)| to.ty.is_assignable_to(&from.ty))
+                    && from_return.is_assignable_to(to_return)
+            },
+            (from, to) => from == to,
+        }
+    }
+}
+
+impl Display for Ty {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Ty::Any => write!(f, "Any"),
+            Ty::Number => write!(f, "Number"),
+            Ty::String => write!(f, "String"),
+            Ty::Bool => write!(f, "Bool"),
+            Ty::Nil => write!(f, "Nil"),
+            Ty::List(item) => write!(f, "List<{}>", item),
+            Ty::Map(key, value) => write!(f, "Map<{}, {}>", key, value),
+            Ty::Range => write!(f, "Range"),
+            Ty::Union(members) => write!(f, "{}", members.iter().map(|m| m.to_string()).collect::<Vec<_>>().join(" | ")),
+            Ty::Function(params, ret) => write!(f, "fn({}) -> {}", describe_params(params), ret),
+            Ty::Module => write!(f, "Module"),
+        }
+    }
+}
+
+fn describe_params(params: &[ParamTy]) -> String {
+    params.iter().map(|p| if p.variadic {
+        format!("...{}: {}", p.name, p.ty)
+    } else if p.optional {
+        format!("[{}]: {}", p.name, p.ty)
+    } else {
+        format!("{}: {}", p.name, p.ty)
+    }).collect::<Vec<_>>().join(", ")
+}
+
+struct Checker {
+    scopes: Vec<HashMap<Identifier, Ty>>,
+    /// Declared structs and traits, which can be named in annotations but whose values are dynamic.
+    types: HashSet<Identifier>,
+    errors: Vec<Spanned<TypeError>>,
+    /// The span of the innermost node being checked, which errors are reported at.
+    span: Span,
+    /// The name and declared return type of the function being checked, which `return` values must match.
+    returns: Option<(Identifier, Ty)>,
+}
+
+impl Checker {
+    fn new() -> Self {
+        Self {
+            scopes: vec![HashMap::new()],
+            types: HashSet::new(),
+            errors: Vec::new(),
+            span: Span::default(),
+            returns: None,
+        }
+    }
+
+    fn check_block(&mut self, block: &[Spanned<Statement>]) -> Ty {
+        // Declare functions up front so calls can be checked before the declaration, as
+        // they run. Decorated ones are declared where they're written, like any other value.
+        for statement in block {
+            let statement = match &statement.node {
+                Statement::Export { declaration } => &declaration.node,
+                statement => statement,
+            };
+            if let Statement::FunctionDeclaration { attributes, name, params, return_type, kind, .. } = statement {
+                if attributes.is_empty() {
+                    // Calling a generator or async function returns an iterator or promise rather than its declared return type.
+                    let return_type = return_type.as_ref().filter(|_| *kind == FunctionKind::Normal);
+                    let ty = self.signature(params, return_type);
+                    self.declare(name, ty);
+                }
+            }
+        }
+
+        let mut tail = Ty::Any;
+        for statement in block {
+            tail = self.check_statement(statement);
+        }
+        tail
+    }
+
+    fn check_statement(&mut self, statement: &Spanned<Statement>) -> Ty {
+        self.at(statement.span, |checker| checker.check_statement_kind(statement))
+    }
+
+    fn check_statement_kind(&mut self, statement: &Statement) -> Ty {
+        match statement {
+            Statement::FunctionDeclaration { attributes, name, params, return_type, body, kind, .. } => {
+                for attribute in attributes.iter().filter(|a| !a.arguments.is_empty()) {
+                    let decorator = Spanned::new(Expression::Identifier(attribute.name.clone()), attribute.span);
+                    self.check_call(&decorator, &attribute.arguments);
+                }
+                // A decorator can replace the function with anything.
+                if !attributes.is_empty() {
+                    self.declare(name, Ty::Any);
+                }
+
+                // A generator's `return` ends it rather than giving its declared type.
+                let declared = return_type.as_ref().map(|t| self.resolve(t)).filter(|_| *kind != FunctionKind::Generator);
+                let returns = declared.clone().map(|declared| (name.clone(), declared));
+                let tail = self.check_function(params, body, returns);
+
+                if let (Some(declared), Some(tail)) = (declared, tail) {
+                    self.expect(&tail, &declared, format!("return value of {}", name));
+                }
+            },
+            Statement::LetDeclaration { name, annotation, initial } => {
+                let declared = annotation.as_ref().map(|t| self.resolve(t));
+                let inferred = initial.as_ref().map(|e| self.infer(e));
+
+                if let (Some(declared), Some(inferred)) = (&declared, &inferred) {
+                    self.expect(inferred, declared, format!("let {}", name));
+                }
+
+                self.declare(name, declared.or(inferred).unwrap_or(Ty::Any));
+            },
+            Statement::ConstDeclaration { name, annotation, value, .. } => {
+                let declared = annotation.as_ref().map(|t| self.resolve(t));
+                let inferred = self.infer(value);
+
+                if let Some(declared) = &declared {
+                    self.expect(&inferred, declared, format!("const {}", name));
+                }
+
+                self.declare(name, declared.unwrap_or(inferred));
+            },
+            // Imported modules aren't checked here, so everything they export is dynamic.
+            Statement::Import { binding, .. } => match binding {
+                ImportBinding::Namespace(alias) => self.declare(alias, Ty::Module),
+                ImportBinding::Names(names) => for name in names {
+                    self.declare(name, Ty::Any)
+                },
+            },
+            Statement::Export { declaration } => return self.check_statement(declaration),
+            Statement::For { binding, iterable, body } => {
+                let item = self.infer(iterable).item();
+                self.scoped(|checker| {
+                    checker.declare(binding, item);
+                    checker.check_block(body)
+                });
+            },
+            Statement::Yield { value } | Statement::Throw { value } | Statement::Defer { value } => {
+                self.infer(value);
+            },
+            Statement::While { condition, body } => {
+                self.infer(condition);
+                self.scoped(|checker| checker.check_block(body));
+            },
+            Statement::Return { value } => {
+                let found = value.as_ref().map_or(Ty::Nil, |value| self.infer(value));
+                if let Some((name, declared)) = self.returns.clone() {
+                    self.expect(&found, &declared, format!("return value of {}", name));
+                }
+            },
+            Statement::Break => {},
+            Statement::With { resource, binding, body } => {
+                let resource = self.infer(resource);
+                return self.scoped(|checker| {
+                    if let Some(binding) = binding {
+                        checker.declare(binding, resource);
+                    }
+                    checker.check_block(body)
+                })
+            },
+            Statement::Try { body, binding, catch, finally } => {
+                let tail = self.scoped(|checker| checker.check_block(body));
+                if let Some(catch) = catch {
+                    self.scoped(|checker| {
+                        if let Some(binding) = binding {
+                            checker.declare(binding, Ty::Any);
+                        }
+                        checker.check_block(catch)
+                    });
+                }
+                if let Some(finally) = finally {
+                    self.scoped(|checker| checker.check_block(finally));
+                }
+                // The value comes from either the body or the catch block, so only a lone `try` keeps its type.
+                return if catch.is_none() { tail } else { Ty::Any }
+            },
+            Statement::If { condition, then, otherwise } => {
+                self.infer(condition);
+                self.scoped(|checker| checker.check_block(then));
+                if let Some(otherwise) = otherwise {
+                    self.scoped(|checker| checker.check_block(otherwise));
+                }
+            },
+            Statement::Expression { expression } => return self.infer(expression),
+            Statement::Struct { name, fields, .. } => {
+                let params = fields.iter().map(|field| ParamTy { name: field.clone(), ty: Ty::Any, optional: false, variadic: false }).collect();
+                self.types.insert(name.clone());
+                self.declare(name, Ty::Function(params, Box::new(Ty::Any)));
+            },
+            Statement::Impl { methods, .. } => {
+                self.scoped(|checker| checker.check_block(methods));
+            },
+            Statement::Trait { name, methods } => {
+                self.types.insert(name.clone());
+                self.declare(name, Ty::Any);
+                for method in methods {
+                    if let Some(default) = &method.default {
+                        let declared = method.return_type.as_ref().map(|t| self.resolve(t));
+                        let returns = declared.clone().map(|declared| (method.name.clone(), declared));
+                        let tail = self.check_function(&method.params, default, returns);
+                        if let (Some(declared), Some(tail)) = (declared, tail) {
+                            self.expect(&tail, &declared, format!("return value of {}", method.name));
+                        }
+                    }
+                }
+            },
+            // Macros are expanded before checking.
+            Statement::Macro { .. } => {},
+        }
+
+        Ty::Any
+    }
+
+    /// Checks a function body with its parameters in scope, returning the type of its tail expression.
+    /// `returns` is what its `return` values are checked against.
+    fn check_function(&mut self, params: &[Spanned<Parameter>], body: &[Spanned<Statement>], returns: Option<(Identifier, Ty)>) -> Option<Ty> {
+        let outer = std::mem::replace(&mut self.returns, returns);
+        let tail = self.scoped(|checker| {
+            for param in params {
+                let ty = param.annotation.as_ref().map(|t| checker.resolve(t)).unwrap_or(Ty::Any);
+
+                if let Some(default) = &param.default {
+                    let found = checker.infer(default);
+                    checker.expect(&found, &ty, format!("default value of {}", param.name));
+                }
+
+                let ty = if param.variadic { Ty::List(Box::new(ty)) } else { ty };
+                checker.declare(&param.name, ty);
+            }
+
+            let tail = checker.check_block(body);
+            matches!(body.last().map(|s| &s.node), Some(Statement::Expression { .. })).then_some(tail)
+        });
+        self.returns = outer;
+        tail
+    }
+
+    fn infer(&mut self, expression: &Spanned<Expression>) -> Ty {
+        self.at(expression.span, |checker| checker.infer_kind(expression))
+    }
+
+    fn infer_kind(&mut self, expression: &Expression) -> Ty {
+        match expression {
+            Expression::Number(_) => Ty::Number,
+            Expression::String(_) | Expression::InterpolatedString(_) => Ty::String,
+            Expression::Bool(_) => Ty::Bool,
+            Expression::Nil => Ty::Nil,
+            Expression::Identifier(name) => self.lookup(name),
+            Expression::List(items) => {
+                let items: Vec<Ty> = items.iter().map(|i| self.infer(i)).collect();
+                Ty::List(Box::new(if items.is_empty() { Ty::Any } else { Ty::union(items) }))
+            },
+            Expression::ListComprehension(item, clauses) => self.scoped(|checker| {
+                checker.check_clauses(clauses);
+                Ty::List(Box::new(checker.infer(item)))
+            }),
+            Expression::MapComprehension(key, value, clauses) => self.scoped(|checker| {
+                checker.check_clauses(clauses);
+                Ty::Map(Box::new(checker.infer(key)), Box::new(checker.infer(value)))
+            }),
+            Expression::Infix(left, op, right) => {
+                let left = self.infer(left);
+                let right = self.infer(right);
+
+                match (op, &left, &right) {
+                    // The right side is only used when the left is nil.
+                    (Op::Coalesce, Ty::Nil, _) => right,
+                    (Op::Coalesce, Ty::Union(members), _) if members.contains(&Ty::Nil) => {
+                        Ty::union(vec![left.without_nil(), right])
+                    },
+                    (Op::Coalesce, _, _) => left,
+                    (Op::Equal | Op::NotEqual | Op::Implements | Op::In, _, _) => Ty::Bool,
+                    (Op::Less | Op::LessEqual | Op::Greater | Op::GreaterEqual, l, r)
+                        if (l.is_assignable_to(&Ty::Number) && r.is_assignable_to(&Ty::Number))
+                            || (l.is_assignable_to(&Ty::String) && r.is_assignable_to(&Ty::String)) => Ty::Bool,
+                    (Op::Less | Op::LessEqual | Op::Greater | Op::GreaterEqual, _, _) => {
+                        self.error(TypeError::InvalidOperands { op: op.clone(), left, right });
+                        Ty::Bool
+                    },
+                    (_, Ty::Number, Ty::Number) => Ty::Number,
+                    (_, l, r) if l.is_assignable_to(&Ty::Number) && r.is_assignable_to(&Ty::Number) => Ty::Any,
+                    (Op::Add, Ty::String, Ty::String) => Ty::String,
+                    (Op::Add, l, r) if l.is_assignable_to(&Ty::String) && r.is_assignable_to(&Ty::String) => Ty::Any,
+                    _ => {
+                        self.error(TypeError::InvalidOperands { op: op.clone(), left, right });
+                        Ty::Any
+                    },
+                }
+            },
+            Expression::Prefix(op, right) => {
+                let right = self.infer(right);
+                if *op == Op::Subtract {
+                    self.expect(&right, &Ty::Number, "negation".to_string());
+                }
+                Ty::Any
+            },
+            Expression::Closure(params, body) => {
+                let ty = self.signature(params, None);
+                self.check_function(params, body, None);
+                ty
+            },
+            Expression::Call(callable, arguments) => self.check_call(callable, arguments),
+            Expression::Index(value, index) => {
+                let value = self.infer(value);
+                let index = self.infer(index);
+                self.index(value, index)
+            },
+            Expression::OptionalIndex(value, index) => match self.infer(value) {
+                Ty::Nil => Ty::Nil,
+                value => {
+                    let index = self.infer(index);
+                    self.index(value.without_nil(), index).or_nil()
+                },
+            },
+            Expression::OptionalGet(receiver, _) => {
+                self.infer(receiver);
+                Ty::Any
+            },
+            Expression::OptionalCall(callable, arguments) => {
+                self.infer(callable);
+                for argument in arguments {
+                    match argument {
+                        Argument::Positional(a) | Argument::Named(_, a) | Argument::Spread(a) => self.infer(a),
+                    };
+                }
+                Ty::Any
+            },
+            Expression::Range(start, end, _) => {
+                let start = self.infer(start);
+                let end = self.infer(end);
+                self.expect(&start, &Ty::Number, "range start".to_string());
+                self.expect(&end, &Ty::Number, "range end".to_string());
+                Ty::Range
+            },
+            Expression::Get(receiver, _) => {
+                self.infer(receiver);
+                Ty::Any
+            },
+            // Only error values are propagated, so whatever is left over is dynamic.
+            Expression::Propagate(value) => {
+                self.infer(value);
+                Ty::Any
+            },
+            Expression::Await(value) => {
+                self.infer(value);
+                Ty::Any
+            },
+            Expression::MacroCall(..) => Ty::Any,
+        }
+    }
+
+    /// Declares the names a comprehension's clauses bind in the current scope.
+    fn check_clauses(&mut self, clauses: &[Clause]) {
+        for clause in clauses {
+            match clause {
+                Clause::For(pattern, iterable) => match (pattern, self.infer(iterable)) {
+                    (Pattern::Tuple(parts), Ty::Map(key, value)) if parts.len() == 2 => {
+                        self.bind(&parts[0], *key);
+                        self.bind(&parts[1], *value);
+                    },
+                    (pattern, iterable) => self.bind(pattern, iterable.item()),
+                },
+                Clause::If(condition) => {
+                    self.infer(condition);
+                },
+            }
+        }
+    }
+
+    fn bind(&mut self, pattern: &Pattern, ty: Ty) {
+        match pattern {
+            Pattern::Name(name) => self.declare(name, ty),
+            Pattern::Tuple(patterns) => {
+                let item = match ty {
+                    Ty::List(item) => *item,
+                    _ => Ty::Any,
+                };
+                for pattern in patterns {
+                    self.bind(pattern, item.clone());
+                }
+            },
+        }
+    }
+
+    fn index(&mut self, value: Ty, index: Ty) -> Ty {
+        match value {
+            // Indexing with a range slices.
+            Ty::List(_) | Ty::String if index == Ty::Range => value,
+            Ty::List(item) => {
+                self.expect(&index, &Ty::Number, "list index".to_string());
+                *item
+            },
+            Ty::String => {
+                self.expect(&index, &Ty::Number, "string index".to_string());
+                Ty::String
+            },
+            Ty::Map(key, value) => {
+                self.expect(&index, &key, "map key".to_string());
+                *value
+            },
+            _ => Ty::Any,
+        }
+    }
+
+    fn check_call(&mut self, callable: &Spanned<Expression>, arguments: &[Argument]) -> Ty {
+        let (function, ty, mut positional) = match &callable.node {
+            Expression::Get(receiver, name) => match self.infer(receiver) {
+                Ty::Module => (name.clone(), Ty::Any, Vec::new()),
+                // Only struct instances have methods, and their types aren't known, so a call on
+                // a receiver that may be one stays dynamic. Other receivers call a function by that name.
+                receiver if receiver.may_be_instance() => (name.clone(), Ty::Any, Vec::new()),
+                receiver => (name.clone(), self.lookup(name), vec![receiver]),
+            },
+            Expression::Identifier(name) => (name.clone(), self.lookup(name), Vec::new()),
+            _ => (String::from("<Closure>"), self.infer(callable), Vec::new()),
+        };
+
+        let mut named = Vec::new();
+        let mut spread = false;
+        for argument in arguments {
+            match argument {
+                Argument::Positional(a) => positional.push(self.infer(a)),
+                Argument::Named(name, a) => named.push((name.clone(), self.infer(a))),
+                Argument::Spread(a) => {
+                    let found = self.infer(a);
+                    self.expect(&found, &Ty::List(Box::new(Ty::Any)), format!("spread argument of {}", function));
+                    spread = true;
+                },
+            }
+        }
+
+        let (params, ret) = match ty {
+            Ty::Function(params, ret) => (params, ret),
+            Ty::Any => return Ty::Any,
+            ty => {
+                self.error(TypeError::NotCallable(ty));
+                return Ty::Any
+            },
+        };
+
+        let given = positional.len() + named.len();
+        let arity_mismatch = || TypeError::ArityMismatch { function: function.clone(), params: describe_params(&params), given };
+        let mut positional = positional.into_iter();
+
+        for param in &params {
+            let found = if param.variadic {
+                positional.by_ref().collect()
+            } else if let Some(found) = positional.next() {
+                vec![found]
+            } else if let Some(index) = named.iter().position(|(name, _)| name == &param.name) {
+                vec![named.remove(index).1]
+            } else if param.optional || spread {
+                Vec::new()
+            } else {
+                self.error(arity_mismatch());
+                return *ret
+            };
+
+            for found in found {
+                self.expect(&found, &param.ty, format!("argument {} of {}", param.name, function));
+            }
+        }
+
+        if positional.next().is_some() {
+            self.error(arity_mismatch());
+        }
+
+        for (argument, _) in named {
+            self.error(TypeError::UnknownArgument { function: function.clone(), argument });
+        }
+
+        *ret
+    }
+
+    fn signature(&mut self, params: &[Spanned<Parameter>], return_type: Option<&Type>) -> Ty {
+        let params = params.iter().map(|p| ParamTy {
+            name: p.name.clone(),
+            ty: p.annotation.as_ref().map(|t| self.resolve(t)).unwrap_or(Ty::Any),
+            optional: p.default.is_some(),
+            variadic: p.variadic,
+        }).collect();
+        let ret = return_type.map(|t| self.resolve(t)).unwrap_or(Ty::Any);
+
+        Ty::Function(params, Box::new(ret))
+    }
+
+    fn resolve(&mut self, ty: &Type) -> Ty {
+        match ty {
+            Type::Union(members) => Ty::union(members.iter().map(|m| self.resolve(m)).collect()),
+            Type::Named(name, arguments) => {
+                let mut arguments: Vec<Ty> = arguments.iter().map(|a| self.resolve(a)).collect();
+                let expected = match name.as_str() {
+                    "Any" | "Number" | "String" | "Bool" | "Nil" | "Range" => 0,
+                    "List" => 1,
+                    "Map" => 2,
+                    name if self.types.contains(name) => 0,
+                    _ => {
+                        self.error(TypeError::UnknownType(name.clone()));
+                        return Ty::Any
+                    },
+                };
+
+                // A bare `List` or `Map` leaves its element types dynamic.
+                if arguments.is_empty() {
+                    arguments = vec![Ty::Any; expected];
+                } else if arguments.len() != expected {
+                    self.error(TypeError::WrongTypeArguments { name: name.clone(), expected, given: arguments.len() });
+                    return Ty::Any
+                }
+
+                let mut arguments = arguments.into_iter().map(Box::new);
+                match name.as_str() {
+                    "Number" => Ty::Number,
+                    "String" => Ty::String,
+                    "Bool" => Ty::Bool,
+                    "Nil" => Ty::Nil,
+                    "Range" => Ty::Range,
+                    "List" => Ty::List(arguments.next().unwrap()),
+                    "Map" => Ty::Map(arguments.next().unwrap(), arguments.next().unwrap()),
+                    _ => Ty::Any,
+                }
+            },
+        }
+    }
+
+    fn expect(&mut self, found: &Ty, expected: &Ty, context: String) {
+        if !found.is_assignable_to(expected) {
+            self.error(TypeError::Mismatch { expected: expected.clone(), found: found.clone(), context });
+        }
+    }
+
+    fn error(&mut self, error: TypeError) {
+        self.errors.push(Spanned::new(error, self.span));
+    }
+
+    fn at<T>(&mut self, span: Span, f: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = std::mem::replace(&mut self.span, span);
+        let result = f(self);
+        self.span = outer;
+        result
+    }
+
+    fn scoped<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        self.scopes.push(HashMap::new());
+        let result = f(self);
+        self.scopes.pop();
+        result
+    }
+
+    fn declare(&mut self, name: &str, ty: Ty) {
+        self.scopes.last_mut().unwrap().insert(name.to_string(), ty);
+    }
+
+    fn lookup(&self, name: &str) -> Ty {
+        self.scopes.iter().rev().find_map(|s| s.get(name)).cloned().unwrap_or(Ty::Any)
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum TypeError {
+    #[error("Expected {expected} but found {found} in {context}.")]
+    Mismatch { expected: Ty, found: Ty, context: String },
+    #[error("Wrong number of arguments for {function}: expected ({params}), got {given}.")]
+    ArityMismatch { function: String, params: String, given: usize },
+    #[error("{function}() has no parameter named {argument}.")]
+    UnknownArgument { function: String, argument: Identifier },
+    #[error("{0} is not callable.")]
+    NotCallable(Ty),
+    #[error("Operator {op:?} cannot be applied to {left} and {right}.")]
+    InvalidOperands { op: Op, left: Ty, right: Ty },
+    #[error("Unknown type {0}.")]
+    UnknownType(Identifier),
+    #[error("{name} expects {expected} type argument(s), got {given}.")]
+    WrongTypeArguments { name: Identifier, expected: usize, given: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(source: &str) -> Vec<TypeError> {
+        let tokens = crate::token::generate(source).into_iter().map(|t| t.unwrap()).collect();
+        check(&crate::parser::parse(tokens).unwrap()).into_iter().map(|e| e.node).collect()
+    }
+
+    #[test]
+    fn it_accepts_unannotated_code() {
+        assert_eq!(errors("fn add(a, b) { a + b }\nlet x = add(1, \"two\")\nx(1)"), vec![]);
+    }
+
+    #[test]
+    fn it_reports_mismatched_annotations() {
+        assert_eq!(errors("let name: String = 1"), vec![TypeError::Mismatch {
+            expected: Ty::String,
+            found: Ty::Number,
+            context: "let name".to_string(),
+        }]);
+        assert_eq!(errors("fn add(a: Number, b: Number) -> Number { a + b }\nadd(1, \"2\")"), vec![TypeError::Mismatch {
+            expected: Ty::Number,
+            found: Ty::String,
+            context: "argument b of add".to_string(),
+        }]);
+        assert_eq!(errors("fn name() -> String { 1 }").len(), 1);
+    }
+
+    #[test]
+    fn it_checks_generic_and_union_types() {
+        assert_eq!(errors("let xs: List<Number | String> = [1, \"two\"]"), vec![]);
+        assert_eq!(errors("let xs: List<Number> = [1, \"two\"]"), vec![TypeError::Mismatch {
+            expected: Ty::List(Box::new(Ty::Number)),
+            found: Ty::List(Box::new(Ty::Union(vec![Ty::Number, Ty::String]))),
+            context: "let xs".to_string(),
+        }]);
+        assert_eq!(errors("let m: Map<String> = x"), vec![TypeError::WrongTypeArguments {
+            name: "Map".to_string(),
+            expected: 2,
+            given: 1,
+        }]);
+        assert_eq!(errors("let x: Strnig = 1"), vec![TypeError::UnknownType("Strnig".to_string())]);
+    }
+
+    #[test]
+    fn it_reports_wrong_arity_and_calls_to_non_callables() {
+        assert_eq!(errors("add(1)\nfn add(a, b = 1, ...rest) { a }\nadd()"), vec![TypeError::ArityMismatch {
+            function: "add".to_string(),
+            params: "a: Any, [b]: Any, ...rest: Any".to_string(),
+            given: 0,
+        }]);
+        assert_eq!(errors("let x = 1\nx()"), vec![TypeError::NotCallable(Ty::Number)]);
+        assert_eq!(errors("fn f(a) { a }\nf(1, b: 1)"), vec![TypeError::UnknownArgument {
+            function: "f".to_string(),
+            argument: "b".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn it_checks_decorator_arguments_but_not_what_decorators_return() {
+        assert_eq!(errors("@memoize\nfn add(a, b) { a + b }\nadd(1)"), vec![]);
+        assert_eq!(errors("fn scale(k: Number) { |f| f }\n@scale(\"ten\")\nfn id(x) { x }").len(), 1);
+    }
+
+    #[test]
+    fn it_checks_struct_construction_comparisons_and_indexing() {
+        assert_eq!(errors("struct Point { x, y }\nlet p: Point = Point(1, y: 2)\np + p < p"), vec![]);
+        assert_eq!(errors("trait Shape { fn area(self) -> Number { \"none\" } }\nlet s: Shape = 1\nlet ok: Bool = s implements Shape").len(), 1);
+        assert!(matches!(errors("struct Point { x, y }\nPoint(1)").as_slice(), [TypeError::ArityMismatch { .. }]));
+        assert_eq!(errors("let ok: Bool = 1 < 2 == (\"a\" >= \"b\")"), vec![]);
+        assert!(matches!(errors("1 < \"a\"").as_slice(), [TypeError::InvalidOperands { op: Op::Less, .. }]));
+        assert_eq!(errors("fn greet(name) { let s: String = \"Hi, \" + \"you\"\n\"Hi, \" + name }"), vec![]);
+        assert!(matches!(errors("\"a\" + 1").as_slice(), [TypeError::InvalidOperands { op: Op::Add, .. }]));
+        assert!(matches!(errors("let xs: List<Number> = [1]\nlet s: String = xs[0]").as_slice(), [TypeError::Mismatch { .. }]));
+    }
+
+    #[test]
+    fn it_types_comprehensions_and_scopes_their_bindings() {
+        assert_eq!(errors("let doubled: List<Number> = [x * 2 for x in 1..10 if x > 3]"), vec![]);
+        assert_eq!(errors("fn invert(names: Map<String, Number>) -> Map<Number, String> { {v: k for (k, v) in names} }"), vec![]);
+        assert!(matches!(errors("let xs: List<String> = [x for x in [1, 2]]").as_slice(), [TypeError::Mismatch { .. }]));
+        // The binding is only in scope inside the comprehension.
+        assert_eq!(errors("let x = \"s\"\nlet ys = [x for x in [1]]\nlet s: String = x"), vec![]);
+    }
+
+    #[test]
+    fn it_checks_deferred_values_and_with_bodies() {
+        assert_eq!(errors("with [1] as xs { let n: Number = xs[0] }\ndefer 1 + 2"), vec![]);
+        assert!(matches!(errors("with [1] as xs { let s: String = xs[0] }").as_slice(), [TypeError::Mismatch { .. }]));
+        assert!(matches!(errors("defer 1 < \"a\"").as_slice(), [TypeError::InvalidOperands { .. }]));
+    }
+
+    #[test]
+    fn it_types_nil_and_coalescing() {
+        assert_eq!(errors("let name: String | Nil = nil\nlet s: String = name ?? \"anonymous\"\nlet n: Number | Nil = [1]?.[0]"), vec![]);
+        assert!(matches!(errors("let name: String | Nil = nil\nlet s: String = name").as_slice(), [TypeError::Mismatch { .. }]));
+        assert!(matches!(errors("let n: Number = nil ?? \"x\"").as_slice(), [TypeError::Mismatch { found: Ty::String, .. }]));
+    }
+
+    #[test]
+    fn it_checks_ranges_and_slices() {
+        assert_eq!(errors("let r: Range = 1..=10\nlet xs: List<Number> = [1, 2][0..1]\nlet found: Bool = 3 in r\nfor i in r { let n: Number = i }"), vec![]);
+        assert_eq!(errors("for i in 0..3 { let s: String = i }").len(), 1);
+        assert!(matches!(errors("1..\"a\"").as_slice(), [TypeError::Mismatch { expected: Ty::Number, .. }]));
+    }
+
+    #[test]
+    fn it_types_loop_variables_and_generator_calls() {
+        assert_eq!(errors("let xs: List<Number> = [1]\nfor x in xs { let s: String = x }").len(), 1);
+        assert_eq!(errors("fn* count() -> Number { yield 1 }\nlet it: List<Any> = count()"), vec![]);
+    }
+
+    #[test]
+    fn it_treats_imported_members_as_dynamic() {
+        assert_eq!(errors("import \"./util\" as util\nfn parse(s: String) { s }\nutil.parse(1)"), vec![]);
+    }
+
+    #[test]
+    fn it_checks_pipes_and_method_calls_against_the_receiver() {
+        assert_eq!(errors("fn twice(n: Number) -> Number { n * 2 }\n\"x\" |> twice\n\"y\".twice()").len(), 2);
+    }
+
+    #[test]
+    fn it_checks_returned_values_against_the_declared_return_type() {
+        assert_eq!(errors("fn f() -> Number { return \"x\" }"), vec![TypeError::Mismatch {
+            expected: Ty::Number,
+            found: Ty::String,
+            context: "return value of f".to_string(),
+        }]);
+        assert_eq!(errors("fn f(x) -> Number {\n    for y in x { return }\n    1\n}").len(), 1);
+        assert_eq!(errors("fn f(x) -> Number | Nil {\n    for y in x { return 1 }\n    return\n}"), vec![]);
+        assert_eq!(errors("fn f() -> Number {\n    let g = || { return \"inner\" }\n    return 2\n}"), vec![]);
+        assert_eq!(errors("fn* f() -> Number {\n    yield 1\n    return\n}"), vec![]);
+    }
+
+    #[test]
+    fn it_leaves_method_calls_on_possible_instances_dynamic() {
+        assert_eq!(errors("struct Sq { s }\nimpl Sq {\n    fn area(self) { self.s * self.s }\n}\nlet area = 1\nSq(2).area()"), vec![]);
+        assert_eq!(errors("fn one(x) { x }\nfn f(x) { x.one(2, 3) }"), vec![]);
+        assert_eq!(errors("let area = 1\n[1].area()"), vec![TypeError::NotCallable(Ty::Number)]);
+    }
+
+    #[test]
+    fn it_reports_errors_at_the_offending_node() {
+        let tokens = crate::token::generate("fn f(a: Number) { a }\nlet ok = 1\nlet x = [f(\"one\")]").into_iter().map(|t| t.unwrap()).collect();
+        let errors = check(&crate::parser::parse(tokens).unwrap());
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span.to_string(), "3:10");
+    }
+}
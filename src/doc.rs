@@ -0,0 +1,359 @@
+//! `crustacean doc`: a Markdown site built from the `///` comments of every
+//! script under a directory, with a page per script, an index, and a search
+//! index for tools to load.
+//!
+//! Fenced code blocks in doc comments are examples, run by `crustacean doc
+//! --test` as scripts of their own. They import the script they document like
+//! any other script would, with `import "./name"`. Blocks fenced with another
+//! language, like ```` ```text ````, are left alone.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::ast::{FunctionKind, Program, Spanned, Statement};
+use crate::module::EXTENSION;
+
+/// Everything documented in one script.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page {
+    /// The script's path under the documented directory, without its extension.
+    pub module: String,
+    pub items: Vec<Item>,
+}
+
+/// A declaration shown on a page: every exported one, and any other with a doc comment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    /// The declared name, or `Type.method` for a method.
+    pub name: String,
+    pub kind: ItemKind,
+    /// The declaration as it would be written, without its body.
+    pub signature: String,
+    pub doc: Option<String>,
+    pub exported: bool,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemKind {
+    Function,
+    Method,
+    Struct,
+    Const,
+}
+
+#[derive(Serialize)]
+struct SearchEntry<'a> {
+    name: &'a str,
+    kind: ItemKind,
+    module: &'a str,
+    url: String,
+    summary: &'a str,
+}
+
+#[derive(Debug, Error)]
+pub enum DocError {
+    #[error("{path}: {source}")]
+    Io { path: String, source: std::io::Error },
+    #[error("{path}: {message}")]
+    Invalid { path: String, message: String },
+}
+
+/// Parses every script under `root`, or just `root` when it is a script.
+pub fn collect(root: &Path) -> Result<Vec<Page>, DocError> {
+    let (base, files) = if root.is_dir() {
+        let mut files = Vec::new();
+        scripts(root, &mut files)?;
+        (root.to_path_buf(), files)
+    } else {
+        (root.parent().map(Path::to_path_buf).unwrap_or_default(), vec![root.to_path_buf()])
+    };
+
+    files.iter().map(|file| {
+        let source = fs::read_to_string(file).map_err(|source| DocError::Io { path: file.display().to_string(), source })?;
+        let program = parse(&source).map_err(|message| DocError::Invalid { path: file.display().to_string(), message })?;
+        let module = file.strip_prefix(&base).unwrap_or(file).with_extension("");
+        let module = module.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+        Ok(page(module, &program))
+    }).collect()
+}
+
+/// Writes a page for each script, an `index.md` linking them, and `search-index.json`.
+pub fn write(pages: &[Page], output: &Path) -> Result<(), DocError> {
+    let mut files = vec![
+        (PathBuf::from("index.md"), index(pages)),
+        (PathBuf::from("search-index.json"), search_index(pages)),
+    ];
+    files.extend(pages.iter().map(|page| (PathBuf::from(format!("{}.md", page.module)), render(page, pages))));
+
+    for (file, contents) in files {
+        let file = output.join(file);
+        let io = |source| DocError::Io { path: file.display().to_string(), source };
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent).map_err(io)?;
+        }
+        fs::write(&file, contents).map_err(io)?;
+    }
+    Ok(())
+}
+
+/// Runs every example on every page, reporting each one. Examples run as if
+/// they were a script next to the one they document. Returns whether they all passed.
+pub fn test(pages: &[Page], root: &Path) -> bool {
+    let base = if root.is_dir() { root } else { root.parent().unwrap_or(Path::new(".")) };
+    let mut failed = 0;
+    let mut count = 0;
+    for page in pages {
+        let path = base.join(format!("{}.doctest.{}", page.module, EXTENSION));
+        for item in &page.items {
+            for (i, example) in item.doc.iter().flat_map(|doc| examples(doc)).enumerate() {
+                count += 1;
+                match run(&example, &path) {
+                    Ok(()) => println!("doctest {} {} ({}) ... ok", page.module, item.name, i + 1),
+                    Err(error) => {
+                        failed += 1;
+                        println!("doctest {} {} ({}) ... FAILED\n    {}", page.module, item.name, i + 1, error);
+                    },
+                }
+            }
+        }
+    }
+    println!("{} passed, {} failed", count - failed, failed);
+    failed == 0
+}
+
+/// The items a parsed script documents, in the order they're declared.
+pub fn page(module: String, program: &Program) -> Page {
+    let mut items = Vec::new();
+    for statement in program {
+        match &statement.node {
+            Statement::Export { declaration } => items.extend(item(declaration, true)),
+            Statement::Impl { target, methods, .. } => {
+                items.extend(methods.iter().filter_map(|method| item(method, false)).map(|mut method| {
+                    method.name = format!("{}.{}", target, method.name);
+                    method.kind = ItemKind::Method;
+                    method
+                }).filter(|method| method.doc.is_some()));
+            },
+            _ => items.extend(item(statement, false).filter(|item| item.doc.is_some())),
+        }
+    }
+    Page { module, items }
+}
+
+fn item(statement: &Spanned<Statement>, exported: bool) -> Option<Item> {
+    let (name, kind, signature, doc) = match &statement.node {
+        Statement::FunctionDeclaration { doc, name, params, return_type, kind, .. } => {
+            let keyword = match kind {
+                FunctionKind::Normal => "fn",
+                FunctionKind::Generator => "fn*",
+                FunctionKind::Async => "async fn",
+            };
+            let return_type = return_type.as_ref().map(|t| format!(" -> {}", t)).unwrap_or_default();
+            let signature = format!("{} {}({}){}", keyword, name, crate::ast::print::signature(params), return_type);
+            (name, ItemKind::Function, signature, doc)
+        },
+        Statement::Struct { doc, name, fields } if fields.is_empty() => (name, ItemKind::Struct, format!("struct {} {{}}", name), doc),
+        Statement::Struct { doc, name, fields } => (name, ItemKind::Struct, format!("struct {} {{ {} }}", name, fields.join(", ")), doc),
+        Statement::ConstDeclaration { doc, name, annotation, value } => {
+            let annotation = annotation.as_ref().map(|t| format!(": {}", t)).unwrap_or_default();
+            (name, ItemKind::Const, format!("const {}{} = {}", name, annotation, value.node), doc)
+        },
+        _ => return None,
+    };
+    Some(Item { name: name.clone(), kind, signature, doc: doc.clone(), exported, line: statement.span.line })
+}
+
+/// The code in each fenced block of a doc comment, apart from blocks in other languages.
+pub fn examples(doc: &str) -> Vec<String> {
+    let mut examples = Vec::new();
+    let mut current: Option<Vec<&str>> = None;
+    let mut skipping = false;
+    for line in doc.lines() {
+        match (line.trim().strip_prefix("```"), current.as_mut()) {
+            (Some(_), Some(_)) => examples.push(current.take().unwrap().join("\n")),
+            (Some(_), None) if skipping => skipping = false,
+            (Some("" | "crustacean" | EXTENSION), None) => current = Some(Vec::new()),
+            (Some(_), None) => skipping = true,
+            (None, Some(lines)) => lines.push(line),
+            (None, None) => {},
+        }
+    }
+    examples
+}
+
+fn render(page: &Page, pages: &[Page]) -> String {
+    let root = "../".repeat(page.module.matches('/').count());
+    let mut markdown = format!("# {}\n\n[Index]({}index.md)\n", page.module, root);
+    for item in &page.items {
+        markdown += &format!("\n<a id=\"{}\"></a>\n### `{}`\n\n", item.name, item.signature);
+        if let Some(doc) = &item.doc {
+            markdown += &link(doc, page, pages);
+            markdown += "\n\n";
+        }
+        let exported = if item.exported { "Exported, defined" } else { "Defined" };
+        markdown += &format!("{} at line {} of `{}.{}`.\n", exported, item.line, page.module, EXTENSION);
+    }
+    markdown
+}
+
+fn index(pages: &[Page]) -> String {
+    let mut markdown = String::from("# Index\n\n## Scripts\n\n");
+    for page in pages {
+        markdown += &format!("- [{}]({}.md)\n", page.module, page.module);
+    }
+
+    let mut items: Vec<(&Page, &Item)> = pages.iter().flat_map(|page| page.items.iter().map(move |item| (page, item))).collect();
+    items.sort_by(|(a, x), (b, y)| x.name.cmp(&y.name).then(a.module.cmp(&b.module)));
+    markdown += "\n## Items\n\n";
+    for (page, item) in items {
+        markdown += &format!("- [`{}`]({}) in {}\n", item.name, url(page, item), page.module);
+    }
+    markdown
+}
+
+fn search_index(pages: &[Page]) -> String {
+    let entries: Vec<SearchEntry> = pages.iter().flat_map(|page| page.items.iter().map(move |item| SearchEntry {
+        name: &item.name,
+        kind: item.kind,
+        module: &page.module,
+        url: url(page, item),
+        summary: item.doc.as_deref().and_then(|doc| doc.lines().next()).unwrap_or_default(),
+    })).collect();
+    serde_json::to_string_pretty(&entries).expect("search entries can always be written as JSON") + "\n"
+}
+
+fn url(page: &Page, item: &Item) -> String {
+    format!("{}.md#{}", page.module, item.name)
+}
+
+/// Links each `name` in code quotes to the item it names, preferring one on
+/// the same page. Fenced examples are left as they are.
+fn link(doc: &str, page: &Page, pages: &[Page]) -> String {
+    let root = "../".repeat(page.module.matches('/').count());
+    let target = |name: &str| -> Option<String> {
+        let name = name.strip_suffix("()").unwrap_or(name);
+        if page.items.iter().any(|item| item.name == name) {
+            return Some(format!("#{}", name))
+        }
+        pages.iter()
+            .find_map(|other| other.items.iter().find(|item| item.name == name).map(|item| format!("{}{}", root, url(other, item))))
+    };
+
+    let mut fenced = false;
+    doc.lines().map(|line| {
+        if line.trim().starts_with("```") {
+            fenced = !fenced;
+            return line.to_string()
+        }
+        let parts: Vec<&str> = line.split('`').collect();
+        // An odd number of backticks leaves a quote open, so the line is left as written.
+        if fenced || parts.len().is_multiple_of(2) {
+            return line.to_string()
+        }
+        parts.iter().enumerate().map(|(i, part)| match (i % 2, target(part)) {
+            (1, Some(target)) => format!("[`{}`]({})", part, target),
+            (1, None) => format!("`{}`", part),
+            _ => part.to_string(),
+        }).collect()
+    }).collect::<Vec<String>>().join("\n")
+}
+
+fn parse(source: &str) -> Result<Program, String> {
+    let tokens = crate::token::generate(source)
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|span| format!("{}: Unrecognised token {:?}.", span, &source[span.start..span.end]))?;
+    crate::parser::parse(tokens).map_err(|e| format!("{}: {}", e.span, e.node))
+}
+
+/// Runs an example the way `crustacean` runs a script, as if it were at `path`.
+fn run(source: &str, path: &Path) -> Result<(), String> {
+    let program = crate::expander::expand(parse(source)?)
+        .map_err(|errors| errors.iter().map(|e| format!("{}: {}", e.span, e.node)).collect::<Vec<_>>().join(" "))?;
+    let program = crate::resolver::resolve(program)
+        .map_err(|errors| errors.iter().map(|e| format!("{}: {}", e.span, e.node)).collect::<Vec<_>>().join(" "))?;
+    let errors = crate::checker::check(&program);
+    if !errors.is_empty() {
+        return Err(errors.iter().map(|e| format!("{}: {}", e.span, e.node)).collect::<Vec<_>>().join(" "))
+    }
+    crate::interpreter::interpret(program, path, Vec::new()).map_err(|e| e.to_string())
+}
+
+/// Every script under a directory, in a stable order.
+fn scripts(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), DocError> {
+    let io = |source| DocError::Io { path: dir.display().to_string(), source };
+    let mut entries = fs::read_dir(dir).map_err(io)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(io)?;
+    entries.sort();
+
+    for entry in entries {
+        if entry.is_dir() {
+            scripts(&entry, files)?;
+        } else if entry.extension().is_some_and(|extension| extension == EXTENSION) {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::tests::TempDir;
+
+    fn page_of(module: &str, source: &str) -> Page {
+        page(module.to_string(), &parse(source).unwrap())
+    }
+
+    #[test]
+    fn it_documents_exports_and_commented_declarations() {
+        let source = "/// Adds.\nexport fn add(a, b = 1) -> Number { a + b }\nexport const TWO = 2\nfn hidden() {}\n\
+            /// A point.\nstruct Point { x, y }\nimpl Point {\n    /// Its length.\n    fn len(self) { 0 }\n    fn other(self) {}\n}";
+        let items: Vec<(String, ItemKind, String, bool)> = page_of("math", source).items.into_iter()
+            .map(|item| (item.name, item.kind, item.signature, item.exported))
+            .collect();
+
+        assert_eq!(items, vec![
+            ("add".to_string(), ItemKind::Function, "fn add(a, b = 1) -> Number".to_string(), true),
+            ("TWO".to_string(), ItemKind::Const, "const TWO = 2".to_string(), true),
+            ("Point".to_string(), ItemKind::Struct, "struct Point { x, y }".to_string(), false),
+            ("Point.len".to_string(), ItemKind::Method, "fn len(self)".to_string(), false),
+        ]);
+    }
+
+    #[test]
+    fn it_finds_examples_in_fenced_blocks() {
+        let doc = "Adds.\n```\nadd(1)\nadd(2)\n```\n```text\nnot code\n```\n```crustacean\nadd(3)\n```";
+
+        assert_eq!(examples(doc), vec!["add(1)\nadd(2)".to_string(), "add(3)".to_string()]);
+    }
+
+    #[test]
+    fn it_links_quoted_names_to_their_items() {
+        let pages = vec![
+            page_of("math", "/// See `sub()`, `str/join` and `missing`.\nexport fn add() {}\nexport fn sub() {}"),
+            page_of("str/join", "/// Unlike `add`.\n/// ```\n/// `add`\n/// ```\nexport fn join() {}"),
+        ];
+
+        assert_eq!(link(pages[0].items[0].doc.as_ref().unwrap(), &pages[0], &pages), "See [`sub()`](#sub), `str/join` and `missing`.");
+        assert_eq!(link(pages[1].items[0].doc.as_ref().unwrap(), &pages[1], &pages), "Unlike [`add`](../math.md#add).\n```\n`add`\n```");
+        assert!(render(&pages[1], &pages).starts_with("# str/join\n\n[Index](../index.md)\n\n<a id=\"join\"></a>\n### `fn join()`\n"));
+        assert!(search_index(&pages).contains("\"url\": \"str/join.md#join\""));
+    }
+
+    #[test]
+    fn it_runs_examples_as_scripts_next_to_the_one_they_document() {
+        let root = TempDir::new("doctest", &[("math.crs", "/// ```\n/// import { add } from \"./math\"\n/// add(1, 2)\n/// ```\nexport fn add(a, b) { a + b }")]);
+        assert!(test(&collect(&root).unwrap(), &root));
+
+        fs::write(root.join("math.crs"), "/// ```\n/// throw error(\"wrong\")\n/// ```\nexport fn add(a, b) { a + b }").unwrap();
+        assert!(!test(&collect(&root).unwrap(), &root));
+    }
+}
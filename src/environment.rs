@@ -1,63 +1,226 @@
-use std::collections::HashMap;
-use std::fmt::{Debug, Formatter, Result};
-use crate::interpreter::Interpreter;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display, Formatter, Result};
+use std::rc::Rc;
+use std::cell::RefCell;
+use crate::ast::{Block, FunctionKind, Identifier, Parameter, Spanned};
+use crate::interpreter::{Interpreter, RuntimeError};
+use crate::event_loop::Promise;
+use crate::iterator::{Range, Sequence};
 
-pub type NativeFunctionCallback = fn (&mut Interpreter, Vec<Value>) -> Option<Value>;
+pub type NativeFunctionCallback = fn (&mut Interpreter, Vec<Value>) -> std::result::Result<Value, RuntimeError>;
+/// A native function holding state of its own, such as the function a decorator wraps.
+pub type NativeClosureCallback = Rc<dyn Fn(&mut Interpreter, Vec<Value>, Vec<(Identifier, Value)>) -> std::result::Result<Value, RuntimeError>>;
 
 #[derive(Debug, Clone)]
 pub struct Environment {
-    values: HashMap<String, Option<Value>>,
+    values: HashMap<String, Value>,
+    constants: HashSet<String>,
+    parent: Option<Rc<RefCell<Environment>>>,
 }
 
 impl Environment {
     pub fn new() -> Self {
         Self {
             values: HashMap::new(),
+            constants: HashSet::new(),
+            parent: None,
         }
     }
-    
-    pub fn set(&mut self, name: impl Into<String>, value: Option<Value>) {
-        self.values.insert(name.into(), value);
+
+    pub fn extend(parent: Rc<RefCell<Environment>>) -> Self {
+        Self {
+            values: HashMap::new(),
+            constants: HashSet::new(),
+            parent: Some(parent),
+        }
+    }
+
+    /// Defines a name in this scope, unless a constant already holds it.
+    pub fn set(&mut self, name: impl Into<String>, value: Value) -> std::result::Result<(), RuntimeError> {
+        let name = name.into();
+        if self.constants.contains(&name) {
+            return Err(RuntimeError::ConstantReassignment(name))
+        }
+        self.values.insert(name, value);
+        Ok(())
+    }
+
+    pub fn set_constant(&mut self, name: impl Into<String>, value: Value) -> std::result::Result<(), RuntimeError> {
+        let name = name.into();
+        self.set(name.clone(), value)?;
+        self.constants.insert(name);
+        Ok(())
+    }
+
+    pub fn get(&self, name: impl Into<String>) -> std::result::Result<Value, RuntimeError> {
+        let name = name.into();
+        match (self.values.get(&name), &self.parent) {
+            (Some(value), _) => Ok(value.clone()),
+            (None, Some(parent)) => parent.borrow().get(name),
+            (None, None) => Err(RuntimeError::UndefinedVariable(name)),
+        }
     }
+}
+
+/// What `help` and `doc` show for a function: its parameter list, as written
+/// between the parentheses, and its doc comment.
+#[derive(Clone, Default)]
+pub struct Documentation {
+    pub signature: String,
+    pub doc: Option<String>,
+}
 
-    pub fn get(&self, name: impl Into<String>) -> Option<Value> {
-        *self.values.get(&name.into()).unwrap().clone()
+impl Documentation {
+    pub fn new(signature: impl Into<String>, doc: impl Into<String>) -> Self {
+        Documentation { signature: signature.into(), doc: Some(doc.into()) }
     }
 }
 
+/// A struct declared with `struct`, along with the methods its `impl` blocks added.
+pub struct StructType {
+    pub name: String,
+    pub doc: Option<String>,
+    pub fields: Vec<Identifier>,
+    pub methods: RefCell<HashMap<Identifier, Value>>,
+    pub traits: RefCell<Vec<Rc<TraitType>>>,
+}
+
+/// A trait declared with `trait`: each of its methods, along with the default
+/// body impls get when they leave it out. Methods without one are required.
+pub struct TraitType {
+    pub name: String,
+    pub methods: Vec<(Identifier, Option<Value>)>,
+}
+
 #[derive(Clone)]
 pub enum Value {
+    /// The absence of a value, as written `nil`. Functions and blocks that don't
+    /// end in an expression produce it, and so do `let` declarations without one.
+    Nil,
     Number(f64),
     String(String),
+    Bool(bool),
+    List(Vec<Value>),
+    /// Keys and their values, in the order the keys were first added.
+    Map(Vec<(Value, Value)>),
+    Range(Range),
+    Iterator(Rc<RefCell<Sequence>>),
+    Promise(Rc<RefCell<Promise>>),
+    Error {
+        kind: String,
+        message: String,
+        trace: Vec<String>,
+    },
+    Module {
+        name: String,
+        environment: Rc<RefCell<Environment>>,
+        exports: Rc<Vec<String>>,
+    },
+    Function {
+        name: String,
+        params: Vec<Spanned<Parameter>>,
+        body: Block,
+        kind: FunctionKind,
+        environment: Rc<RefCell<Environment>>,
+        doc: Option<Rc<String>>,
+    },
     NativeFunction{
-        callback: NativeFunctionCallback
+        name: String,
+        callback: NativeFunctionCallback,
+        documentation: Rc<Documentation>,
+    },
+    NativeClosure {
+        name: String,
+        callback: NativeClosureCallback,
+        documentation: Rc<Documentation>,
+    },
+    Struct(Rc<StructType>),
+    Trait(Rc<TraitType>),
+    /// An instance of a struct, holding a value for each of its fields in order.
+    Instance {
+        ty: Rc<StructType>,
+        fields: Vec<Value>,
+    },
+}
+
+impl Value {
+    /// The name a function was declared with.
+    pub fn function_name(&self) -> Option<&str> {
+        match self {
+            Value::Function { name, .. } | Value::NativeFunction { name, .. } | Value::NativeClosure { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The signature and doc comment of a function, or the fields and doc comment of a struct.
+    pub fn documentation(&self) -> Option<Documentation> {
+        match self {
+            Value::Function { params, doc, .. } => Some(Documentation { signature: crate::ast::print::signature(params), doc: doc.as_deref().cloned() }),
+            Value::NativeFunction { documentation, .. } | Value::NativeClosure { documentation, .. } => Some(Documentation::clone(documentation)),
+            Value::Struct(ty) => Some(Documentation { signature: ty.fields.join(", "), doc: ty.doc.clone() }),
+            _ => None,
+        }
+    }
+
+    /// A method the struct this value is an instance of has.
+    pub fn method(&self, name: &str) -> Option<Value> {
+        match self {
+            Value::Instance { ty, .. } => ty.methods.borrow().get(name).cloned(),
+            _ => None,
+        }
+    }
+
+    /// The name of this value's type, as used in error messages.
+    pub fn type_name(&self) -> String {
+        match self {
+            Value::Nil => "Nil",
+            Value::Number(_) => "Number",
+            Value::String(_) => "String",
+            Value::Bool(_) => "Bool",
+            Value::List(_) => "List",
+            Value::Map(_) => "Map",
+            Value::Range(_) => "Range",
+            Value::Iterator(_) => "Iterator",
+            Value::Promise(_) => "Promise",
+            Value::Error { .. } => "Error",
+            Value::Module { .. } => "Module",
+            Value::Function { .. } | Value::NativeFunction { .. } | Value::NativeClosure { .. } => "Function",
+            Value::Struct(_) => "Struct",
+            Value::Trait(_) => "Trait",
+            Value::Instance { ty, .. } => return ty.name.clone(),
+        }.to_string()
     }
 }
 
 impl Debug for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         write!(f, "{}", match self {
+            Value::Nil => "nil".to_string(),
             Value::Number(n) => n.to_string(),
             Value::String(s) => s.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::List(items) => format!("[{}]", items.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ")),
+            Value::Map(entries) => format!("{{{}}}", entries.iter().map(|(key, value)| format!("{}: {}", key, value)).collect::<Vec<_>>().join(", ")),
+            Value::Range(range) => range.to_string(),
+            Value::Iterator(_) => "<iterator>".to_string(),
+            Value::Promise(_) => "<promise>".to_string(),
+            Value::Error { kind, message, .. } => format!("{}: {}", kind, message),
+            Value::Module { name, .. } => format!("<module {}>", name),
+            Value::Function { name, .. } => format!("<{}>", name),
             Value::NativeFunction { name, .. } => format!("<{}>", name),
-            _ => todo!(),
+            Value::NativeClosure { name, .. } => format!("<{}>", name),
+            Value::Struct(ty) => format!("<struct {}>", ty.name),
+            Value::Trait(ty) => format!("<trait {}>", ty.name),
+            Value::Instance { ty, fields } => format!("{}({})", ty.name, ty.fields.iter().zip(fields)
+                .map(|(name, value)| format!("{}: {}", name, value))
+                .collect::<Vec<_>>()
+                .join(", ")),
         })
     }
 }
 
-impl Value {
-    pub fn to_number(self) -> f64 {
-        match self {
-            Value::Number(n) => n,
-            _ => unreachable!()
-        }
-    }
-
-    pub fn to_string(self) -> String {
-        match self {
-            Value::String(s) => s,
-            Value::Number(n) => n.to_string(),
-            _ => todo!()
-        }
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        Debug::fmt(self, f)
     }
 }
\ No newline at end of file
@@ -0,0 +1,293 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io;
+use std::process::{Command, Output};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+use corosensei::CoroutineResult;
+
+use crate::environment::Value;
+use crate::interpreter::{Routine, RuntimeError};
+
+pub type TaskId = u64;
+
+/// A subprocess that has exited, along with what it wrote.
+type Exit = (u64, io::Result<Output>);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Clock {
+    Real(Instant),
+    /// Jumps straight to the next timer instead of sleeping, for fast, reproducible tests.
+    Virtual,
+}
+
+#[derive(Debug)]
+pub enum PromiseState {
+    Pending,
+    Resolved(Value),
+    Rejected(Value),
+}
+
+#[derive(Debug)]
+pub struct Promise {
+    pub state: PromiseState,
+    pub handled: bool,
+    waiters: Vec<TaskId>,
+}
+
+impl Promise {
+    fn new() -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(Self {
+            state: PromiseState::Pending,
+            handled: false,
+            waiters: Vec::new(),
+        }))
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(self.state, PromiseState::Pending)
+    }
+}
+
+struct Task {
+    routine: Option<Box<Routine>>,
+    promise: Rc<RefCell<Promise>>,
+}
+
+enum Timer {
+    Resolve(Rc<RefCell<Promise>>, Value),
+    Start(TaskId),
+}
+
+/// Drives async tasks, timers and subprocesses on a single thread. Ready tasks
+/// run in the order they became ready, and timers due at the same time fire in
+/// the order they were set, so scheduling is deterministic. Each subprocess is
+/// waited on by a thread of its own, which only reports back when it exits.
+pub struct EventLoop {
+    clock: Clock,
+    now: u64,
+    next_id: TaskId,
+    tasks: HashMap<TaskId, Task>,
+    ready: VecDeque<TaskId>,
+    timers: BinaryHeap<Reverse<(u64, u64)>>,
+    pending_timers: HashMap<u64, Timer>,
+    rejected: Vec<Rc<RefCell<Promise>>>,
+    processes: HashMap<u64, Rc<RefCell<Promise>>>,
+    exits: (Sender<Exit>, Receiver<Exit>),
+}
+
+impl EventLoop {
+    pub fn new(clock: Clock) -> Self {
+        Self {
+            clock,
+            now: 0,
+            next_id: 0,
+            tasks: HashMap::new(),
+            ready: VecDeque::new(),
+            timers: BinaryHeap::new(),
+            pending_timers: HashMap::new(),
+            rejected: Vec::new(),
+            processes: HashMap::new(),
+            exits: mpsc::channel(),
+        }
+    }
+
+    /// Milliseconds since the loop started.
+    pub fn now(&self) -> u64 {
+        match self.clock {
+            Clock::Real(start) => start.elapsed().as_millis() as u64,
+            Clock::Virtual => self.now,
+        }
+    }
+
+    /// Queues a task to run as soon as the loop is free.
+    pub fn spawn(&mut self, routine: Box<Routine>) -> Rc<RefCell<Promise>> {
+        let (id, promise) = self.add_task(routine);
+        self.ready.push_back(id);
+        promise
+    }
+
+    /// Queues a task to start once `delay` milliseconds have passed.
+    pub fn schedule(&mut self, routine: Box<Routine>, delay: u64) -> Rc<RefCell<Promise>> {
+        let (id, promise) = self.add_task(routine);
+        self.add_timer(delay, Timer::Start(id));
+        promise
+    }
+
+    /// A promise that resolves to `value` once `delay` milliseconds have passed.
+    pub fn sleep(&mut self, delay: u64, value: Value) -> Rc<RefCell<Promise>> {
+        let promise = Promise::new();
+        self.add_timer(delay, Timer::Resolve(promise.clone(), value));
+        promise
+    }
+
+    /// Starts a subprocess, returning a promise of its exit status and output.
+    pub fn exec(&mut self, mut command: Command) -> Rc<RefCell<Promise>> {
+        let id = self.next_id();
+        let promise = Promise::new();
+        let exits = self.exits.0.clone();
+        std::thread::spawn(move || exits.send((id, command.output())));
+        self.processes.insert(id, promise.clone());
+        promise
+    }
+
+    fn add_task(&mut self, routine: Box<Routine>) -> (TaskId, Rc<RefCell<Promise>>) {
+        let id = self.next_id();
+        let promise = Promise::new();
+        self.tasks.insert(id, Task { routine: Some(routine), promise: promise.clone() });
+        (id, promise)
+    }
+
+    fn add_timer(&mut self, delay: u64, timer: Timer) {
+        let id = self.next_id();
+        self.timers.push(Reverse((self.now() + delay, id)));
+        self.pending_timers.insert(id, timer);
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Runs tasks and timers until `target` settles, or until there is nothing
+    /// left to do when no target is given.
+    pub fn run_until(events: &Rc<RefCell<EventLoop>>, target: Option<&Rc<RefCell<Promise>>>) -> Result<(), RuntimeError> {
+        loop {
+            if target.is_some_and(|t| !t.borrow().is_pending()) {
+                return Ok(())
+            }
+
+            // The loop must not stay borrowed while a task runs, since the task can schedule more work.
+            let next = events.borrow_mut().next_task();
+            if let Some((id, mut routine)) = next {
+                let result = routine.resume(());
+                events.borrow_mut().suspend_or_finish(id, routine, result);
+                continue;
+            }
+
+            if events.borrow_mut().wait_for_process() {
+                continue;
+            }
+            if !events.borrow_mut().fire_next_timer() {
+                return match target {
+                    Some(_) => Err(RuntimeError::NeverSettled),
+                    None => events.borrow_mut().unhandled_rejection(),
+                }
+            }
+        }
+    }
+
+    fn next_task(&mut self) -> Option<(TaskId, Box<Routine>)> {
+        let id = self.ready.pop_front()?;
+        let routine = self.tasks.get_mut(&id)?.routine.take()?;
+        Some((id, routine))
+    }
+
+    fn suspend_or_finish(&mut self, id: TaskId, routine: Box<Routine>, result: CoroutineResult<Value, Result<Value, RuntimeError>>) {
+        match result {
+            // A task only suspends to await a pending promise.
+            CoroutineResult::Yield(Value::Promise(awaited)) => {
+                if awaited.borrow().is_pending() {
+                    awaited.borrow_mut().waiters.push(id);
+                } else {
+                    self.ready.push_back(id);
+                }
+                self.tasks.get_mut(&id).unwrap().routine = Some(routine);
+            },
+            // Only an AST read from JSON can yield from an async function. The task is abandoned.
+            CoroutineResult::Yield(_) => {
+                drop(routine);
+                let task = self.tasks.remove(&id).unwrap();
+                self.settle(&task.promise, rejected(RuntimeError::YieldOutsideGenerator));
+            },
+            CoroutineResult::Return(result) => {
+                let task = self.tasks.remove(&id).unwrap();
+                self.settle(&task.promise, match result {
+                    Ok(value) => PromiseState::Resolved(value),
+                    Err(error) => rejected(error),
+                });
+            },
+        }
+    }
+
+    /// Settles the promise of the next subprocess to exit. While subprocesses
+    /// are running, a virtual clock only reaches the next timer once they have
+    /// all exited, and a real one waits for them until that timer is due.
+    /// Returns whether a promise was settled.
+    fn wait_for_process(&mut self) -> bool {
+        if self.processes.is_empty() {
+            return false
+        }
+
+        let (_, exited) = &self.exits;
+        let exit = match (self.clock, self.timers.peek()) {
+            (_, Some(Reverse((deadline, _)))) if *deadline <= self.now() => exited.try_recv().ok(),
+            (Clock::Real(_), Some(Reverse((deadline, _)))) => exited.recv_timeout(Duration::from_millis(deadline.saturating_sub(self.now()))).ok(),
+            _ => exited.recv().ok(),
+        };
+        let Some((id, output)) = exit else {
+            return false
+        };
+
+        let promise = self.processes.remove(&id).unwrap();
+        self.settle(&promise, match output {
+            Ok(output) => PromiseState::Resolved(Value::Map(vec![
+                (Value::String("status".to_string()), output.status.code().map_or(Value::Nil, |code| Value::Number(code as f64))),
+                (Value::String("stdout".to_string()), Value::String(String::from_utf8_lossy(&output.stdout).into_owned())),
+                (Value::String("stderr".to_string()), Value::String(String::from_utf8_lossy(&output.stderr).into_owned())),
+            ])),
+            Err(error) => PromiseState::Rejected(Value::Error { kind: "IOError".to_string(), message: error.to_string(), trace: Vec::new() }),
+        });
+        true
+    }
+
+    fn settle(&mut self, promise: &Rc<RefCell<Promise>>, state: PromiseState) {
+        if matches!(state, PromiseState::Rejected(_)) {
+            self.rejected.push(promise.clone());
+        }
+
+        let mut promise = promise.borrow_mut();
+        promise.state = state;
+        self.ready.extend(promise.waiters.drain(..));
+    }
+
+    fn fire_next_timer(&mut self) -> bool {
+        let Some(Reverse((deadline, id))) = self.timers.pop() else {
+            return false
+        };
+
+        match self.clock {
+            Clock::Real(_) => std::thread::sleep(Duration::from_millis(deadline.saturating_sub(self.now()))),
+            Clock::Virtual => self.now = self.now.max(deadline),
+        }
+
+        match self.pending_timers.remove(&id).unwrap() {
+            Timer::Resolve(promise, value) => self.settle(&promise, PromiseState::Resolved(value)),
+            Timer::Start(task) => self.ready.push_back(task),
+        }
+        true
+    }
+
+    fn unhandled_rejection(&mut self) -> Result<(), RuntimeError> {
+        for promise in self.rejected.drain(..) {
+            let promise = promise.borrow();
+            if let (false, PromiseState::Rejected(error)) = (promise.handled, &promise.state) {
+                return Err(RuntimeError::Thrown(error.clone()))
+            }
+        }
+        Ok(())
+    }
+}
+
+fn rejected(error: RuntimeError) -> PromiseState {
+    PromiseState::Rejected(match error {
+        RuntimeError::Thrown(error) => error,
+        error => Value::Error {
+            kind: error.kind().to_string(),
+            message: error.to_string(),
+            trace: Vec::new(),
+        },
+    })
+}
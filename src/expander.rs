@@ -0,0 +1,745 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use thiserror::Error;
+
+use crate::ast::*;
+use crate::ast::fold::{self, Folder};
+use crate::ast::visit::{self, Visitor};
+use crate::token::Token;
+
+/// Expands macro calls before a program is resolved. A macro is visible from
+/// its definition to the end of the enclosing block, and its definition is
+/// removed from the program once expanded.
+///
+/// Expansion is hygienic: a name the template declares is renamed to
+/// `name#n`, so it can't capture or shadow names at the call site, while
+/// names passed in as arguments are left alone.
+pub fn expand(program: Program) -> Result<Program, Vec<Spanned<ExpandError>>> {
+    let mut expander = Expander::new();
+    let program = expander.fold_block(program);
+
+    if expander.errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(expander.errors)
+    }
+}
+
+const RECURSION_LIMIT: usize = 64;
+
+/// Appended to every name written in a template, until hygiene decides
+/// whether it's declared by the expansion or refers to something outside it.
+const MARK: char = '#';
+
+struct Macro {
+    pattern: Vec<Matcher>,
+    template: Vec<Transcriber>,
+}
+
+enum Fragment {
+    Expr,
+    Ident,
+    Tt,
+    Block,
+}
+
+enum Matcher {
+    Token(Token),
+    Fragment(Identifier, Fragment),
+    /// `$( ... ) separator *` or `+`, where the flag is true for `+`.
+    Repeat(Vec<Matcher>, Option<Token>, bool),
+}
+
+enum Transcriber {
+    Token(Token),
+    Variable(Identifier),
+    Repeat(Vec<Transcriber>, Option<Token>),
+}
+
+#[derive(Debug, Clone)]
+enum Binding {
+    Fragment(Vec<Spanned<Token>>),
+    Repeated(Vec<Binding>),
+}
+
+type Bindings = HashMap<Identifier, Binding>;
+
+struct Expander {
+    scopes: Vec<HashMap<Identifier, Rc<Macro>>>,
+    errors: Vec<Spanned<ExpandError>>,
+    expansions: usize,
+    depth: usize,
+}
+
+impl Expander {
+    fn new() -> Self {
+        Self {
+            scopes: vec![HashMap::new()],
+            errors: Vec::new(),
+            expansions: 0,
+            depth: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<Rc<Macro>> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name)).cloned()
+    }
+
+    /// Expands `f` one level deeper into nested macro calls.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn expand_statement(&mut self, statement: Spanned<Statement>, block: &mut Block) {
+        let Spanned { node, span } = statement;
+        match node {
+            Statement::Macro { name, pattern, template } => match compile(&pattern, &template) {
+                Ok(definition) => {
+                    self.scopes.last_mut().unwrap().insert(name, Rc::new(definition));
+                },
+                Err(error) => self.errors.push(Spanned::new(error, span)),
+            },
+            // A call on its own line can expand to any number of statements.
+            Statement::Expression { expression: Spanned { node: Expression::MacroCall(name, arguments), span } } => {
+                if let Some(program) = self.expand_call(&name, &arguments, span) {
+                    self.nested(|expander| for statement in program {
+                        expander.expand_statement(statement, block)
+                    });
+                }
+            },
+            node => block.push(self.fold_statement(Spanned::new(node, span))),
+        }
+    }
+
+    fn expand_call(&mut self, name: &Identifier, arguments: &[Spanned<Token>], span: Span) -> Option<Program> {
+        match self.try_expand_call(name, arguments, span) {
+            Ok(program) => Some(program),
+            Err(error) => {
+                self.errors.push(Spanned::new(error, span));
+                None
+            },
+        }
+    }
+
+    fn try_expand_call(&mut self, name: &Identifier, arguments: &[Spanned<Token>], span: Span) -> Result<Program, ExpandError> {
+        let Some(definition) = self.lookup(name) else {
+            return if name == "stringify" {
+                let expression = Spanned::new(Expression::String(stringify(arguments)), span);
+                Ok(vec![Spanned::new(Statement::Expression { expression }, span)])
+            } else {
+                Err(ExpandError::UnknownMacro(name.clone()))
+            }
+        };
+        if self.depth >= RECURSION_LIMIT {
+            return Err(ExpandError::RecursionLimit(name.clone()))
+        }
+
+        let bindings = match_all(&definition.pattern, arguments).ok_or_else(|| ExpandError::NoMatch(name.clone()))?;
+        let mut tokens = Vec::new();
+        transcribe(&definition.template, &bindings, span, &mut tokens)?;
+
+        let program = crate::parser::parse(tokens.into_iter().map(|t| (t.node, t.span)).collect())
+            .map_err(|error| ExpandError::InvalidExpansion(name.clone(), error.node.to_string()))?;
+
+        self.expansions += 1;
+        let mut declared = Declared(HashSet::new());
+        declared.visit_block(&program);
+        Ok(Hygiene { declared: declared.0, expansion: self.expansions }.fold_block(program))
+    }
+}
+
+impl Folder for Expander {
+    /// Every block is its own scope, and macro calls on their own line are spliced into it.
+    fn fold_block(&mut self, block: Block) -> Block {
+        self.scopes.push(HashMap::new());
+        let mut expanded = Vec::new();
+        for statement in block {
+            self.expand_statement(statement, &mut expanded);
+        }
+        self.scopes.pop();
+        expanded
+    }
+
+    fn fold_expression(&mut self, expression: Spanned<Expression>) -> Spanned<Expression> {
+        let Spanned { node, span } = expression;
+        let Expression::MacroCall(name, arguments) = node else {
+            return fold::walk_expression(self, Spanned::new(node, span))
+        };
+
+        match self.expand_call(&name, &arguments, span).map(|program| program.into_iter().map(|s| s.node).collect::<Vec<_>>()) {
+            Some(program) => match <[Statement; 1]>::try_from(program) {
+                Ok([Statement::Expression { expression }]) => {
+                    let expression = self.nested(|expander| expander.fold_expression(expression));
+                    Spanned::new(expression.node, span)
+                },
+                _ => {
+                    self.errors.push(Spanned::new(ExpandError::ExpectedExpression(name.clone()), span));
+                    Spanned::new(Expression::MacroCall(name, arguments), span)
+                },
+            },
+            None => Spanned::new(Expression::MacroCall(name, arguments), span),
+        }
+    }
+}
+
+fn compile(pattern: &[Spanned<Token>], template: &[Spanned<Token>]) -> Result<Macro, ExpandError> {
+    let pattern = matchers(pattern)?;
+    let template = transcribers(template)?;
+
+    let mut bound = HashSet::new();
+    pattern_variables(&pattern, &mut bound);
+    let mut used = HashSet::new();
+    template_variables(&template, &mut used);
+    if let Some(unbound) = used.difference(&bound).next() {
+        return Err(ExpandError::UnknownVariable(unbound.clone()))
+    }
+
+    Ok(Macro { pattern, template })
+}
+
+fn matchers(tokens: &[Spanned<Token>]) -> Result<Vec<Matcher>, ExpandError> {
+    let mut matchers = Vec::new();
+    let mut i = 0;
+
+    while i < tokens.len() {
+        match (&tokens[i].node, tokens.get(i + 1).map(|t| &t.node)) {
+            (Token::Dollar, Some(Token::Identifier(name))) => {
+                let fragment = match (tokens.get(i + 2).map(|t| &t.node), tokens.get(i + 3).map(|t| &t.node)) {
+                    (Some(Token::Colon), Some(Token::Identifier(kind))) => match kind.as_str() {
+                        "expr" => Fragment::Expr,
+                        "ident" => Fragment::Ident,
+                        "tt" => Fragment::Tt,
+                        "block" => Fragment::Block,
+                        _ => return Err(ExpandError::UnknownFragment(kind.clone())),
+                    },
+                    _ => return Err(ExpandError::MissingFragment(name.clone())),
+                };
+                matchers.push(Matcher::Fragment(name.clone(), fragment));
+                i += 4;
+            },
+            (Token::Dollar, Some(Token::LeftParen)) => {
+                let end = i + 1 + tree_length(&tokens[i + 1..]);
+                let (separator, at_least_once, next) = repetition(tokens, end)?;
+                matchers.push(Matcher::Repeat(self::matchers(&tokens[i + 2..end - 1])?, separator, at_least_once));
+                i = next;
+            },
+            (token, _) => {
+                matchers.push(Matcher::Token(token.clone()));
+                i += 1;
+            },
+        }
+    }
+
+    Ok(matchers)
+}
+
+fn transcribers(tokens: &[Spanned<Token>]) -> Result<Vec<Transcriber>, ExpandError> {
+    let mut transcribers = Vec::new();
+    let mut i = 0;
+
+    while i < tokens.len() {
+        match (&tokens[i].node, tokens.get(i + 1).map(|t| &t.node)) {
+            (Token::Dollar, Some(Token::Identifier(name))) => {
+                transcribers.push(Transcriber::Variable(name.clone()));
+                i += 2;
+            },
+            (Token::Dollar, Some(Token::LeftParen)) => {
+                let end = i + 1 + tree_length(&tokens[i + 1..]);
+                let (separator, _, next) = repetition(tokens, end)?;
+                transcribers.push(Transcriber::Repeat(self::transcribers(&tokens[i + 2..end - 1])?, separator));
+                i = next;
+            },
+            (token, _) => {
+                transcribers.push(Transcriber::Token(token.clone()));
+                i += 1;
+            },
+        }
+    }
+
+    Ok(transcribers)
+}
+
+/// Reads the `*`, `+` or `separator *` after a repetition starting at `i`,
+/// returning the separator, whether it was `+`, and where the repetition ends.
+fn repetition(tokens: &[Spanned<Token>], i: usize) -> Result<(Option<Token>, bool, usize), ExpandError> {
+    match (tokens.get(i).map(|t| &t.node), tokens.get(i + 1).map(|t| &t.node)) {
+        (Some(Token::Asterisk), _) => Ok((None, false, i + 1)),
+        (Some(Token::Plus), _) => Ok((None, true, i + 1)),
+        (Some(separator), Some(Token::Asterisk)) => Ok((Some(separator.clone()), false, i + 2)),
+        (Some(separator), Some(Token::Plus)) => Ok((Some(separator.clone()), true, i + 2)),
+        _ => Err(ExpandError::InvalidRepetition),
+    }
+}
+
+fn pattern_variables(matchers: &[Matcher], variables: &mut HashSet<Identifier>) {
+    for matcher in matchers {
+        match matcher {
+            Matcher::Token(_) => {},
+            Matcher::Fragment(name, _) => {
+                variables.insert(name.clone());
+            },
+            Matcher::Repeat(inner, ..) => pattern_variables(inner, variables),
+        }
+    }
+}
+
+fn template_variables(transcribers: &[Transcriber], variables: &mut HashSet<Identifier>) {
+    for transcriber in transcribers {
+        match transcriber {
+            Transcriber::Token(_) => {},
+            Transcriber::Variable(name) => {
+                variables.insert(name.clone());
+            },
+            Transcriber::Repeat(inner, _) => template_variables(inner, variables),
+        }
+    }
+}
+
+/// The number of tokens in the token tree at the start of `tokens`: a single
+/// token, or a bracketed group up to its closing bracket.
+fn tree_length(tokens: &[Spanned<Token>]) -> usize {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.node {
+            Token::LeftParen | Token::LeftBracket | Token::LeftBrace => depth += 1,
+            Token::RightParen | Token::RightBracket | Token::RightBrace => depth -= 1,
+            _ => {},
+        }
+        if depth <= 0 {
+            return i + 1
+        }
+    }
+    tokens.len()
+}
+
+fn is_closer(token: &Token) -> bool {
+    matches!(token, Token::RightParen | Token::RightBracket | Token::RightBrace)
+}
+
+fn match_all(matchers: &[Matcher], tokens: &[Spanned<Token>]) -> Option<Bindings> {
+    match_prefix(matchers, tokens).into_iter().find(|(length, _)| *length == tokens.len()).map(|(_, bindings)| bindings)
+}
+
+/// Every way `matchers` can match the start of `tokens`, with how many tokens each used.
+fn match_prefix(matchers: &[Matcher], tokens: &[Spanned<Token>]) -> Vec<(usize, Bindings)> {
+    let Some((first, rest)) = matchers.split_first() else {
+        return vec![(0, Bindings::new())]
+    };
+
+    let mut matches = Vec::new();
+    for (length, bindings) in match_one(first, tokens) {
+        for (rest_length, rest_bindings) in match_prefix(rest, &tokens[length..]) {
+            let mut bindings = bindings.clone();
+            bindings.extend(rest_bindings);
+            matches.push((length + rest_length, bindings));
+        }
+    }
+    matches
+}
+
+fn match_one(matcher: &Matcher, tokens: &[Spanned<Token>]) -> Vec<(usize, Bindings)> {
+    match matcher {
+        Matcher::Token(token) => match tokens.first() {
+            Some(first) if first.node == *token => vec![(1, Bindings::new())],
+            _ => Vec::new(),
+        },
+        Matcher::Fragment(name, fragment) => fragment_lengths(fragment, tokens).into_iter()
+            .map(|length| (length, Bindings::from([(name.clone(), Binding::Fragment(tokens[..length].to_vec()))])))
+            .collect(),
+        Matcher::Repeat(inner, separator, at_least_once) => {
+            let mut matches = Vec::new();
+            if !at_least_once {
+                matches.push((0, repeated(inner, &[])));
+            }
+
+            let mut unfinished = vec![(0, Vec::new())];
+            while let Some((mut start, iterations)) = unfinished.pop() {
+                if let (Some(separator), false) = (separator, iterations.is_empty()) {
+                    match tokens.get(start) {
+                        Some(token) if token.node == *separator => start += 1,
+                        _ => continue,
+                    }
+                }
+                for (length, bindings) in match_prefix(inner, &tokens[start..]) {
+                    if length == 0 {
+                        continue
+                    }
+                    let mut iterations = iterations.clone();
+                    iterations.push(bindings);
+                    matches.push((start + length, repeated(inner, &iterations)));
+                    unfinished.push((start + length, iterations));
+                }
+            }
+            matches
+        },
+    }
+}
+
+/// Collects each variable in a repetition into the list of what it matched on every iteration.
+fn repeated(inner: &[Matcher], iterations: &[Bindings]) -> Bindings {
+    let mut variables = HashSet::new();
+    pattern_variables(inner, &mut variables);
+
+    variables.into_iter().map(|name| {
+        let items = iterations.iter().map(|bindings| bindings[&name].clone()).collect();
+        (name, Binding::Repeated(items))
+    }).collect()
+}
+
+/// The lengths of the starts of `tokens` a fragment could match, longest first.
+fn fragment_lengths(fragment: &Fragment, tokens: &[Spanned<Token>]) -> Vec<usize> {
+    let Some(first) = tokens.first() else {
+        return Vec::new()
+    };
+    match fragment {
+        Fragment::Ident if matches!(first.node, Token::Identifier(_)) => vec![1],
+        Fragment::Tt if !is_closer(&first.node) => vec![tree_length(tokens)],
+        Fragment::Block if first.node == Token::LeftBrace => vec![tree_length(tokens)],
+        Fragment::Expr => {
+            let mut lengths = Vec::new();
+            let mut end = 0;
+            while end < tokens.len() && !is_closer(&tokens[end].node) {
+                end += tree_length(&tokens[end..]);
+                if is_expression(&tokens[..end]) {
+                    lengths.push(end);
+                }
+            }
+            lengths.reverse();
+            lengths
+        },
+        _ => Vec::new(),
+    }
+}
+
+fn is_expression(tokens: &[Spanned<Token>]) -> bool {
+    let program = crate::parser::parse(tokens.iter().map(|t| (t.node.clone(), t.span)).collect());
+    matches!(program.as_deref(), Ok([Spanned { node: Statement::Expression { .. }, .. }]))
+}
+
+/// Writes out a template. Its own tokens take the span of the call and its
+/// names are marked for hygiene; what the variables matched is copied as is.
+fn transcribe(transcribers: &[Transcriber], bindings: &Bindings, call: Span, tokens: &mut Vec<Spanned<Token>>) -> Result<(), ExpandError> {
+    for transcriber in transcribers {
+        match transcriber {
+            Transcriber::Token(Token::Identifier(name)) => tokens.push(Spanned::new(Token::Identifier(format!("{}{}", name, MARK)), call)),
+            Transcriber::Token(token) => tokens.push(Spanned::new(token.clone(), call)),
+            Transcriber::Variable(name) => match &bindings[name] {
+                // An expression of several tokens keeps its meaning whatever surrounds it.
+                Binding::Fragment(fragment) if tree_length(fragment) < fragment.len() => {
+                    tokens.push(Spanned::new(Token::LeftParen, call));
+                    tokens.extend(fragment.iter().cloned());
+                    tokens.push(Spanned::new(Token::RightParen, call));
+                },
+                Binding::Fragment(fragment) => tokens.extend(fragment.iter().cloned()),
+                Binding::Repeated(_) => return Err(ExpandError::StillRepeating(name.clone())),
+            },
+            Transcriber::Repeat(inner, separator) => {
+                let mut variables = HashSet::new();
+                template_variables(inner, &mut variables);
+                let repeating: Vec<(&Identifier, &Vec<Binding>)> = variables.iter()
+                    .filter_map(|name| match &bindings[name] {
+                        Binding::Repeated(items) => Some((name, items)),
+                        Binding::Fragment(_) => None,
+                    })
+                    .collect();
+
+                let Some(count) = repeating.first().map(|(_, items)| items.len()) else {
+                    return Err(ExpandError::NothingRepeats)
+                };
+                if repeating.iter().any(|(_, items)| items.len() != count) {
+                    return Err(ExpandError::RepetitionMismatch)
+                }
+
+                for i in 0..count {
+                    if let (Some(separator), true) = (separator, i > 0) {
+                        tokens.push(Spanned::new(separator.clone(), call));
+                    }
+                    let mut iteration = bindings.clone();
+                    for (name, items) in &repeating {
+                        iteration.insert((*name).clone(), items[i].clone());
+                    }
+                    transcribe(inner, &iteration, call, tokens)?;
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// The source text of the tokens passed to `stringify!`, without the
+/// parentheses expansion puts around a whole expression.
+fn stringify(tokens: &[Spanned<Token>]) -> String {
+    match tokens {
+        [Spanned { node: Token::LeftParen, .. }, inner @ .., Spanned { node: Token::RightParen, .. }] if tree_length(tokens) == tokens.len() => {
+            crate::ast::print::tokens(inner)
+        },
+        _ => crate::ast::print::tokens(tokens),
+    }
+}
+
+/// Collects the names an expansion declares.
+struct Declared(HashSet<Identifier>);
+
+impl Visitor for Declared {
+    fn visit_statement(&mut self, statement: &Spanned<Statement>) {
+        match &statement.node {
+            Statement::FunctionDeclaration { name, .. }
+            | Statement::LetDeclaration { name, .. }
+            | Statement::ConstDeclaration { name, .. }
+            | Statement::Struct { name, .. }
+            | Statement::Trait { name, .. }
+            | Statement::For { binding: name, .. } => {
+                self.0.insert(name.clone());
+            },
+            // Method names are looked up on instances, so they keep the name they were written as.
+            Statement::Impl { methods, .. } => {
+                for method in methods {
+                    visit::walk_statement(self, method);
+                }
+                return
+            },
+            Statement::Try { binding: Some(name), .. }
+            | Statement::With { binding: Some(name), .. }
+            | Statement::Import { binding: ImportBinding::Namespace(name), .. } => {
+                self.0.insert(name.clone());
+            },
+            Statement::Import { binding: ImportBinding::Names(names), .. } => self.0.extend(names.iter().cloned()),
+            _ => {},
+        }
+        visit::walk_statement(self, statement)
+    }
+
+    fn visit_parameter(&mut self, parameter: &Spanned<Parameter>) {
+        self.0.insert(parameter.name.clone());
+        visit::walk_parameter(self, parameter)
+    }
+
+    fn visit_clause(&mut self, clause: &Clause) {
+        if let Clause::For(pattern, _) = clause {
+            self.0.extend(pattern.names().into_iter().cloned());
+        }
+        visit::walk_clause(self, clause)
+    }
+}
+
+/// Renames the names written in a template: those the expansion declares get
+/// a name of their own, and the rest go back to the name they were written as.
+struct Hygiene {
+    declared: HashSet<Identifier>,
+    expansion: usize,
+}
+
+impl Hygiene {
+    fn rename(&self, name: Identifier) -> Identifier {
+        match name.strip_suffix(MARK) {
+            Some(written) if self.declared.contains(&name) => format!("{}{}{}", written, MARK, self.expansion),
+            Some(written) => written.to_string(),
+            None => name,
+        }
+    }
+
+    fn rename_pattern(&self, pattern: Pattern) -> Pattern {
+        match pattern {
+            Pattern::Name(name) => Pattern::Name(self.rename(name)),
+            Pattern::Tuple(patterns) => Pattern::Tuple(patterns.into_iter().map(|p| self.rename_pattern(p)).collect()),
+        }
+    }
+
+    fn rename_tokens(&self, tokens: Vec<Spanned<Token>>) -> Vec<Spanned<Token>> {
+        tokens.into_iter().map(|Spanned { node, span }| match node {
+            Token::Identifier(name) => Spanned::new(Token::Identifier(self.rename(name)), span),
+            token => Spanned::new(token, span),
+        }).collect()
+    }
+}
+
+impl Folder for Hygiene {
+    fn fold_statement(&mut self, statement: Spanned<Statement>) -> Spanned<Statement> {
+        let Spanned { node, span } = statement;
+        let node = match node {
+            Statement::FunctionDeclaration { doc, attributes, name, params, return_type, body, kind } => {
+                let attributes = attributes.into_iter().map(|a| Spanned::new(Attribute { name: self.rename(a.node.name), ..a.node }, a.span)).collect();
+                Statement::FunctionDeclaration { doc, attributes, name: self.rename(name), params, return_type, body, kind }
+            },
+            Statement::LetDeclaration { name, annotation, initial } => Statement::LetDeclaration { name: self.rename(name), annotation, initial },
+            Statement::ConstDeclaration { doc, name, annotation, value } => Statement::ConstDeclaration { doc, name: self.rename(name), annotation, value },
+            Statement::Import { path, binding: ImportBinding::Namespace(alias) } => {
+                Statement::Import { path, binding: ImportBinding::Namespace(self.rename(alias)) }
+            },
+            Statement::Import { path, binding: ImportBinding::Names(names) } => {
+                Statement::Import { path, binding: ImportBinding::Names(names.into_iter().map(|n| self.rename(n)).collect()) }
+            },
+            Statement::For { binding, iterable, body } => Statement::For { binding: self.rename(binding), iterable, body },
+            Statement::Struct { doc, name, fields } => Statement::Struct {
+                doc,
+                name: self.rename(name),
+                fields: fields.into_iter().map(|field| self.rename(field)).collect(),
+            },
+            Statement::Impl { trait_name, target, methods } => {
+                Statement::Impl { trait_name: trait_name.map(|t| self.rename(t)), target: self.rename(target), methods }
+            },
+            Statement::Trait { name, methods } => Statement::Trait {
+                name: self.rename(name),
+                methods: methods.into_iter().map(|m| Spanned::new(TraitMethod { name: self.rename(m.node.name), ..m.node }, m.span)).collect(),
+            },
+            Statement::Try { body, binding, catch, finally } => Statement::Try { body, binding: binding.map(|b| self.rename(b)), catch, finally },
+            Statement::With { resource, binding, body } => Statement::With { resource, binding: binding.map(|b| self.rename(b)), body },
+            Statement::Macro { name, pattern, template } => Statement::Macro {
+                name: self.rename(name),
+                pattern: self.rename_tokens(pattern),
+                template: self.rename_tokens(template),
+            },
+            node => node,
+        };
+        fold::walk_statement(self, Spanned::new(node, span))
+    }
+
+    fn fold_expression(&mut self, expression: Spanned<Expression>) -> Spanned<Expression> {
+        let Spanned { node, span } = expression;
+        let node = match node {
+            Expression::Identifier(name) => Expression::Identifier(self.rename(name)),
+            Expression::Get(receiver, name) => Expression::Get(receiver, self.rename(name)),
+            Expression::OptionalGet(receiver, name) => Expression::OptionalGet(receiver, self.rename(name)),
+            Expression::MacroCall(name, arguments) => Expression::MacroCall(self.rename(name), self.rename_tokens(arguments)),
+            node => node,
+        };
+        fold::walk_expression(self, Spanned::new(node, span))
+    }
+
+    fn fold_parameter(&mut self, parameter: Spanned<Parameter>) -> Spanned<Parameter> {
+        let Spanned { mut node, span } = parameter;
+        node.name = self.rename(node.name);
+        fold::walk_parameter(self, Spanned::new(node, span))
+    }
+
+    fn fold_argument(&mut self, argument: Argument) -> Argument {
+        match argument {
+            Argument::Named(name, value) => fold::walk_argument(self, Argument::Named(self.rename(name), value)),
+            argument => fold::walk_argument(self, argument),
+        }
+    }
+
+    fn fold_clause(&mut self, clause: Clause) -> Clause {
+        match clause {
+            Clause::For(pattern, iterable) => fold::walk_clause(self, Clause::For(self.rename_pattern(pattern), iterable)),
+            clause => fold::walk_clause(self, clause),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error, PartialEq)]
+pub enum ExpandError {
+    #[error("Unknown macro {0}!.")]
+    UnknownMacro(Identifier),
+    #[error("The arguments to {0}! don't match its pattern.")]
+    NoMatch(Identifier),
+    #[error("{0}! expanded to invalid code: {1}")]
+    InvalidExpansion(Identifier, String),
+    #[error("{0}! must expand to a single expression here.")]
+    ExpectedExpression(Identifier),
+    #[error("{0}! kept expanding into itself.")]
+    RecursionLimit(Identifier),
+    #[error("Unknown fragment kind {0}, expected expr, ident, tt or block.")]
+    UnknownFragment(String),
+    #[error("${0} needs a fragment kind, as in ${0}:expr.")]
+    MissingFragment(Identifier),
+    #[error("A repetition must end in * or +, optionally after a separator.")]
+    InvalidRepetition,
+    #[error("The template uses ${0}, which its pattern doesn't bind.")]
+    UnknownVariable(Identifier),
+    #[error("${0} matched repeatedly, so it can only be used inside $( ... )*.")]
+    StillRepeating(Identifier),
+    #[error("Variables repeated together matched different numbers of times.")]
+    RepetitionMismatch,
+    #[error("A repetition in a template must use a variable that matched repeatedly.")]
+    NothingRepeats,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_source(source: &str) -> Program {
+        let tokens = crate::token::generate(source).into_iter().map(|t| t.unwrap()).collect();
+        crate::parser::parse(tokens).unwrap()
+    }
+
+    fn expand_source(source: &str) -> Result<Program, Vec<Spanned<ExpandError>>> {
+        expand(parse_source(source))
+    }
+
+    #[test]
+    fn it_expands_macros_where_they_are_called() {
+        let program = expand_source("macro double!($x:expr) { $x * 2 }\nlet y = double!(1 + 2)\ndouble!(y)").unwrap();
+
+        assert_eq!(program, parse_source("let y = (1 + 2) * 2\ny * 2"));
+    }
+
+    #[test]
+    fn it_matches_repetitions() {
+        let program = expand_source(
+            "macro sum!($($x:expr),+) { 0 $(+ $x)+ }\n\
+             macro calls!($($f:ident($($a:tt)*)),*) { [$($f($($a),*)),*] }\n\
+             sum!(1, f(2, 3), 4)\n\
+             calls!(f(1 2), g(), h(x))"
+        ).unwrap();
+
+        assert_eq!(program, parse_source("0 + 1 + f(2, 3) + 4\n[f(1, 2), g(), h(x)]"));
+        assert_eq!(
+            expand_source("macro sum!($($x:expr),+) { 0 $(+ $x)+ }\nsum!()").unwrap_err()[0].node,
+            ExpandError::NoMatch("sum".to_string())
+        );
+    }
+
+    #[test]
+    fn it_keeps_names_declared_by_a_template_apart_from_the_call_site() {
+        let program = expand_source(
+            "macro timed!($body:block) { let start = now()\ntry $body finally { println(now() - start) } }\n\
+             let start = 1\n\
+             timed!({ println(start) })\n\
+             macro define!($name:ident) { let $name = 2 }\n\
+             define!(answer)\n\
+             answer"
+        ).unwrap();
+
+        assert_eq!(crate::ast::print::print(&program), "let start = 1\n\
+            let start#1 = now()\n\
+            try {\n    println(start)\n} finally {\n    println(now() - start#1)\n}\n\
+            let answer = 2\n\
+            answer\n");
+
+        let program = expand_source("macro squares!($e:expr) { [v * v for (v, _) in $e] }\nsquares!(v)").unwrap();
+        assert_eq!(crate::ast::print::print(&program), "[v#1 * v#1 for (v#1, _#1) in v]\n");
+    }
+
+    #[test]
+    fn it_captures_source_text_with_stringify() {
+        let program = expand_source("macro check!($c:expr) { if $c {} else { throw stringify!($c) } }\ncheck!(f(x) + 1)").unwrap();
+
+        assert_eq!(program, parse_source("if f(x) + 1 {} else { throw \"f(x) + 1\" }"));
+    }
+
+    #[test]
+    fn it_reports_errors_at_the_call() {
+        let errors = expand_source("let x = 1\nlet y = nope!(x)\nmacro loop!() { loop!() }\nloop!()\nmacro two!() { 1\n2 }\nlet z = two!()").unwrap_err();
+        let errors: Vec<(String, ExpandError)> = errors.into_iter().map(|e| (e.span.to_string(), e.node)).collect();
+
+        assert_eq!(errors, vec![
+            ("2:9".to_string(), ExpandError::UnknownMacro("nope".to_string())),
+            ("4:1".to_string(), ExpandError::RecursionLimit("loop".to_string())),
+            ("7:9".to_string(), ExpandError::ExpectedExpression("two".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn it_rejects_templates_using_unbound_variables() {
+        let errors = expand_source("macro bad!($x:expr) { $y }").unwrap_err();
+
+        assert_eq!(errors[0].node, ExpandError::UnknownVariable("y".to_string()));
+        assert_eq!(expand_source("macro bad!($x) { $x }").unwrap_err()[0].node, ExpandError::MissingFragment("x".to_string()));
+    }
+}
@@ -1,105 +1,1807 @@
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::slice::Iter;
 use std::rc::Rc;
 use std::cell::{RefCell, Ref, RefMut};
+use corosensei::{Coroutine, Yielder};
+use thiserror::Error;
 
 use crate::ast::*;
 use crate::environment::*;
+use crate::event_loop::{Clock, EventLoop, PromiseState};
+use crate::iterator::{advance, iterate, Range, Sequence};
+use crate::module::ModuleLoader;
 
-pub fn interpret(ast: Program) {
-    let mut interpreter = Interpreter::new(ast.iter());
-    interpreter.define_global_function("println", crate::stdlib::println);
-    interpreter.run();
+/// A body running on its own stack: a generator suspends with each yielded
+/// value, an async task with each promise it awaits.
+pub type Routine = Coroutine<(), Value, Result<Value, RuntimeError>>;
+
+/// Runs a script, which sees the arguments given to it as the list `args`.
+pub fn interpret(ast: Program, path: &Path, args: Vec<String>) -> Result<(), RuntimeError> {
+    let mut interpreter = Interpreter::script(&ast, path, args);
+    interpreter.run_script()
+}
+
+/// Runs a script, then every function it marked `@test`, reporting each one.
+/// Returns whether they all passed.
+pub fn test(ast: Program, path: &Path, args: Vec<String>) -> Result<bool, RuntimeError> {
+    let mut interpreter = Interpreter::script(&ast, path, args);
+    interpreter.run_script()?;
+
+    let tests = interpreter.tests.borrow().clone();
+    let mut failed = 0;
+    for test in &tests {
+        // An async test passes once its promise resolves.
+        let result = interpreter.call(test.clone(), Vec::new(), Vec::new())
+            .and_then(|value| interpreter.await_value(value));
+        match result {
+            Ok(_) => println!("test {} ... ok", test.function_name().unwrap_or_default()),
+            Err(error) => {
+                failed += 1;
+                println!("test {} ... FAILED\n    {}", test.function_name().unwrap_or_default(), error);
+            },
+        }
+    }
+    println!("{} passed, {} failed", tests.len() - failed, failed);
+    Ok(failed == 0)
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Interpreter<'i> {
-    ast: Iter<'i, Statement>,
+    ast: Iter<'i, Spanned<Statement>>,
     environment: Rc<RefCell<Environment>>,
-    globals: HashMap<String, Value>,
+    /// The outermost scope, holding the standard library. Every module's own scope extends it.
+    globals: Rc<RefCell<Environment>>,
+    modules: Rc<RefCell<ModuleLoader>>,
+    events: Rc<RefCell<EventLoop>>,
+    path: PathBuf,
+    exports: Vec<Identifier>,
+    frames: Vec<String>,
+    tests: Rc<RefCell<Vec<Value>>>,
+    /// What each block being run has deferred so far, innermost last.
+    deferred: Vec<Vec<Spanned<Expression>>>,
+    yielder: Option<&'i Yielder<(), Value>>,
 }
 
 impl<'i> Interpreter<'i> {
-    fn new(ast: Iter<'i, Statement>) -> Self {
+    /// An interpreter for the script at `path`, with the standard library, a
+    /// real clock, and the arguments the script was given.
+    fn script(ast: &'i Program, path: &Path, args: Vec<String>) -> Self {
+        let mut interpreter = Interpreter::new(ast.iter());
+        interpreter.events = Rc::new(RefCell::new(EventLoop::new(Clock::Real(std::time::Instant::now()))));
+        interpreter.modules = Rc::new(RefCell::new(ModuleLoader::for_script(path)));
+        interpreter.path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        interpreter.define_stdlib();
+        // Bound in the script's own scope rather than as a global, so parameters named `args` still shadow it.
+        let args = Value::List(args.into_iter().map(Value::String).collect());
+        interpreter.env_mut().set("args", args).expect("a new scope has no constants");
+        interpreter
+    }
+
+    fn run_script(&mut self) -> Result<(), RuntimeError> {
+        let entry = self.path.clone();
+        self.modules.borrow_mut().enter(&entry)?;
+        let result = self.run().and_then(|_| EventLoop::run_until(&self.events, None));
+        self.modules.borrow_mut().leave(&entry, None);
+        result
+    }
+
+    fn new(ast: Iter<'i, Spanned<Statement>>) -> Self {
+        let globals = Rc::new(RefCell::new(Environment::new()));
         Self {
-            ast:ast,
-            environment: Rc::new(RefCell::new(Environment::new())),
-            globals: HashMap::new(),
+            ast,
+            environment: Rc::new(RefCell::new(Environment::extend(globals.clone()))),
+            globals,
+            modules: Rc::new(RefCell::new(ModuleLoader::default())),
+            events: Rc::new(RefCell::new(EventLoop::new(Clock::Virtual))),
+            path: PathBuf::from("."),
+            exports: Vec::new(),
+            frames: Vec::new(),
+            tests: Rc::default(),
+            deferred: Vec::new(),
+            yielder: None,
         }
     }
 
-    fn run_statement(&mut self, statement: Statement) {
-        match statement {
-            Statement::LetDeclaration { name, initial } => {
-                if initial.is_none() {
-                    self.env_mut().set(name, None)
-                } else {
-                    let initial = initial.unwrap();
-                    let value = self.run_expression(initial);
-                    self.env_mut().set(name, value)
+    fn run_statement(&mut self, statement: Spanned<Statement>) -> Result<Value, RuntimeError> {
+        match statement.node {
+            Statement::FunctionDeclaration { doc, attributes, name, params, body, kind, .. } => {
+                let function = self.function(doc, attributes, name.clone(), params, body, kind)?;
+                self.env_mut().set(name, function)?
+            },
+            Statement::LetDeclaration { name, initial, .. } => {
+                let value = match initial {
+                    Some(initial) => self.run_expression(initial)?,
+                    None => Value::Nil,
+                };
+                self.env_mut().set(name, value)?
+            },
+            Statement::ConstDeclaration { name, value, .. } => {
+                let value = self.run_expression(value)?;
+                self.env_mut().set_constant(name, value)?
+            },
+            Statement::Import { path, binding } => {
+                let module = self.import(&path)?;
+
+                match binding {
+                    ImportBinding::Namespace(alias) => self.env_mut().set(alias, module)?,
+                    ImportBinding::Names(names) => for name in names {
+                        let value = self.member(&module, &name)?;
+                        self.env_mut().set(name, value)?
+                    },
+                }
+            },
+            Statement::Export { declaration } => {
+                match &declaration.node {
+                    Statement::FunctionDeclaration { name, .. }
+                    | Statement::LetDeclaration { name, .. }
+                    | Statement::ConstDeclaration { name, .. }
+                    | Statement::Struct { name, .. }
+                    | Statement::Trait { name, .. } => {
+                        self.exports.push(name.clone())
+                    },
+                    _ => unreachable!(),
+                }
+                self.run_statement(*declaration)?;
+            },
+            Statement::For { binding, iterable, body } => {
+                let iterable = self.run_expression(iterable)?;
+                let sequence = iterate(iterable, self)?;
+
+                while let Some(item) = advance(&sequence, self)? {
+                    match self.run_scoped_block(body.clone(), vec![(binding.clone(), item)]) {
+                        Err(RuntimeError::Break) => break,
+                        result => result?,
+                    };
+                }
+            },
+            Statement::Yield { value } => {
+                let value = self.run_expression(value)?;
+                // Only an AST read from JSON can yield outside a generator.
+                self.yielder.ok_or(RuntimeError::YieldOutsideGenerator)?.suspend(value);
+            },
+            Statement::While { condition, body } => {
+                while truthy(&self.run_expression(condition.clone())?) {
+                    match self.run_scoped_block(body.clone(), Vec::new()) {
+                        Err(RuntimeError::Break) => break,
+                        result => result?,
+                    };
+                }
+            },
+            Statement::Return { value } => {
+                let value = match value {
+                    Some(value) => self.run_expression(value)?,
+                    None => Value::Nil,
+                };
+                return Err(RuntimeError::Return(value))
+            },
+            Statement::Break => return Err(RuntimeError::Break),
+            Statement::Throw { value } => {
+                let value = match self.run_expression(value)? {
+                    error @ Value::Error { .. } => error,
+                    value => self.error_value("Error", value.to_string()),
+                };
+                return Err(RuntimeError::Thrown(value))
+            },
+            Statement::Defer { value } => {
+                self.deferred.last_mut().expect("statements run inside a block").push(value);
+            },
+            Statement::With { resource, binding, body } => {
+                let resource = self.run_expression(resource)?;
+                let Some(close) = resource.method("close") else {
+                    return Err(self.raise("TypeError", format!("{} can't be used with `with`, it has no close method", resource.type_name())))
+                };
+
+                let result = self.run_scoped_block(body, binding.into_iter().map(|b| (b, resource.clone())).collect());
+                let cleanup = self.call(close, vec![resource], Vec::new());
+                return after_cleanup(result, cleanup)
+            },
+            Statement::Try { body, binding, catch, finally } => {
+                let result = match (self.run_scoped_block(body, Vec::new()), catch) {
+                    (Err(error), Some(catch)) if !matches!(error, RuntimeError::Return(_) | RuntimeError::Break) => {
+                        let error = self.catch(error);
+                        self.run_scoped_block(catch, binding.into_iter().map(|b| (b, error.clone())).collect())
+                    },
+                    (result, _) => result,
+                };
+
+                if let Some(finally) = finally {
+                    self.run_scoped_block(finally, Vec::new())?;
+                }
+                return result
+            },
+            Statement::If { condition, then, otherwise } => {
+                let condition = self.run_expression(condition)?;
+                return match (truthy(&condition), otherwise) {
+                    (true, _) => self.run_scoped_block(then, Vec::new()),
+                    (false, Some(otherwise)) => self.run_scoped_block(otherwise, Vec::new()),
+                    (false, None) => Ok(Value::Nil),
                 }
             },
             Statement::Expression {expression} => {
-                self.run_expression(expression);
+                return self.run_expression(expression)
+            },
+            Statement::Struct { doc, name, fields } => {
+                let ty = StructType { name: name.clone(), doc, fields, methods: RefCell::default(), traits: RefCell::default() };
+                self.env_mut().set(name, Value::Struct(Rc::new(ty)))?
+            },
+            Statement::Impl { trait_name, target, methods } => {
+                let ty = match self.run_expression(Expression::Identifier(target.clone()).into())? {
+                    Value::Struct(ty) => ty,
+                    _ => return Err(RuntimeError::NotAStruct(target)),
+                };
+                let implemented = match trait_name {
+                    Some(name) => match self.run_expression(Expression::Identifier(name.clone()).into())? {
+                        Value::Trait(implemented) => Some(implemented),
+                        _ => return Err(RuntimeError::NotATrait(name)),
+                    },
+                    None => None,
+                };
+
+                let mut defined = Vec::new();
+                for method in methods {
+                    if let Statement::FunctionDeclaration { doc, attributes, name, params, body, kind, .. } = method.node {
+                        let function = self.function(doc, attributes, name.clone(), params, body, kind)?;
+                        defined.push((name, function));
+                    }
+                }
+
+                // An impl of a trait is checked against it as a whole, before any of its methods are added.
+                if let Some(implemented) = implemented {
+                    if let Some((method, _)) = defined.iter().find(|(name, _)| !implemented.methods.iter().any(|(m, _)| m == name)) {
+                        return Err(RuntimeError::UnknownTraitMethod { trait_name: implemented.name.clone(), method: method.clone() })
+                    }
+                    let mut missing = Vec::new();
+                    for (name, default) in &implemented.methods {
+                        match (defined.iter().any(|(defined, _)| defined == name), default) {
+                            (true, _) => {},
+                            (false, Some(default)) => defined.push((name.clone(), default.clone())),
+                            (false, None) => missing.push(name.clone()),
+                        }
+                    }
+                    if !missing.is_empty() {
+                        return Err(RuntimeError::MissingTraitMethods { trait_name: implemented.name.clone(), target, missing })
+                    }
+                    ty.traits.borrow_mut().push(implemented);
+                }
+                ty.methods.borrow_mut().extend(defined);
             },
-            _ => todo!("{:?}",statement),
+            Statement::Trait { name, methods } => {
+                let methods = methods.into_iter().map(|method| {
+                    let TraitMethod { name, params, default, .. } = method.node;
+                    let default = default.map(|body| Value::Function {
+                        name: name.clone(),
+                        params,
+                        body,
+                        kind: FunctionKind::Normal,
+                        environment: self.environment.clone(),
+                        doc: None,
+                    });
+                    (name, default)
+                }).collect();
+                let declared = TraitType { name: name.clone(), methods };
+                self.env_mut().set(name, Value::Trait(Rc::new(declared)))?
+            },
+            // Macros are expanded away before the program runs.
+            Statement::Macro { .. } => {},
         }
+
+        Ok(Value::Nil)
     }
 
-    fn run_expression(&mut self, expression: Expression) -> Option<Value> {
-        Some(match expression {
-            Expression::Number(n) => Value::Number(n),
-            Expression::String(s) => Value::String(s),
-            Expression::Identifier(n) => {
-                if self.globals.contains_key(&n) {
-                    self.globals[&n].clone() 
-                } else {
-                    return self.env().get(n)
+    /// Makes the value a function declaration binds its name to, closing over the current scope.
+    fn function(&mut self, doc: Option<String>, attributes: Vec<Spanned<Attribute>>, name: Identifier, params: Vec<Spanned<Parameter>>, body: Block, kind: FunctionKind) -> Result<Value, RuntimeError> {
+        let mut function = Value::Function {
+            name,
+            params,
+            body,
+            kind,
+            environment: self.environment.clone(),
+            doc: doc.map(Rc::new),
+        };
+        // The decorator closest to the function wraps it first.
+        for attribute in attributes.into_iter().rev() {
+            function = self.decorate(function, attribute.node)?;
+        }
+        Ok(function)
+    }
+
+    /// Runs a block's statements, then whatever they deferred, whether the block
+    /// finished, returned or threw.
+    fn run_block(&mut self, block: Block) -> Result<Value, RuntimeError> {
+        let (hoisted, rest): (Vec<_>, Vec<_>) = block.into_iter().partition(is_hoisted);
+        self.deferred.push(Vec::new());
+        let mut result = Ok(Value::Nil);
+        for statement in hoisted.into_iter().chain(rest) {
+            result = self.run_statement(statement);
+            if result.is_err() {
+                break
+            }
+        }
+
+        let deferred = self.deferred.pop().expect("every block has its own deferred list");
+        for value in deferred.into_iter().rev() {
+            let cleanup = self.run_expression(value);
+            result = after_cleanup(result, cleanup);
+        }
+        result
+    }
+
+    fn run_scoped_block(&mut self, block: Block, bindings: Vec<(Identifier, Value)>) -> Result<Value, RuntimeError> {
+        let scope = Rc::new(RefCell::new(Environment::extend(self.environment.clone())));
+        let outer = std::mem::replace(&mut self.environment, scope);
+        for (name, value) in bindings {
+            self.env_mut().set(name, value)?;
+        }
+        let result = self.run_block(block);
+        self.environment = outer;
+        result
+    }
+
+    /// Runs a comprehension's clauses in a scope of their own, so the names they
+    /// bind don't leak, calling `emit` for every item that gets through them.
+    fn comprehend(&mut self, clauses: &[Clause], emit: &mut dyn FnMut(&mut Self) -> Result<(), RuntimeError>) -> Result<(), RuntimeError> {
+        let scope = Rc::new(RefCell::new(Environment::extend(self.environment.clone())));
+        let outer = std::mem::replace(&mut self.environment, scope);
+        let result = self.run_clauses(clauses, emit);
+        self.environment = outer;
+        result
+    }
+
+    fn run_clauses(&mut self, clauses: &[Clause], emit: &mut dyn FnMut(&mut Self) -> Result<(), RuntimeError>) -> Result<(), RuntimeError> {
+        match clauses.split_first() {
+            None => emit(self),
+            Some((Clause::If(condition), rest)) => match self.run_expression(condition.clone())? {
+                value if truthy(&value) => self.run_clauses(rest, emit),
+                _ => Ok(()),
+            },
+            Some((Clause::For(pattern, iterable), rest)) => {
+                let iterable = self.run_expression(iterable.clone())?;
+                let sequence = iterate(iterable, self)?;
+
+                while let Some(item) = advance(&sequence, self)? {
+                    self.bind(pattern, item)?;
+                    self.run_clauses(rest, emit)?;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Sets the names in a pattern to the parts of a value.
+    fn bind(&mut self, pattern: &Pattern, value: Value) -> Result<(), RuntimeError> {
+        match (pattern, value) {
+            (Pattern::Name(name), value) => self.env_mut().set(name.clone(), value),
+            (Pattern::Tuple(patterns), Value::List(items)) if patterns.len() == items.len() => {
+                for (pattern, item) in patterns.iter().zip(items) {
+                    self.bind(pattern, item)?;
                 }
+                Ok(())
+            },
+            (pattern, value) => Err(self.raise("TypeError", format!("can't take {} apart as {}", value, pattern))),
+        }
+    }
+
+    /// Where a key is in a map's entries.
+    fn entry(&mut self, entries: &[(Value, Value)], key: &Value) -> Result<Option<usize>, RuntimeError> {
+        for (i, (existing, _)) in entries.iter().enumerate() {
+            if self.equals(existing, key)? {
+                return Ok(Some(i))
             }
-            Expression::Infix(left, op, right) => {
-                let left = self.run_expression(*left).unwrap();
-                let right = self.run_expression(*right).unwrap();
+        }
+        Ok(None)
+    }
 
-                match (left, op, right) {
-                    (Value::Number(l), Op::Add, Value::Number(r)) => Value::Number(l + r),
-                    (Value::Number(l), Op::Multiply, Value::Number(r)) => Value::Number(l * r),
-                    (Value::Number(l), Op::Divide, Value::Number(r)) => Value::Number(l / r),
-                    (Value::Number(l), Op::Subtract, Value::Number(r)) => Value::Number(l - r),
-                    _ => todo!()
+    /// Adds a key to a map's entries, replacing its value if it's already there.
+    fn insert(&mut self, entries: &mut Vec<(Value, Value)>, key: Value, value: Value) -> Result<(), RuntimeError> {
+        match self.entry(entries, &key)? {
+            Some(i) => entries[i].1 = value,
+            None => entries.push((key, value)),
+        }
+        Ok(())
+    }
+
+    fn run_expression(&mut self, expression: Spanned<Expression>) -> Result<Value, RuntimeError> {
+        Ok(match expression.node {
+            Expression::Number(n) => Value::Number(n),
+            Expression::String(s) | Expression::InterpolatedString(s) => Value::String(s),
+            Expression::Bool(b) => Value::Bool(b),
+            Expression::Nil => Value::Nil,
+            Expression::Identifier(n) => self.env().get(n)?,
+            Expression::List(items) => {
+                let mut values = Vec::new();
+                for item in items {
+                    values.push(self.run_expression(item)?);
+                }
+                Value::List(values)
+            },
+            Expression::ListComprehension(item, clauses) => {
+                let mut items = Vec::new();
+                self.comprehend(&clauses, &mut |interpreter| {
+                    items.push(interpreter.run_expression((*item).clone())?);
+                    Ok(())
+                })?;
+                Value::List(items)
+            },
+            Expression::MapComprehension(key, value, clauses) => {
+                let mut entries = Vec::new();
+                self.comprehend(&clauses, &mut |interpreter| {
+                    let key = interpreter.run_expression((*key).clone())?;
+                    let value = interpreter.run_expression((*value).clone())?;
+                    interpreter.insert(&mut entries, key, value)
+                })?;
+                Value::Map(entries)
+            },
+            Expression::Prefix(Op::Bang, value) => Value::Bool(!truthy(&self.run_expression(*value)?)),
+            Expression::Prefix(op, value) => match self.run_expression(*value)? {
+                Value::Number(n) => Value::Number(-n),
+                value => return Err(RuntimeError::UnsupportedPrefix { op, value: value.type_name() }),
+            },
+            Expression::Infix(left, Op::Coalesce, right) => match self.run_expression(*left)? {
+                Value::Nil => self.run_expression(*right)?,
+                value => value,
+            },
+            Expression::Infix(left, op, right) => {
+                let left = self.run_expression(*left)?;
+                let right = self.run_expression(*right)?;
+                return self.operate(left, op, right)
+            },
+            Expression::Index(value, index) => {
+                let value = self.run_expression(*value)?;
+                let index = self.run_expression(*index)?;
+                return self.index(value, index)
+            },
+            Expression::Range(start, end, inclusive) => {
+                let start = self.run_expression(*start)?;
+                let end = self.run_expression(*end)?;
+                match (start, end) {
+                    (Value::Number(start), Value::Number(end)) => Value::Range(Range::new(start, end, inclusive)),
+                    (start, end) => {
+                        let message = format!("Range bounds must be numbers, not {} and {}", start.type_name(), end.type_name());
+                        return Err(self.raise("TypeError", message))
+                    },
                 }
             },
+            Expression::Closure(params, body) => Value::Function {
+                name: String::from("<Closure>"),
+                params,
+                body,
+                kind: FunctionKind::Normal,
+                environment: self.environment.clone(),
+                doc: None,
+            },
             Expression::Call(callable, arguments) => {
-                let callable = self.run_expression(*callable).unwrap();
-                let arguments: Vec<Value>= arguments.into_iter().map(|a| self.run_expression(a).unwrap()).collect();
-                match callable {
-                    Value::NativeFunction { callback, .. } => return callback(self, arguments),
-                    _ => todo!(),
+                let Spanned { node: callable, span } = *callable;
+                let (callable, mut positional) = match callable {
+                    Expression::Get(receiver, name) => {
+                        let receiver = self.run_expression(*receiver)?;
+                        self.method(receiver, name)?
+                    },
+                    Expression::OptionalGet(receiver, name) => match self.run_expression(*receiver)? {
+                        Value::Nil => return Ok(Value::Nil),
+                        receiver => self.method(receiver, name)?,
+                    },
+                    callable => (self.run_expression(Spanned::new(callable, span))?, Vec::new()),
+                };
+                let named = self.run_arguments(arguments, &mut positional)?;
+                return self.call(callable, positional, named)
+            },
+            Expression::Get(receiver, name) => {
+                let receiver = self.run_expression(*receiver)?;
+                return self.member(&receiver, &name)
+            },
+            Expression::OptionalGet(receiver, name) => match self.run_expression(*receiver)? {
+                Value::Nil => Value::Nil,
+                receiver => return self.member(&receiver, &name),
+            },
+            Expression::OptionalIndex(value, index) => match self.run_expression(*value)? {
+                Value::Nil => Value::Nil,
+                value => {
+                    let index = self.run_expression(*index)?;
+                    return self.index(value, index)
+                },
+            },
+            Expression::OptionalCall(callable, arguments) => match self.run_expression(*callable)? {
+                Value::Nil => Value::Nil,
+                callable => {
+                    let mut positional = Vec::new();
+                    let named = self.run_arguments(arguments, &mut positional)?;
+                    return self.call(callable, positional, named)
+                },
+            },
+            Expression::Propagate(value) => match self.run_expression(*value)? {
+                // `?` returns an error from the enclosing function, or throws it at the top level.
+                error @ Value::Error { .. } if self.frames.is_empty() => return Err(RuntimeError::Thrown(error)),
+                error @ Value::Error { .. } => return Err(RuntimeError::Return(error)),
+                value => value,
+            },
+            Expression::Await(value) => {
+                let value = self.run_expression(*value)?;
+                return self.await_value(value)
+            },
+            Expression::MacroCall(name, _) => return Err(RuntimeError::UnexpandedMacro(name)),
+        })
+    }
+
+    /// What `receiver.name(args)` calls, along with the arguments it's called with
+    /// before `args`. Modules are the only values with members, so for anything
+    /// else without a method of that name it calls `name(receiver, args)`.
+    fn method(&mut self, receiver: Value, name: Identifier) -> Result<(Value, Vec<Value>), RuntimeError> {
+        Ok(match receiver {
+            module @ Value::Module { .. } => (self.member(&module, &name)?, Vec::new()),
+            receiver @ Value::Instance { .. } if receiver.method(&name).is_some() => (receiver.method(&name).unwrap(), vec![receiver]),
+            receiver => (self.run_expression(Expression::Identifier(name).into())?, vec![receiver]),
+        })
+    }
+
+    /// Evaluates arguments, adding positional ones to `positional` and returning the named ones.
+    fn run_arguments(&mut self, arguments: Vec<Argument>, positional: &mut Vec<Value>) -> Result<Vec<(Identifier, Value)>, RuntimeError> {
+        let mut named = Vec::new();
+        for argument in arguments {
+            match argument {
+                Argument::Positional(a) => positional.push(self.run_expression(a)?),
+                Argument::Named(name, a) => named.push((name, self.run_expression(a)?)),
+                Argument::Spread(a) => match self.run_expression(a)? {
+                    Value::List(items) => positional.extend(items),
+                    value => return Err(RuntimeError::InvalidSpread(value)),
+                },
+            }
+        }
+        Ok(named)
+    }
+
+    /// Passes `function` to the decorator named by `attribute`, or with arguments,
+    /// to the decorator that calling it with them returns.
+    fn decorate(&mut self, function: Value, attribute: Attribute) -> Result<Value, RuntimeError> {
+        let Attribute { name, arguments } = attribute;
+        let mut decorator = self.run_expression(Expression::Identifier(name.clone()).into())?;
+        if !arguments.is_empty() {
+            let mut positional = Vec::new();
+            let named = self.run_arguments(arguments, &mut positional)?;
+            decorator = self.call(decorator, positional, named)?;
+        }
+
+        match self.call(decorator, vec![function], Vec::new())? {
+            Value::Nil => Err(RuntimeError::InvalidDecorator(name)),
+            decorated => Ok(decorated),
+        }
+    }
+
+    /// Remembers a function marked `@test`, to be run by `test` once the script has run.
+    pub fn register_test(&mut self, function: Value) {
+        self.tests.borrow_mut().push(function);
+    }
+
+    /// Applies a binary operator. Operands it has no built-in meaning for are
+    /// handed to the left operand's hook method, such as `add` for `+`.
+    fn operate(&mut self, left: Value, op: Op, right: Value) -> Result<Value, RuntimeError> {
+        let value = match op {
+            Op::Equal | Op::NotEqual => Value::Bool(self.equals(&left, &right)? == (op == Op::Equal)),
+            Op::Implements => match (&left, right) {
+                (Value::Instance { ty, .. }, Value::Trait(implemented)) => {
+                    Value::Bool(ty.traits.borrow().iter().any(|t| Rc::ptr_eq(t, &implemented)))
+                },
+                (_, Value::Trait(_)) => Value::Bool(false),
+                (_, right) => return Err(RuntimeError::NotATrait(right.to_string())),
+            },
+            Op::In => match (left, right) {
+                (Value::Number(n), Value::Range(range)) => Value::Bool(range.contains(n)),
+                (_, Value::Range(_)) => Value::Bool(false),
+                (Value::String(part), Value::String(string)) => Value::Bool(string.contains(&part)),
+                (key, Value::Map(entries)) => Value::Bool(self.entry(&entries, &key)?.is_some()),
+                (left, Value::List(items)) => {
+                    let mut found = false;
+                    for item in &items {
+                        if self.equals(&left, item)? {
+                            found = true;
+                            break
+                        }
+                    }
+                    Value::Bool(found)
+                },
+                // The container is the one asked, so `x in c` calls `c.contains(x)`.
+                (left, right) => return self.call_hook(right, &op, left),
+            },
+            Op::Less | Op::LessEqual | Op::Greater | Op::GreaterEqual => {
+                let ordering = self.compare(left, &op, right)?;
+                Value::Bool(ordering.is_some_and(|ordering| match op {
+                    Op::Less => ordering.is_lt(),
+                    Op::LessEqual => ordering.is_le(),
+                    Op::Greater => ordering.is_gt(),
+                    _ => ordering.is_ge(),
+                }))
+            },
+            _ => match (left, right) {
+                (Value::Number(l), Value::Number(r)) => Value::Number(match op {
+                    Op::Add => l + r,
+                    Op::Subtract => l - r,
+                    Op::Multiply => l * r,
+                    Op::Divide => l / r,
+                    _ => l % r,
+                }),
+                (Value::String(l), Value::String(r)) if op == Op::Add => Value::String(l + &r),
+                (left, right) => return self.call_hook(left, &op, right),
+            },
+        };
+        Ok(value)
+    }
+
+    fn equals(&mut self, left: &Value, right: &Value) -> Result<bool, RuntimeError> {
+        Ok(match (left, right) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Number(l), Value::Number(r)) => l == r,
+            (Value::String(l), Value::String(r)) => l == r,
+            (Value::Bool(l), Value::Bool(r)) => l == r,
+            (Value::Range(l), Value::Range(r)) => l == r,
+            (Value::List(l), Value::List(r)) => {
+                if l.len() != r.len() {
+                    return Ok(false)
+                }
+                for (l, r) in l.iter().zip(r) {
+                    if !self.equals(l, r)? {
+                        return Ok(false)
+                    }
+                }
+                true
+            },
+            // Maps are equal when they have the same keys with equal values, in any order.
+            (Value::Map(l), Value::Map(r)) => {
+                if l.len() != r.len() {
+                    return Ok(false)
+                }
+                for (key, value) in l {
+                    match self.entry(r, key)? {
+                        Some(i) if self.equals(value, &r[i].1)? => {},
+                        _ => return Ok(false),
+                    }
+                }
+                true
+            },
+            (Value::Error { kind: lk, message: lm, .. }, Value::Error { kind: rk, message: rm, .. }) => lk == rk && lm == rm,
+            (Value::Iterator(l), Value::Iterator(r)) => Rc::ptr_eq(l, r),
+            (Value::Promise(l), Value::Promise(r)) => Rc::ptr_eq(l, r),
+            (Value::Struct(l), Value::Struct(r)) => Rc::ptr_eq(l, r),
+            (Value::Trait(l), Value::Trait(r)) => Rc::ptr_eq(l, r),
+            (Value::Instance { .. }, _) if left.method("eq").is_some() => {
+                match self.call_hook(left.clone(), &Op::Equal, right.clone())? {
+                    Value::Bool(equal) => equal,
+                    _ => return Err(self.raise("TypeError", format!("{}.eq must return a Bool", left.type_name()))),
+                }
+            },
+            // Without an `eq` method, instances are equal when their fields are.
+            (Value::Instance { ty: lt, fields: lf }, Value::Instance { ty: rt, fields: rf }) => {
+                if !Rc::ptr_eq(lt, rt) {
+                    return Ok(false)
                 }
+                self.equals(&Value::List(lf.clone()), &Value::List(rf.clone()))?
             },
-            _ => todo!("{:?}", expression),
+            (Value::Module { environment: l, .. }, Value::Module { environment: r, .. }) => Rc::ptr_eq(l, r),
+            // Functions have no identity of their own, so they're the same one when declared
+            // with the same name and body in the same scope.
+            (Value::Function { name: ln, body: lb, environment: le, .. }, Value::Function { name: rn, body: rb, environment: re, .. }) => {
+                ln == rn && Rc::ptr_eq(le, re) && lb == rb
+            },
+            (Value::NativeFunction { documentation: l, .. }, Value::NativeFunction { documentation: r, .. }) => Rc::ptr_eq(l, r),
+            (Value::NativeClosure { callback: l, .. }, Value::NativeClosure { callback: r, .. }) => Rc::ptr_eq(l, r),
+            _ => false,
         })
     }
 
-    fn define_global_function(&mut self, name: impl Into<String>, callback: NativeFunctionCallback) {
-        let name = name.into();
-        self.globals.insert(name.clone(), Value::NativeFunction {
-            name: name,
-            callback: callback,
+    /// Orders two values, or gives `None` when they're unordered, like `NaN` and a number.
+    fn compare(&mut self, left: Value, op: &Op, right: Value) -> Result<Option<std::cmp::Ordering>, RuntimeError> {
+        Ok(match (&left, &right) {
+            (Value::Number(l), Value::Number(r)) => l.partial_cmp(r),
+            (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+            _ => {
+                let name = left.type_name();
+                match self.call_hook(left, op, right)? {
+                    Value::Number(n) => n.partial_cmp(&0.0),
+                    _ => return Err(self.raise("TypeError", format!("{}.cmp must return a Number", name))),
+                }
+            },
+        })
+    }
+
+    /// Calls the method a struct defines to handle `op`.
+    fn call_hook(&mut self, left: Value, op: &Op, right: Value) -> Result<Value, RuntimeError> {
+        let hook = hook(op);
+        match left.method(hook) {
+            Some(method) => self.call(method, vec![left, right], Vec::new()),
+            None => Err(RuntimeError::UnsupportedOperator { op: op.clone(), left: left.type_name(), right: right.type_name(), hook }),
+        }
+    }
+
+    fn index(&mut self, value: Value, index: Value) -> Result<Value, RuntimeError> {
+        let position = |i: f64, length: usize| match i {
+            i if i >= 0.0 && i.fract() == 0.0 && (i as usize) < length => Ok(i as usize),
+            index => Err(RuntimeError::IndexOutOfRange { index, length }),
+        };
+
+        Ok(match (value, index) {
+            (Value::List(items), Value::Number(i)) => items[position(i, items.len())?].clone(),
+            // A key that isn't there is nil.
+            (Value::Map(mut entries), key) => match self.entry(&entries, &key)? {
+                Some(i) => entries.swap_remove(i).1,
+                None => Value::Nil,
+            },
+            (Value::String(s), Value::Number(i)) => {
+                let chars: Vec<char> = s.chars().collect();
+                Value::String(chars[position(i, chars.len())?].to_string())
+            },
+            // Indexing with a range slices, taking each position the range produces.
+            (Value::List(items), Value::Range(range)) => Value::List(range.numbers()
+                .map(|i| position(i, items.len()).map(|i| items[i].clone()))
+                .collect::<Result<_, _>>()?),
+            (Value::String(s), Value::Range(range)) => {
+                let chars: Vec<char> = s.chars().collect();
+                Value::String(range.numbers()
+                    .map(|i| position(i, chars.len()).map(|i| chars[i]))
+                    .collect::<Result<_, _>>()?)
+            },
+            (value, index) => match value.method("index") {
+                Some(method) => return self.call(method, vec![value, index], Vec::new()),
+                None => return Err(RuntimeError::NotIndexable { value: value.type_name(), index: index.type_name() }),
+            },
+        })
+    }
+
+    /// Makes an instance of a struct from arguments naming its fields in order or by name.
+    fn construct(&mut self, ty: Rc<StructType>, arguments: Vec<Value>, named: Vec<(Identifier, Value)>) -> Result<Value, RuntimeError> {
+        let arity_mismatch = || RuntimeError::ArityMismatch { function: ty.name.clone(), params: ty.fields.join(", "), given: arguments.len() + named.len() };
+        if arguments.len() > ty.fields.len() {
+            return Err(arity_mismatch())
+        }
+
+        let mut fields = vec![None; ty.fields.len()];
+        for (field, value) in fields.iter_mut().zip(arguments.iter().cloned()) {
+            *field = Some(value);
+        }
+        for (argument, value) in named.iter().cloned() {
+            match ty.fields.iter().position(|field| *field == argument) {
+                None => return Err(RuntimeError::UnknownArgument { function: ty.name.clone(), argument }),
+                Some(i) if fields[i].is_some() => return Err(RuntimeError::DuplicateArgument { function: ty.name.clone(), argument }),
+                Some(i) => fields[i] = Some(value),
+            }
+        }
+
+        let fields = fields.into_iter().collect::<Option<Vec<_>>>().ok_or_else(arity_mismatch)?;
+        Ok(Value::Instance { ty, fields })
+    }
+
+    fn member(&self, value: &Value, name: &str) -> Result<Value, RuntimeError> {
+        match value {
+            Value::Module { name: module, environment, exports } => {
+                if !exports.iter().any(|export| export == name) {
+                    return Err(RuntimeError::MissingExport { module: module.clone(), name: name.to_string() })
+                }
+                environment.borrow().get(name)
+            },
+            Value::Error { kind, message, trace } => match name {
+                "kind" => Ok(Value::String(kind.clone())),
+                "message" => Ok(Value::String(message.clone())),
+                "trace" => Ok(Value::List(trace.iter().cloned().map(Value::String).collect())),
+                _ => Err(RuntimeError::UnknownMember { value: value.clone(), name: name.to_string() }),
+            },
+            Value::Instance { ty, fields } => match (ty.fields.iter().position(|field| field == name), value.method(name)) {
+                (Some(i), _) => Ok(fields[i].clone()),
+                // A method read without calling it keeps its receiver.
+                (None, Some(method)) => {
+                    let receiver = value.clone();
+                    Ok(Value::NativeClosure {
+                        name: name.to_string(),
+                        documentation: Rc::new(method.documentation().unwrap_or_default()),
+                        callback: Rc::new(move |interpreter, mut arguments, named| {
+                            arguments.insert(0, receiver.clone());
+                            interpreter.call(method.clone(), arguments, named)
+                        }),
+                    })
+                },
+                (None, None) => Err(RuntimeError::UnknownMember { value: value.clone(), name: name.to_string() }),
+            },
+            value => Err(RuntimeError::UnknownMember { value: value.clone(), name: name.to_string() }),
+        }
+    }
+
+    /// Evaluates a module in its own environment the first time it is imported,
+    /// returning the cached module on later imports.
+    fn import(&mut self, path: &str) -> Result<Value, RuntimeError> {
+        let resolved = self.modules.borrow().resolve(path, &self.path)?;
+        if let Some(module) = self.modules.borrow().cached(&resolved) {
+            return Ok(module)
+        }
+
+        self.modules.borrow_mut().enter(&resolved)?;
+        let ast = match ModuleLoader::load(&resolved) {
+            Ok(ast) => ast,
+            Err(error) => {
+                self.modules.borrow_mut().leave(&resolved, None);
+                return Err(error)
+            },
+        };
+
+        let mut interpreter = Interpreter::new(ast.iter());
+        interpreter.environment = Rc::new(RefCell::new(Environment::extend(self.globals.clone())));
+        interpreter.globals = self.globals.clone();
+        interpreter.modules = self.modules.clone();
+        interpreter.events = self.events.clone();
+        interpreter.path = resolved.clone();
+        interpreter.frames = self.frames.clone();
+        interpreter.tests = self.tests.clone();
+
+        let module = interpreter.run().map(|_| Value::Module {
+            name: path.to_string(),
+            environment: interpreter.environment.clone(),
+            exports: Rc::new(interpreter.exports.clone()),
         });
+        self.modules.borrow_mut().leave(&resolved, module.as_ref().ok().cloned());
+        module
+    }
+
+    pub fn call(&mut self, callable: Value, arguments: Vec<Value>, named: Vec<(Identifier, Value)>) -> Result<Value, RuntimeError> {
+        match callable {
+            Value::NativeFunction { name, callback, .. } => {
+                if let Some((argument, _)) = named.into_iter().next() {
+                    return Err(RuntimeError::UnknownArgument { function: name, argument })
+                }
+                callback(self, arguments)
+            },
+            Value::NativeClosure { callback, .. } => callback(self, arguments, named),
+            Value::Struct(ty) => self.construct(ty, arguments, named),
+            Value::Function { name, params, body, kind, environment, .. } => {
+                let frame = Rc::new(RefCell::new(Environment::extend(environment)));
+                let caller = std::mem::replace(&mut self.environment, frame);
+                let result = self.bind_arguments(&name, &params, arguments, named).and_then(|_| {
+                    match kind {
+                        FunctionKind::Generator => {
+                            let generator = self.coroutine(Some(name), move |interpreter| interpreter.run_block(body));
+                            return Ok(Value::Iterator(Rc::new(RefCell::new(Sequence::Generator(generator)))))
+                        },
+                        FunctionKind::Async => {
+                            let task = self.coroutine(Some(name), move |interpreter| interpreter.run_block(body));
+                            return Ok(Value::Promise(self.events.borrow_mut().spawn(task)))
+                        },
+                        FunctionKind::Normal => {},
+                    }
+
+                    self.frames.push(name);
+                    let result = match self.run_block(body) {
+                        Err(RuntimeError::Return(value)) => Ok(value),
+                        // Errors leaving a function become script errors, so they keep the trace from where they happened.
+                        Err(error) => Err(RuntimeError::Thrown(self.catch(error))),
+                        result => result,
+                    };
+                    self.frames.pop();
+                    result
+                });
+                self.environment = caller;
+                result
+            },
+            value => Err(RuntimeError::NotCallable(value)),
+        }
+    }
+
+    /// Prepares `body` to run on its own stack, in an interpreter that shares this one's
+    /// environment, globals, modules and event loop. Nothing runs until it is first resumed.
+    pub fn coroutine(&self, frame: Option<String>, body: impl FnOnce(&mut Interpreter) -> Result<Value, RuntimeError> + 'static) -> Box<Routine> {
+        let environment = self.environment.clone();
+        let globals = self.globals.clone();
+        let modules = self.modules.clone();
+        let events = self.events.clone();
+        let path = self.path.clone();
+        let tests = self.tests.clone();
+        let mut frames = self.frames.clone();
+        frames.extend(frame);
+
+        Box::new(Coroutine::new(move |yielder: &Yielder<(), Value>, ()| {
+            let mut interpreter = Interpreter::new(Default::default());
+            interpreter.environment = environment;
+            interpreter.globals = globals;
+            interpreter.modules = modules;
+            interpreter.events = events;
+            interpreter.path = path;
+            interpreter.frames = frames;
+            interpreter.tests = tests;
+            interpreter.yielder = Some(yielder);
+
+            match body(&mut interpreter) {
+                Err(RuntimeError::Return(value)) => Ok(value),
+                Err(error) => Err(RuntimeError::Thrown(interpreter.catch(error))),
+                result => result,
+            }
+        }))
+    }
+
+    /// Waits for a promise to settle. Inside a task this suspends it until the event loop
+    /// wakes it, while at the top level it runs the event loop until the promise settles.
+    pub fn await_value(&mut self, value: Value) -> Result<Value, RuntimeError> {
+        let promise = match value {
+            Value::Promise(promise) => promise,
+            value => return Ok(value),
+        };
+
+        if promise.borrow().is_pending() {
+            match self.yielder {
+                Some(yielder) => {
+                    yielder.suspend(Value::Promise(promise.clone()));
+                },
+                None => EventLoop::run_until(&self.events, Some(&promise))?,
+            }
+        }
+
+        let mut promise = promise.borrow_mut();
+        promise.handled = true;
+        match &promise.state {
+            PromiseState::Resolved(value) => Ok(value.clone()),
+            PromiseState::Rejected(error) => Err(RuntimeError::Thrown(error.clone())),
+            PromiseState::Pending => Err(RuntimeError::NeverSettled),
+        }
+    }
+
+    pub fn events(&self) -> &Rc<RefCell<EventLoop>> {
+        &self.events
+    }
+
+    fn bind_arguments(&mut self, function: &str, params: &[Spanned<Parameter>], arguments: Vec<Value>, mut named: Vec<(Identifier, Value)>) -> Result<(), RuntimeError> {
+        let given = arguments.len() + named.len();
+        let arity_mismatch = || RuntimeError::ArityMismatch {
+            function: function.to_string(),
+            params: params.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", "),
+            given,
+        };
+        let mut arguments = arguments.into_iter();
+
+        for param in params {
+            let value = if param.variadic {
+                Value::List(arguments.by_ref().collect())
+            } else if let Some(value) = arguments.next() {
+                if named.iter().any(|(name, _)| name == &param.name) {
+                    return Err(RuntimeError::DuplicateArgument { function: function.to_string(), argument: param.name.clone() })
+                }
+                value
+            } else if let Some(index) = named.iter().position(|(name, _)| name == &param.name) {
+                named.remove(index).1
+            } else if let Some(default) = &param.default {
+                // Defaults are evaluated in the new frame so they can refer to earlier parameters.
+                self.run_expression(default.clone())?
+            } else {
+                return Err(arity_mismatch())
+            };
+
+            self.env_mut().set(param.name.clone(), value)?;
+        }
+
+        if arguments.next().is_some() {
+            return Err(arity_mismatch())
+        }
+
+        if let Some((argument, _)) = named.into_iter().next() {
+            return Err(RuntimeError::UnknownArgument { function: function.to_string(), argument })
+        }
+
+        Ok(())
+    }
+
+    /// Builds an error value carrying the current script stack trace.
+    pub fn error_value(&self, kind: impl Into<String>, message: impl Into<String>) -> Value {
+        Value::Error {
+            kind: kind.into(),
+            message: message.into(),
+            trace: self.frames.iter().rev().cloned().collect(),
+        }
+    }
+
+    /// Raises a script error that can be caught with `try`/`catch`.
+    pub fn raise(&self, kind: impl Into<String>, message: impl Into<String>) -> RuntimeError {
+        RuntimeError::Thrown(self.error_value(kind, message))
+    }
+
+    /// Turns any catchable error into the error value a `catch` block receives.
+    fn catch(&self, error: RuntimeError) -> Value {
+        match error {
+            RuntimeError::Thrown(value) => value,
+            error => self.error_value(error.kind(), error.to_string()),
+        }
+    }
+
+    fn define_stdlib(&mut self) {
+        self.define_global_function("print", Documentation::new("...values", "Prints values separated by spaces, without ending the line."), crate::stdlib::print);
+        self.define_global_function("println", Documentation::new("value", "Prints a value on its own line."), crate::stdlib::println);
+        self.define_global_function("error", Documentation::new("message, kind = \"Error\"", "An error value to `throw`."), crate::stdlib::error);
+        self.define_global_function("read_file", Documentation::new("path", "The contents of a file, as a string."), crate::stdlib::read_file);
+        self.define_global_function("open", Documentation::new("path, mode = \"r\"", "Opens a file to read, or with mode \"w\" to write over it or \"a\" to append to it."), crate::stdlib::open);
+        self.define_global_function("len", Documentation::new("items", "The number of items in a list or map, or of characters in a string."), crate::stdlib::len);
+        self.define_global_function("split", Documentation::new("string, separator", "The parts of a string between each separator."), crate::stdlib::split);
+        self.define_global_function("map", Documentation::new("items, f", "Maps over a list eagerly, or over an iterator or range lazily."), crate::stdlib::map);
+        self.define_global_function("take", Documentation::new("items, n", "Takes the first `n` values of a list eagerly, or of an iterator or range lazily."), crate::stdlib::take);
+        self.define_global_function("collect", Documentation::new("items", "Runs an iterator to the end, collecting its values into a list."), crate::stdlib::collect);
+        self.define_global_function("step", Documentation::new("range, k", "Counts through a range `k` at a time."), crate::stdlib::step);
+        self.define_global_function("reverse", Documentation::new("items", "Reverses a list, string or range."), crate::stdlib::reverse);
+        self.define_global_function("sleep", Documentation::new("ms", "A promise that resolves to `ms` once that many milliseconds have passed."), crate::stdlib::sleep);
+        self.define_global_function("set_timeout", Documentation::new("f, ms", "Calls a function after `ms` milliseconds, returning a promise of its result."), crate::stdlib::set_timeout);
+        self.define_global_function("all", Documentation::new("promises", "A promise of the values of every promise in a list."), crate::stdlib::all);
+        self.define_global_function("exec", Documentation::new("program, args = []", "A promise of the exit `status`, `stdout` and `stderr` of running a program."), crate::stdlib::exec);
+        self.define_global_function("now", Documentation::new("", "Milliseconds since the event loop started."), crate::stdlib::now);
+        self.define_global_function("memoize", Documentation::new("f", "Decorator remembering what a function returned for each list of arguments."), crate::stdlib::memoize);
+        self.define_global_function("trace", Documentation::new("f", "Decorator reporting every call of a function on stderr."), crate::stdlib::trace);
+        self.define_global_function("deprecated", Documentation::new("message", "Decorator warning on stderr the first time a function is called."), crate::stdlib::deprecated);
+        self.define_global_function("test", Documentation::new("f", "Decorator marking a function to be run by `crustacean test`."), crate::stdlib::test);
+        self.define_global_function("help", Documentation::new("value", "Prints the signature and doc comment of a function or struct."), crate::stdlib::help);
+        self.define_global_function("doc", Documentation::new("value", "The doc comment of a function or struct, or nil if it has none."), crate::stdlib::doc);
     }
 
-    fn env(&self) -> Ref<Environment> {
+    /// Defines a native function, along with the signature and doc comment `help` shows for it.
+    fn define_global_function(&mut self, name: impl Into<String>, documentation: Documentation, callback: NativeFunctionCallback) {
+        let name = name.into();
+        let function = Value::NativeFunction { name: name.clone(), callback, documentation: Rc::new(documentation) };
+        self.globals.borrow_mut().set(name, function).expect("the standard library has no constants");
+    }
+
+    fn env(&self) -> Ref<'_, Environment> {
         RefCell::borrow(&self.environment)
     }
 
-    fn env_mut(&mut self) -> RefMut<Environment> {
+    fn env_mut(&mut self) -> RefMut<'_, Environment> {
         RefCell::borrow_mut(&self.environment)
     }
- 
-    fn run(&mut self) {
-        while let Some(statement) = self.ast.next() {
-            self.run_statement(statement.clone());
+
+    /// Runs the program after defining its functions, so they can be called,
+    /// and can call each other, from anywhere in it.
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        let program = self.ast.by_ref().cloned().collect();
+        self.run_block(program).map(|_| ())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RuntimeError {
+    #[error("Uncaught {}", describe_thrown(.0))]
+    Thrown(Value),
+    #[error("Cannot return from outside a function.")]
+    Return(Value),
+    #[error("Cannot break from outside a loop.")]
+    Break,
+    #[error("Wrong number of arguments for {function}: expected ({params}), got {given}.")]
+    ArityMismatch { function: String, params: String, given: usize },
+    #[error("{function}() has no parameter named {argument}.")]
+    UnknownArgument { function: String, argument: Identifier },
+    #[error("{function}() received more than one value for {argument}.")]
+    DuplicateArgument { function: String, argument: Identifier },
+    #[error("Cannot spread {0:?}, only lists can be spread into arguments.")]
+    InvalidSpread(Value),
+    #[error("{0:?} is not callable.")]
+    NotCallable(Value),
+    #[error("{value:?} has no member named {name}.")]
+    UnknownMember { value: Value, name: Identifier },
+    #[error("Module {path} not found, searched {searched}.")]
+    ModuleNotFound { path: String, searched: String },
+    #[error("Could not load module {path}: {message}")]
+    InvalidModule { path: String, message: String },
+    #[error("{0} is not defined.")]
+    UndefinedVariable(Identifier),
+    #[error("Cannot reassign constant {0}.")]
+    ConstantReassignment(Identifier),
+    #[error("yield can only be used inside a fn* generator.")]
+    YieldOutsideGenerator,
+    #[error("Awaited a promise that can never settle.")]
+    NeverSettled,
+    #[error("Import cycle detected: {0}.")]
+    ImportCycle(String),
+    #[error("Module {module} does not export {name}.")]
+    MissingExport { module: String, name: Identifier },
+    #[error("Decorator {0} returned nothing.")]
+    InvalidDecorator(Identifier),
+    #[error("Cannot apply {op} to {left} and {right}: {left} has no {hook} method.")]
+    UnsupportedOperator { op: Op, left: String, right: String, hook: &'static str },
+    #[error("Cannot apply {op} to {value}.")]
+    UnsupportedPrefix { op: Op, value: String },
+    #[error("{0}! was never expanded, so it cannot run.")]
+    UnexpandedMacro(Identifier),
+    #[error("Cannot index {value} with {index}: {value} has no index method.")]
+    NotIndexable { value: String, index: String },
+    #[error("Index {index} is out of range for length {length}.")]
+    IndexOutOfRange { index: f64, length: usize },
+    #[error("{0} is not a struct, so it cannot have an impl.")]
+    NotAStruct(Identifier),
+    #[error("{0} is not a trait.")]
+    NotATrait(String),
+    #[error("impl {trait_name} for {target} is missing {}.", .missing.join(", "))]
+    MissingTraitMethods { trait_name: String, target: Identifier, missing: Vec<Identifier> },
+    #[error("{method} is not a method of trait {trait_name}.")]
+    UnknownTraitMethod { trait_name: String, method: Identifier },
+}
+
+impl RuntimeError {
+    pub fn kind(&self) -> &str {
+        match self {
+            RuntimeError::Thrown(Value::Error { kind, .. }) => kind,
+            RuntimeError::Thrown(_) | RuntimeError::Return(_) => "Error",
+            RuntimeError::ArityMismatch { .. } | RuntimeError::UnknownArgument { .. } | RuntimeError::DuplicateArgument { .. } => "ArgumentError",
+            RuntimeError::InvalidSpread(_) | RuntimeError::NotCallable(_) | RuntimeError::UnknownMember { .. } => "TypeError",
+            RuntimeError::ConstantReassignment(_) | RuntimeError::InvalidDecorator(_) => "TypeError",
+            RuntimeError::UnsupportedOperator { .. } | RuntimeError::NotIndexable { .. } | RuntimeError::NotAStruct(_) => "TypeError",
+            RuntimeError::UnsupportedPrefix { .. } | RuntimeError::UnexpandedMacro(_) => "TypeError",
+            RuntimeError::NotATrait(_) | RuntimeError::MissingTraitMethods { .. } | RuntimeError::UnknownTraitMethod { .. } => "TypeError",
+            RuntimeError::IndexOutOfRange { .. } => "IndexError",
+            RuntimeError::UndefinedVariable(_) => "NameError",
+            RuntimeError::NeverSettled => "AsyncError",
+            RuntimeError::YieldOutsideGenerator | RuntimeError::Break => "SyntaxError",
+            RuntimeError::ModuleNotFound { .. } | RuntimeError::InvalidModule { .. } | RuntimeError::ImportCycle(_) | RuntimeError::MissingExport { .. } => "ImportError",
         }
     }
-}
\ No newline at end of file
+}
+
+/// The method a struct defines to give `op` a meaning for its instances.
+fn hook(op: &Op) -> &'static str {
+    match op {
+        Op::Add => "add",
+        Op::Subtract => "sub",
+        Op::Multiply => "mul",
+        Op::Divide => "div",
+        Op::Modulo => "rem",
+        Op::Equal | Op::NotEqual => "eq",
+        Op::Less | Op::LessEqual | Op::Greater | Op::GreaterEqual => "cmp",
+        Op::Bang => unreachable!("! is a prefix operator"),
+        Op::In => "contains",
+        Op::Coalesce => unreachable!("?? is evaluated before its right side is"),
+        Op::Implements => unreachable!("implements can't be overloaded"),
+    }
+}
+
+/// What a block gives once its cleanup has run: the block's own result, unless
+/// the cleanup failed when the block hadn't.
+fn after_cleanup(result: Result<Value, RuntimeError>, cleanup: Result<Value, RuntimeError>) -> Result<Value, RuntimeError> {
+    match (result, cleanup) {
+        (Ok(_) | Err(RuntimeError::Return(_) | RuntimeError::Break), Err(error)) => Err(error),
+        (result, _) => result,
+    }
+}
+
+/// Whether a statement declares a function that runs before the rest of its
+/// block, in the program or any block within it. Decorated functions are declared where they're written, since
+/// their decorators may use values defined above them.
+fn is_hoisted(statement: &Spanned<Statement>) -> bool {
+    match &statement.node {
+        Statement::FunctionDeclaration { attributes, .. } => attributes.is_empty(),
+        Statement::Export { declaration } => is_hoisted(declaration),
+        _ => false,
+    }
+}
+
+/// Whether a condition holds: everything but `false` and nil does.
+fn truthy(value: &Value) -> bool {
+    !matches!(value, Value::Bool(false) | Value::Nil)
+}
+
+fn describe_thrown(value: &Value) -> String {
+    match value {
+        Value::Error { trace, .. } => trace.iter().fold(value.to_string(), |description, frame| {
+            format!("{}\n    at {}", description, frame)
+        }),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::tests::TempDir;
+
+    fn evaluate(source: &str) -> Result<Value, RuntimeError> {
+        let tokens = crate::token::generate(source).into_iter().map(|t| t.unwrap()).collect();
+        let ast = crate::parser::parse(tokens).unwrap();
+        let mut interpreter = Interpreter::new(ast.iter());
+        interpreter.define_stdlib();
+        interpreter.run_block(ast.clone())
+    }
+
+    fn output(source: &str) -> String {
+        evaluate(source).unwrap().to_string()
+    }
+
+    fn evaluate_file(path: &Path) -> Result<Value, RuntimeError> {
+        let ast = ModuleLoader::load(path)?;
+        let mut interpreter = Interpreter::new(ast.iter());
+        interpreter.modules = Rc::new(RefCell::new(ModuleLoader::new(vec![path.parent().unwrap().join("lib")])));
+        interpreter.path = path.canonicalize().unwrap();
+
+        let entry = interpreter.path.clone();
+        interpreter.modules.borrow_mut().enter(&entry)?;
+        interpreter.run_block(ast.clone())
+    }
+
+    #[test]
+    fn it_calls_functions_as_methods_of_their_first_argument() {
+        assert_eq!(output("let xs = [1, 2, 3]\n[xs.len(), len(xs), \"héllo\".len()]"), "[3, 3, 5]");
+        assert_eq!(output("fn add(a, b) { a + b }\n1.add(2)"), "3");
+    }
+
+    #[test]
+    fn it_pipes_values_into_the_first_argument() {
+        assert_eq!(output("fn sub(a, b) { a - b }\n10 |> sub(3)"), "7");
+        assert_eq!(output("[1, 2, 3] |> len"), "3");
+        assert_eq!(output("fn sub(a, b) { a - b }\n10 |> sub(3) |> sub(1)"), "6");
+    }
+
+    #[test]
+    fn it_raises_a_catchable_error_for_undefined_names() {
+        assert_eq!(output("try { println(nope) } catch e { [e.kind, e.message] }"), "[NameError, nope is not defined.]");
+        assert!(matches!(evaluate("nope"), Err(RuntimeError::UndefinedVariable(name)) if name == "nope"));
+    }
+
+    #[test]
+    fn it_negates_truthiness_with_bang() {
+        assert_eq!(output("[!true, !false, !nil, !0, !\"\", ![]]"), "[false, true, true, false, false, false]");
+    }
+
+    #[test]
+    fn it_negates_numbers() {
+        assert_eq!(output("let x = 2\n[-x, -(x + 1), - -x]"), "[-2, -3, 2]");
+        assert_eq!(output("try { -\"a\" } catch e { e.message }"), "Cannot apply - to String.");
+    }
+
+    #[test]
+    fn it_can_call_short_lambdas() {
+        assert_eq!(output("let double = |x| x * 2\ndouble(4)"), "8");
+        assert_eq!(output("let triple = x => x * 3\n2 |> triple"), "6");
+    }
+
+    #[test]
+    fn it_can_call_functions_with_default_parameters() {
+        let source = "fn add(a, b = a * 10) { a + b }\n";
+
+        assert_eq!(output(&format!("{}add(1)", source)), "11");
+        assert_eq!(output(&format!("{}add(1, 2)", source)), "3");
+    }
+
+    #[test]
+    fn it_can_collect_variadic_parameters_and_spread_arguments() {
+        let source = "fn rest(first, ...others) { others }\nlet xs = [2, 3]\n";
+
+        assert_eq!(output(&format!("{}rest(1, 2, 3)", source)), "[2, 3]");
+        assert_eq!(output(&format!("{}rest(1)", source)), "[]");
+        assert_eq!(output(&format!("{}rest(...xs)", source)), "[3]");
+    }
+
+    #[test]
+    fn it_can_bind_named_arguments() {
+        let source = "fn connect(host, port = 80) { [host, port] }\n";
+
+        assert_eq!(output(&format!("{}connect(port: 1, host: \"x\")", source)), "[x, 1]");
+        assert!(matches!(
+            evaluate(&format!("{}connect(\"x\", host: \"y\")", source)),
+            Err(RuntimeError::DuplicateArgument { argument, .. }) if argument == "host"
+        ));
+        assert!(matches!(
+            evaluate(&format!("{}connect(\"x\", timeout: 1)", source)),
+            Err(RuntimeError::UnknownArgument { argument, .. }) if argument == "timeout"
+        ));
+    }
+
+    #[test]
+    fn it_can_import_modules_relative_to_the_importer_and_from_the_search_path() {
+        let root = TempDir::new("imports", &[
+            ("main.crs", "import \"./util/math.crs\" as math\nimport { render } from \"format\"\nrender(math.double(21))"),
+            ("util/math.crs", "import \"../util/constants\" as constants\nexport fn double(x) { x * constants.two }"),
+            ("util/constants.crs", "export let two = 2"),
+            ("lib/format.crs", "let prefix = [\"answer\"]\nexport fn render(x) { [prefix, x] }"),
+        ]);
+
+        assert_eq!(evaluate_file(&root.join("main.crs")).unwrap().to_string(), "[[answer], 42]");
+    }
+
+    #[test]
+    fn it_evaluates_each_module_once_in_its_own_namespace() {
+        let root = TempDir::new("namespaces", &[
+            ("main.crs", "let secret = 1\nimport \"./a\" as a\nimport \"./a\" as again\n[a, again, secret, a == again]"),
+            ("a.crs", "let secret = 2\nexport let shared = [secret]"),
+        ]);
+
+        let modules = match evaluate_file(&root.join("main.crs")).unwrap() {
+            Value::List(items) => items,
+            value => panic!("expected a list, found {:?}", value),
+        };
+        match (&modules[0], &modules[1]) {
+            (Value::Module { environment: a, .. }, Value::Module { environment: again, .. }) => assert!(Rc::ptr_eq(a, again)),
+            modules => panic!("expected two modules, found {:?}", modules),
+        }
+        assert_eq!(modules[2].to_string(), "1");
+        assert_eq!(modules[3].to_string(), "true");
+    }
+
+    #[test]
+    fn it_only_exposes_exported_names() {
+        let root = TempDir::new("exports", &[
+            ("main.crs", "import { secret } from \"./a\""),
+            ("a.crs", "let secret = 2"),
+        ]);
+
+        assert!(matches!(
+            evaluate_file(&root.join("main.crs")),
+            Err(RuntimeError::MissingExport { name, .. }) if name == "secret"
+        ));
+    }
+
+    #[test]
+    fn it_reports_import_cycles_with_the_chain() {
+        let root = TempDir::new("cycles", &[
+            ("main.crs", "import \"./a\" as a"),
+            ("a.crs", "import \"./b\" as b"),
+            ("b.crs", "import \"./a\" as a"),
+        ]);
+
+        let error = evaluate_file(&root.join("main.crs")).unwrap_err().to_string();
+        let root = root.canonicalize().unwrap();
+        assert_eq!(error, format!(
+            "Import cycle detected: {a} -> {b} -> {a}.",
+            a = root.join("a.crs").display(),
+            b = root.join("b.crs").display(),
+        ));
+    }
+
+    #[test]
+    fn it_reports_missing_modules() {
+        let root = TempDir::new("missing", &[("main.crs", "import \"nowhere\" as nowhere")]);
+
+        assert!(matches!(evaluate_file(&root.join("main.crs")), Err(RuntimeError::ModuleNotFound { .. })));
+    }
+
+    #[test]
+    fn it_can_throw_and_catch_errors() {
+        assert_eq!(output("try { throw \"boom\" } catch e { [e.kind, e.message] }"), "[Error, boom]");
+        assert_eq!(output("try { throw error(\"bad\", \"ValueError\") } catch e { e }"), "ValueError: bad");
+        assert_eq!(output("try { 1 } catch e { 2 }"), "1");
+    }
+
+    #[test]
+    fn it_runs_finally_blocks_on_every_path() {
+        let error = evaluate("try { 1 } finally { throw \"cleanup\" }").unwrap_err();
+        assert_eq!(error.to_string(), "Uncaught Error: cleanup");
+
+        let error = evaluate("try { throw \"first\" } catch e { 1 } finally { throw \"cleanup\" }").unwrap_err();
+        assert_eq!(error.to_string(), "Uncaught Error: cleanup");
+
+        let error = evaluate("try { throw \"first\" } finally { 1 }").unwrap_err();
+        assert_eq!(error.to_string(), "Uncaught Error: first");
+    }
+
+    #[test]
+    fn it_records_the_script_stack_trace() {
+        let source = "fn inner() { throw \"bad\" }\nfn outer() { inner() }\n";
+
+        assert_eq!(output(&format!("{}try {{ outer() }} catch e {{ e.trace }}", source)), "[inner, outer]");
+        assert_eq!(evaluate(&format!("{}outer()", source)).unwrap_err().to_string(), "Uncaught Error: bad\n    at inner\n    at outer");
+    }
+
+    #[test]
+    fn it_can_catch_interpreter_and_native_errors() {
+        assert_eq!(output("try { 1() } catch e { e.kind }"), "TypeError");
+        assert_eq!(output("fn one(a) { a }\ntry { one() } catch e { e.kind }"), "ArgumentError");
+        assert_eq!(output("try { read_file(\"/does/not/exist\") } catch e { e.kind }"), "IOError");
+    }
+
+    #[test]
+    fn it_can_propagate_returned_errors() {
+        let source = "fn parse(x) { error(\"nope\") }\nfn ok(x) { x }\n";
+
+        assert_eq!(output(&format!("{}fn run() {{ parse(1)?\n2 }}\nrun().message", source)), "nope");
+        assert_eq!(output(&format!("{}fn run() {{ ok(1)? + 1 }}\nrun()", source)), "2");
+        assert_eq!(output(&format!("{}fn run(x) {{ let y = x?\ny + 1 }}\n[run(1), run(parse(1)).message]", source)), "[2, nope]");
+        assert_eq!(evaluate(&format!("{}parse(1)?", source)).unwrap_err().to_string(), "Uncaught Error: nope\n    at parse");
+    }
+
+    #[test]
+    fn it_can_suspend_and_resume_generators() {
+        assert_eq!(output("fn* numbers() { yield 1 yield 2 yield 3 }\ncollect(numbers())"), "[1, 2, 3]");
+        assert_eq!(output("fn* numbers() { yield 1 yield 2 }\nnumbers().map(|x| x * 10).collect()"), "[10, 20]");
+    }
+
+    #[test]
+    fn it_rejects_yield_outside_generators_in_a_given_ast() {
+        let ast: Program = vec![Statement::Yield { value: Expression::Number(1.0).into() }.into()];
+        let mut interpreter = Interpreter::new(ast.iter());
+
+        assert!(matches!(interpreter.run_block(ast.clone()), Err(RuntimeError::YieldOutsideGenerator)));
+    }
+
+    #[test]
+    fn it_evaluates_generators_lazily() {
+        let source = "fn* count(n) { yield n\nfor m in count(n + 1) { yield m } }\n";
+
+        assert_eq!(output(&format!("{}count(1) |> take(3) |> collect", source)), "[1, 2, 3]");
+        assert_eq!(output("fn* risky() { yield 1\nthrow \"boom\" }\nrisky() |> take(1) |> collect"), "[1]");
+        assert_eq!(output("fn* risky() { yield 1\nthrow \"boom\" }\ntry { collect(risky()) } catch e { e.trace }"), "[risky]");
+    }
+
+    #[test]
+    fn it_can_loop_over_lists_and_generators() {
+        let root = TempDir::new("generators", &[("log.txt", "first,second,third")]);
+        let source = format!(
+            "fn* lines(path) {{ for line in split(read_file(path), \",\") {{ yield [line] }} }}\nlines(\"{}\") |> take(2) |> collect",
+            root.join("log.txt").display(),
+        );
+
+        assert_eq!(output(&source), "[[first], [second]]");
+        assert_eq!(output("[1, 2].map(|x| x + 1)"), "[2, 3]");
+    }
+
+    #[test]
+    fn it_runs_async_tasks_concurrently_on_a_virtual_clock() {
+        let source = "async fn after(ms, x) { await sleep(ms)\n[x, now()] }\n";
+
+        assert_eq!(output("await sleep(5)"), "5");
+        assert_eq!(output(&format!("{}await all([after(20, 1), after(10, 2)])", source)), "[[1, 20], [2, 10]]");
+        assert_eq!(output("await set_timeout(fn() { now() }, 15)"), "15");
+    }
+
+    #[test]
+    fn it_runs_subprocesses_alongside_other_tasks() {
+        let source = "async fn after(ms, x) { await sleep(ms)\nx }\n";
+
+        assert_eq!(output("let done = await exec(\"sh\", [\"-c\", \"echo out; echo err >&2; exit 3\"])\n[done[\"status\"], done[\"stdout\"], done[\"stderr\"]]"), "[3, out\n, err\n]");
+        assert_eq!(output(&format!("{}await all([after(10, 1), exec(\"echo\", [2]), after(0, 3)])", source)), "[1, {status: 0, stdout: 2\n, stderr: }, 3]");
+        assert_eq!(output("try { await exec(\"crustacean-no-such-program\") } catch e { e.kind }"), "IOError");
+    }
+
+    #[test]
+    fn it_rejects_tasks_that_yield_outside_a_generator() {
+        let interpreter = Interpreter::new(Default::default());
+        let yields = Spanned::new(Statement::Yield { value: Expression::Number(1.0).into() }, Span::default());
+        let task = interpreter.coroutine(None, move |interpreter| interpreter.run_block(vec![yields]));
+        let promise = interpreter.events().borrow_mut().spawn(task);
+
+        assert!(EventLoop::run_until(interpreter.events(), Some(&promise)).is_ok());
+        assert!(matches!(&promise.borrow().state, PromiseState::Rejected(Value::Error { kind, .. }) if kind == "SyntaxError"));
+    }
+
+    #[test]
+    fn it_can_catch_rejected_promises_when_awaiting_them() {
+        let source = "async fn fail() { await sleep(1)\nthrow \"late\" }\n";
+
+        assert_eq!(output(&format!("{}try {{ await fail() }} catch e {{ [e.message, e.trace] }}", source)), "[late, [fail]]");
+        assert_eq!(output(&format!("{}try {{ await all([sleep(1), fail()]) }} catch e {{ e.message }}", source)), "late");
+    }
+
+    #[test]
+    fn it_reports_promises_that_can_never_settle_and_unhandled_rejections() {
+        assert!(matches!(evaluate("async fn wait() { await forever }\nlet forever = wait()\nawait forever"), Err(RuntimeError::NeverSettled)));
+
+        let tokens = crate::token::generate("async fn fail() { throw \"ignored\" }\nfail()").into_iter().map(|t| t.unwrap()).collect();
+        let ast = crate::parser::parse(tokens).unwrap();
+        let mut interpreter = Interpreter::new(ast.iter());
+        interpreter.run_block(ast.clone()).unwrap();
+        let error = EventLoop::run_until(&interpreter.events, None).unwrap_err();
+        assert_eq!(error.to_string(), "Uncaught Error: ignored\n    at fail");
+    }
+
+    #[test]
+    fn it_rejects_writes_to_constants_at_runtime() {
+        assert_eq!(output("const LIMIT = [10]\nfn limit() { LIMIT }\nlimit()"), "[10]");
+        assert!(matches!(
+            evaluate("const LIMIT = 10\ntry { let LIMIT = 20 } catch e { 1 }\nlet LIMIT = 30"),
+            Err(RuntimeError::ConstantReassignment(name)) if name == "LIMIT"
+        ));
+        assert_eq!(output("try { const LIMIT = 10\nlet LIMIT = 20 } catch e { e.kind }"), "TypeError");
+        // A block's own scope can shadow a constant from outside it.
+        assert_eq!(output("const LIMIT = 10\ntry { let LIMIT = 20\nLIMIT } catch e { e.kind }"), "20");
+    }
+
+    #[test]
+    fn it_reports_arity_mismatches_with_the_expected_parameters() {
+        let error = evaluate("fn greet(name, greeting = \"Hello\", ...rest) { name }\ngreet()").unwrap_err();
+
+        assert_eq!(error.to_string(), "Wrong number of arguments for greet: expected (name, [greeting], ...rest), got 0.");
+        assert!(matches!(evaluate("fn one(a) { a }\none(1, 2)"), Err(RuntimeError::ArityMismatch { given: 2, .. })));
+        assert_eq!(evaluate("println()").unwrap_err().to_string(), "Wrong number of arguments for println: expected (value), got 0.");
+    }
+
+    #[test]
+    fn it_applies_decorators_closest_to_the_function_first() {
+        let source = "fn add_one(f) { |x| f(x) + 1 }\nfn double(f) { |x| f(x) * 2 }\nfn scale(k) { |f| |x| f(x) * k }\n";
+
+        assert_eq!(output(&format!("{}@add_one\n@double\nfn id(x) {{ x }}\nid(3)", source)), "7");
+        assert_eq!(output(&format!("{}@scale(10)\n@add_one\nfn id(x) {{ x }}\nid(3)", source)), "40");
+        assert!(matches!(evaluate("fn nothing(f) { println(f) }\n@nothing\nfn f() {}"), Err(RuntimeError::InvalidDecorator(name)) if name == "nothing"));
+    }
+
+    #[test]
+    fn it_gives_blocks_their_own_scope_inside_the_global_one() {
+        assert_eq!(output("fn sign(n) { if n < 0 { \"-\" } else { \"+\" } }\n[sign(0 - 1), sign(0)]"), "[-, +]");
+        assert_eq!(output("if nil { 1 }"), "nil");
+        assert_eq!(output("let x = 0\nif true { let x = 1 }\nfor i in 1..3 { let x = i }\ntry { let x = 4 } finally {}\nx"), "0");
+        assert_eq!(output("let y = \"outer\"\nfn f() { let y = \"inner\"\ny }\n[f(), y]"), "[inner, outer]");
+        assert_eq!(output("let f = |print| print\nlet println = 2\n[f(1), println]"), "[1, 2]");
+    }
+
+    #[test]
+    fn it_hoists_undecorated_functions_above_the_rest_of_the_program() {
+        let source = "let greeting = ping(\"Hello, \")\nexport fn ping(s) { pong(s) + \"!\" }\nfn pong(s) { s + \"world\" }\n@memoize\nfn later() {}";
+        let tokens = crate::token::generate(source).into_iter().map(|t| t.unwrap()).collect();
+        let ast = crate::parser::parse(tokens).unwrap();
+        let mut interpreter = Interpreter::new(ast.iter());
+        interpreter.define_stdlib();
+
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.env().get("greeting").unwrap().to_string(), "Hello, world!");
+        assert_eq!(interpreter.exports, vec!["ping".to_string()]);
+    }
+
+    #[test]
+    fn it_hoists_functions_in_nested_blocks_as_the_checker_does() {
+        let source = "fn g() {\n    h()\n    fn h() { 1 }\n}\ng()";
+        let tokens = crate::token::generate(source).into_iter().map(|t| t.unwrap()).collect();
+        assert!(crate::checker::check(&crate::parser::parse(tokens).unwrap()).is_empty());
+
+        assert_eq!(output(source), "1");
+        assert_eq!(output("if true { f() \n fn f() { 2 } }"), "2");
+    }
+
+    #[test]
+    fn it_gives_scripts_their_arguments_as_args() {
+        let tokens = crate::token::generate("let f = |args| args\n[args, f(1)]").into_iter().map(|t| t.unwrap()).collect();
+        let ast = crate::parser::parse(tokens).unwrap();
+        let mut interpreter = Interpreter::script(&ast, Path::new("<eval>"), vec!["-v".to_string(), "two words".to_string()]);
+
+        assert_eq!(interpreter.run_block(ast.clone()).unwrap().to_string(), "[[-v, two words], 1]");
+    }
+
+    #[test]
+    fn it_keeps_doc_comments_on_functions_and_structs() {
+        let source = "/// Adds two numbers.\n@memoize\nfn add(a, b = 1) { a + b }\n/// A point.\nstruct Point { x, y }\nfn bare() {}\n";
+
+        assert_eq!(output(&format!("{}[doc(add), doc(Point), doc(bare), doc(|x| x)]", source)), "[Adds two numbers., A point., nil, nil]");
+        assert_eq!(output("doc(take)"), "Takes the first `n` values of a list eagerly, or of an iterator or range lazily.");
+        assert_eq!(output(&format!("{}help(add)", source)), "nil");
+        let add = evaluate(&format!("{}add", source)).unwrap().documentation().unwrap();
+        assert_eq!((add.signature.as_str(), add.doc.as_deref()), ("a, b = 1", Some("Adds two numbers.")));
+        assert!(matches!(evaluate("help(1)"), Err(RuntimeError::Thrown(_))));
+    }
+
+    #[test]
+    fn it_can_memoize_trace_deprecate_and_register_tests() {
+        // A memoized generator hands back the same iterator, so taking from one advances the other.
+        let source = "@memoize\nfn* count(n) { yield n\nyield n + 1 }\ntake(count(1), 1) |> collect\n";
+        assert_eq!(output(&format!("{}count(1) |> collect", source)), "[2]");
+        assert_eq!(output(&format!("{}count(5) |> collect", source)), "[5, 6]");
+
+        assert_eq!(output("@deprecated(\"use g\")\n@trace\nfn f(x) { x * 2 }\n[f(2), f(x: 3)]"), "[4, 6]");
+
+        let tokens = crate::token::generate("@test\nfn checks() { 1 }\nchecks()").into_iter().map(|t| t.unwrap()).collect();
+        let ast = crate::parser::parse(tokens).unwrap();
+        let mut interpreter = Interpreter::new(ast.iter());
+        interpreter.define_stdlib();
+        assert_eq!(interpreter.run_block(ast.clone()).unwrap().to_string(), "1");
+        assert_eq!(interpreter.tests.borrow()[0].function_name(), Some("checks"));
+    }
+
+    #[test]
+    fn it_dispatches_operators_on_structs_to_their_methods() {
+        let source = "struct Money { cents }
+impl Money {
+    fn add(self, other) { Money(self.cents + other.cents) }
+    fn mul(self, k) { Money(cents: self.cents * k) }
+    fn cmp(self, other) { self.cents - other.cents }
+    fn index(self, i) { [self.cents][i] }
+}
+let a = Money(150)
+let b = Money(25)
+";
+
+        assert_eq!(output(&format!("{}a + b * 2", source)), "Money(cents: 200)");
+        assert_eq!(output(&format!("{}[a < b, a >= b, a == Money(150), a != b, a[0]]", source)), "[false, true, true, true, 150]");
+        assert_eq!(output("[[1, \"a\"] == [1, \"a\"], \"abc\"[2], 7 % 4]"), "[true, c, 3]");
+
+        let error = evaluate(&format!("{}a - b", source)).unwrap_err();
+        assert_eq!(error.to_string(), "Cannot apply - to Money and Money: Money has no sub method.");
+        assert!(matches!(evaluate("[1] + 2"), Err(RuntimeError::UnsupportedOperator { hook: "add", .. })));
+        assert!(matches!(evaluate("[1][1]"), Err(RuntimeError::IndexOutOfRange { length: 1, .. })));
+        assert!(matches!(evaluate("struct P { x }\nP(1, 2)"), Err(RuntimeError::ArityMismatch { given: 2, .. })));
+    }
+
+    #[test]
+    fn it_dispatches_trait_methods_and_checks_impls_when_they_are_declared() {
+        let source = "trait Shape {
+    fn area(self)
+    fn describe(self) { [self.area(), \"units\"] }
+}
+struct Square { side }
+struct Circle { r }
+impl Shape for Square {
+    fn area(self) { self.side * self.side }
+}
+";
+
+        assert_eq!(output(&format!("{}Square(3).describe()", source)), "[9, units]");
+        assert_eq!(output(&format!("{}[Square(1) implements Shape, Circle(1) implements Shape, 1 implements Shape]", source)), "[true, false, false]");
+
+        let error = evaluate(&format!("{}impl Shape for Circle {{}}", source)).unwrap_err();
+        assert_eq!(error.to_string(), "impl Shape for Circle is missing area.");
+        assert!(matches!(
+            evaluate(&format!("{}impl Shape for Circle {{\n    fn area(self) {{ 1 }}\n    fn volume(self) {{ 1 }}\n}}", source)),
+            Err(RuntimeError::UnknownTraitMethod { method, .. }) if method == "volume"
+        ));
+        assert!(matches!(evaluate(&format!("{}impl Square for Circle {{}}", source)), Err(RuntimeError::NotATrait(name)) if name == "Square"));
+    }
+
+    #[test]
+    fn it_iterates_ranges_lazily_in_either_direction() {
+        assert_eq!(output("collect(1..5)"), "[1, 2, 3, 4]");
+        assert_eq!(output("collect(1..=5)"), "[1, 2, 3, 4, 5]");
+        assert_eq!(output("collect((0..10).step(3))"), "[0, 3, 6, 9]");
+        assert_eq!(output("collect((10..0).step(0 - 4))"), "[10, 6, 2]");
+        assert_eq!(output("collect((1..=9).step(2).reverse())"), "[9, 7, 5, 3, 1]");
+        assert_eq!(output("collect(5..1)"), "[]");
+        assert_eq!(output("fn* down() {\n    for i in (1..4).reverse() { yield i }\n}\ncollect(down())"), "[3, 2, 1]");
+        assert_eq!(output("collect(take(map(0..1000000000000, |x| x * 2), 3))"), "[0, 2, 4]");
+        assert_eq!(output("[1..5, (0..1).step(0.5), (1..4).reverse()]"), "[1..5, (0..1).step(0.5), (3..=1).step(-1)]");
+        assert_eq!(output("(1..10) == (1..=9)"), "true");
+        assert!(matches!(evaluate("(1..2).step(0)"), Err(RuntimeError::Thrown(Value::Error { kind, .. })) if kind == "ValueError"));
+    }
+
+    #[test]
+    fn it_checks_membership_and_slices_with_ranges() {
+        assert_eq!(output("[3 in 1..5, 5 in 1..5, 5 in 1..=5, 2.5 in 1..5, 4 in (0..10).step(2), \"a\" in 1..5]"), "[true, false, true, false, true, false]");
+        assert_eq!(output("[2 in [1, 2], 3 in [1, 2], \"ell\" in \"hello\"]"), "[true, false, true]");
+        assert_eq!(output("let xs = [10, 20, 30, 40]\n[xs[1..3], xs[(0..4).step(2)], xs[(0..4).reverse()], xs[2..2]]"), "[[20, 30], [10, 30], [40, 30, 20, 10], []]");
+        assert_eq!(output("\"hello\"[1..=3]"), "ell");
+        assert!(matches!(evaluate("[1, 2][1..3]"), Err(RuntimeError::IndexOutOfRange { length: 2, .. })));
+        assert_eq!(output("struct Evens {}\nimpl Evens {\n    fn contains(self, n) { n % 2 == 0 }\n}\n[4 in Evens(), 3 in Evens()]"), "[true, false]");
+        assert_eq!(evaluate("1 in 5").unwrap_err().to_string(), "Cannot apply in to Number and Number: Number has no contains method.");
+    }
+
+    #[test]
+    fn it_compares_functions_by_identity() {
+        let source = "fn f() {}\nfn g() {}\nlet h = |x| x\nlet k = |x| x + 1\n";
+        assert_eq!(output(&format!("{}[f == f, f == g, h == h, h == k, f == h]", source)), "[true, false, true, false, false]");
+        assert_eq!(output("[println == println, println == len]"), "[true, false]");
+    }
+
+    #[test]
+    fn it_gives_nil_instead_of_panicking_on_missing_values() {
+        assert_eq!(output("let x\nx"), "nil");
+        assert_eq!(output("fn nothing() { let y = 1 }\n[nothing(), println(\"\"), nil]"), "[nil, nil, nil]");
+        assert_eq!(output("[nil == nil, nil == 0, nil != false]"), "[true, false, true]");
+        assert!(matches!(evaluate("nil.x"), Err(RuntimeError::UnknownMember { value: Value::Nil, .. })));
+    }
+
+    #[test]
+    fn it_short_circuits_optional_chains_and_coalescing() {
+        let source = "struct User { name, friend }
+impl User {
+    fn greet(self) { \"hi \" }
+}
+let bob = User(\"bob\", nil)
+let alice = User(\"alice\", bob)
+";
+
+        assert_eq!(output(&format!("{}[bob.friend?.name, alice.friend?.name, bob.friend?.greet(), alice.friend?.greet()]", source)), "[nil, bob, nil, hi ]");
+        assert_eq!(output("let f = nil\nlet g = |x| x\n[f?.(1), g?.(1), nil?.[0], [1, 2]?.[1]]"), "[nil, 1, nil, 2]");
+        assert_eq!(output("[nil ?? 1, 2 ?? 3, false ?? 3, nil ?? nil]"), "[1, 2, false, nil]");
+        // Nothing after a nil receiver is evaluated.
+        assert_eq!(output("nil?.[missing]"), "nil");
+        assert_eq!(output("nil?.(missing)"), "nil");
+        assert_eq!(output("nil?.f(missing)"), "nil");
+        assert_eq!(output("1 ?? missing"), "1");
+        assert_eq!(output("let user = nil\nuser?.name"), "nil");
+        assert_eq!(output("let xs = [1, 2]\nxs?.len()"), "2");
+    }
+
+    #[test]
+    fn it_builds_lists_and_maps_from_comprehensions() {
+        assert_eq!(output("let xs = [3, 0 - 1, 4]\n[x * 2 for x in xs if x > 0]"), "[6, 8]");
+        assert_eq!(output("[[x, y] for x in 1..=3 for y in x..=3 if x != y]"), "[[1, 2], [1, 3], [2, 3]]");
+        assert_eq!(output("{k: v for (k, v) in [[\"a\", 1], [\"b\", 2], [\"a\", 3]]}"), "{a: 3, b: 2}");
+
+        let map = "let m = {x: x * x for x in 1..4}\n";
+        assert_eq!(output(&format!("{}[m[2], m[5], 3 in m, 4 in m]", map)), "[4, nil, true, false]");
+        assert_eq!(output(&format!("{}[k + v for (k, v) in m]", map)), "[2, 6, 12]");
+        assert_eq!(output(&format!("{}[m == {{x: x * x for x in [3, 2, 1]}}, m == {{x: x for x in 1..4}}]", map)), "[true, false]");
+        assert!(matches!(evaluate("[a for (a, b) in [[1, 2, 3]]]"), Err(RuntimeError::Thrown(_))));
+    }
+
+    #[test]
+    fn it_keeps_comprehension_bindings_out_of_the_enclosing_scope() {
+        assert_eq!(output("let x = \"outer\"\nlet ys = [x for x in 1..3]\n[x, ys]"), "[outer, [1, 2]]");
+        assert_eq!(output("let y = 0\nlet ys = [y for y in 1..3]\nlet f = || y\n[f(), ys]"), "[0, [1, 2]]");
+    }
+
+    #[test]
+    fn it_runs_deferred_values_last_first_however_a_block_exits() {
+        let root = TempDir::new("defer", &[("log.txt", "")]);
+        let log = root.join("log.txt");
+        let source = format!("let log = open({:?}, \"w\")
+fn finish() {{
+    defer log.write(\"1\")
+    defer log.write(\"2\")
+    log.write(\"finished \")
+}}
+fn throw_early() {{
+    defer log.write(\" thrown\")
+    throw error(\"boom\")
+    log.write(\"unreachable\")
+}}
+fn propagate_early() {{
+    defer log.write(\" propagated\")
+    error(\"stop\")?
+    log.write(\"unreachable\")
+}}
+finish()
+try {{ throw_early() }} catch {{}}
+propagate_early()
+for i in [\" a\", \" b\"] {{
+    defer log.write(i)
+}}
+log.close()
+read_file({:?})", log.to_str().unwrap(), log.to_str().unwrap());
+
+        assert_eq!(output(&source), "finished 21 thrown propagated a b");
+        assert_eq!(output("fn boom() { throw error(\"cleanup\") }\nfn f() {\n    defer boom()\n    1\n}\ntry { f() } catch e { e }"), "Error: cleanup");
+    }
+
+    #[test]
+    fn it_returns_early_from_functions_and_breaks_out_of_loops() {
+        assert_eq!(output("fn first(xs) {\n    for x in xs {\n        for y in xs { if y > x { return [x, y] } }\n    }\n}\nfirst([2, 1, 3])"), "[2, 3]");
+        assert_eq!(output("fn f() {\n    return\n    1\n}\nf()"), "nil");
+        assert_eq!(output("fn f() { try { return 1 } catch e { 2 }\n3 }\nf()"), "1");
+        assert_eq!(output("fn f() {\n    for x in [1, 2, 3] {\n        try { if x == 2 { break } } catch e { return \"caught\" }\n        if x == 3 { return \"ran on\" }\n    }\n    \"broke\"\n}\nf()"), "broke");
+        assert_eq!(output("fn* numbers() {\n    yield 1\n    return\n    yield 2\n}\ncollect(numbers())"), "[1]");
+    }
+
+    #[test]
+    fn it_loops_while_a_condition_holds_in_a_scope_of_its_own() {
+        assert_eq!(output("while now() < 3 { await sleep(1) }\nnow()"), "3");
+        assert_eq!(output("let x = 1\nwhile true {\n    let x = 2\n    break\n}\nwhile nil { x }\nx"), "1");
+        assert_eq!(output("fn f() { while true { return 1 } }\nf()"), "1");
+        assert!(matches!(evaluate("while true {\n    let y = 1\n    break\n}\ny"), Err(RuntimeError::UndefinedVariable(name)) if name == "y"));
+    }
+
+    #[test]
+    fn it_runs_deferred_values_and_closes_resources_on_return_and_break() {
+        let root = TempDir::new("jumps", &[("log.txt", "")]);
+        let log = root.join("log.txt");
+        let source = format!("let log = open({:?}, \"w\")
+fn first(xs) {{
+    defer log.write(\" deferred \")
+    for x in xs {{
+        if x > 1 {{ return x }}
+    }}
+}}
+log.write(first([1, 2, 3]))
+for i in [1, 2, 3] {{
+    defer log.write(i)
+    if i == 2 {{ break }}
+}}
+struct Lock {{ name }}
+impl Lock {{
+    fn close(self) {{ log.write(\" closed \" + self.name + \" \") }}
+}}
+fn locked() {{
+    with Lock(\"a\") {{ return 1 }}
+    2
+}}
+log.write(locked())
+for i in [1, 2] {{
+    with Lock(\"b\") {{ break }}
+}}
+log.close()
+read_file({:?})", log.to_str().unwrap(), log.to_str().unwrap());
+
+        assert_eq!(output(&source), " deferred 212 closed a 1 closed b ");
+    }
+
+    #[test]
+    fn it_closes_resources_used_with_with() {
+        let root = TempDir::new("with", &[("data.txt", "")]);
+        let data = root.join("data.txt");
+        let path = format!("{:?}", data.to_str().unwrap());
+
+        assert_eq!(output(&format!("with open({}, \"w\") as f {{ f.write(\"a\") }}\nwith open({}, \"a\") as f {{ f.write([1]) }}\nread_file({})", path, path, path)), "a[1]");
+        assert_eq!(output(&format!("with open({}) as f {{ f.read() }}", path)), "a[1]");
+        assert_eq!(output(&format!("let f = open({})\nwith f {{}}\ntry {{ f.read() }} catch e {{ e }}", path)), format!("ValueError: {} is closed", data.to_str().unwrap()));
+
+        let lock = "struct Lock { name }\nimpl Lock {\n    fn close(self) { throw error(self.name) }\n}\n";
+        // A failing close is reported, unless the body already failed.
+        assert_eq!(output(&format!("{}try {{ with Lock(\"closed\") as l {{ l.name }} }} catch e {{ e }}", lock)), "Error: closed");
+        assert_eq!(output(&format!("{}try {{ with Lock(\"closed\") {{ throw error(\"body\") }} }} catch e {{ e }}", lock)), "Error: body");
+        assert!(matches!(evaluate("with 1 {}"), Err(RuntimeError::Thrown(Value::Error { kind, .. })) if kind == "TypeError"));
+        assert!(matches!(evaluate("open(\"missing/file.txt\")"), Err(RuntimeError::Thrown(Value::Error { kind, .. })) if kind == "IOError"));
+    }
+}
@@ -0,0 +1,147 @@
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+use std::vec::IntoIter;
+use corosensei::CoroutineResult;
+
+use crate::environment::Value;
+use crate::interpreter::{Interpreter, Routine, RuntimeError};
+
+/// The state behind a lazy `Value::Iterator`.
+pub enum Sequence {
+    /// A generator body running on its own stack, suspended at each `yield`.
+    Generator(Box<Routine>),
+    Items(IntoIter<Value>),
+    Map(Rc<RefCell<Sequence>>, Value),
+    Take(Rc<RefCell<Sequence>>, usize),
+    /// A range, along with the position of the next number it produces.
+    Range(Range, usize),
+}
+
+/// The numbers from `start` towards `end`, `step` apart. Numbers are worked
+/// out as they're needed, so a range never holds a list of them.
+#[derive(Debug, Clone, Copy)]
+pub struct Range {
+    pub start: f64,
+    pub end: f64,
+    pub step: f64,
+    pub inclusive: bool,
+}
+
+impl Range {
+    pub fn new(start: f64, end: f64, inclusive: bool) -> Self {
+        Self { start, end, step: 1.0, inclusive }
+    }
+
+    /// How many numbers the range produces. A step pointing away from the end gives none.
+    pub fn len(&self) -> usize {
+        let steps = (self.end - self.start) / self.step;
+        match (steps >= 0.0, self.inclusive) {
+            (false, _) => 0,
+            (true, true) => steps.floor() as usize + 1,
+            (true, false) => steps.ceil() as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: usize) -> Option<f64> {
+        (i < self.len()).then_some(self.start + i as f64 * self.step)
+    }
+
+    pub fn numbers(self) -> impl Iterator<Item = f64> {
+        (0..self.len()).map(move |i| self.start + i as f64 * self.step)
+    }
+
+    pub fn contains(&self, n: f64) -> bool {
+        let i = (n - self.start) / self.step;
+        i >= 0.0 && i.fract() == 0.0 && (i as usize) < self.len()
+    }
+
+    /// The same numbers, from last to first.
+    pub fn reverse(&self) -> Self {
+        if self.is_empty() {
+            return *self
+        }
+        let last = self.start + (self.len() - 1) as f64 * self.step;
+        Self { start: last, end: self.start, step: -self.step, inclusive: true }
+    }
+}
+
+/// Ranges are equal when they produce the same numbers.
+impl PartialEq for Range {
+    fn eq(&self, other: &Self) -> bool {
+        let len = self.len();
+        len == other.len() && (len == 0 || self.start == other.start) && (len < 2 || self.step == other.step)
+    }
+}
+
+/// Writes a range the way it would be written in source.
+impl Display for Range {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let range = format!("{}{}{}", self.start, if self.inclusive { "..=" } else { ".." }, self.end);
+        if self.step == 1.0 {
+            write!(f, "{}", range)
+        } else {
+            write!(f, "({}).step({})", range, self.step)
+        }
+    }
+}
+
+impl Sequence {
+    fn next(&mut self, interpreter: &mut Interpreter) -> Result<Option<Value>, RuntimeError> {
+        match self {
+            Sequence::Generator(generator) => {
+                if generator.done() {
+                    return Ok(None)
+                }
+
+                match generator.resume(()) {
+                    CoroutineResult::Yield(value) => Ok(Some(value)),
+                    CoroutineResult::Return(result) => result.map(|_| None),
+                }
+            },
+            Sequence::Items(items) => Ok(items.next()),
+            Sequence::Map(source, callback) => match advance(source, interpreter)? {
+                Some(value) => interpreter.call(callback.clone(), vec![value], Vec::new()).map(Some),
+                None => Ok(None),
+            },
+            Sequence::Take(source, remaining) => {
+                if *remaining == 0 {
+                    return Ok(None)
+                }
+
+                *remaining -= 1;
+                advance(source, interpreter)
+            },
+            Sequence::Range(range, next) => {
+                let value = range.get(*next).map(Value::Number);
+                *next += 1;
+                Ok(value)
+            },
+        }
+    }
+}
+
+/// Produces the next value of a sequence, or `None` once it is exhausted.
+pub fn advance(sequence: &Rc<RefCell<Sequence>>, interpreter: &mut Interpreter) -> Result<Option<Value>, RuntimeError> {
+    let mut sequence = sequence.try_borrow_mut()
+        .map_err(|_| interpreter.raise("TypeError", "Iterator is already running"))?;
+    sequence.next(interpreter)
+}
+
+/// Turns anything that can be looped over into a sequence.
+pub fn iterate(value: Value, interpreter: &Interpreter) -> Result<Rc<RefCell<Sequence>>, RuntimeError> {
+    match value {
+        Value::Iterator(sequence) => Ok(sequence),
+        Value::List(items) => Ok(Rc::new(RefCell::new(Sequence::Items(items.into_iter())))),
+        Value::Range(range) => Ok(Rc::new(RefCell::new(Sequence::Range(range, 0)))),
+        Value::Map(entries) => {
+            let pairs: Vec<Value> = entries.into_iter().map(|(key, value)| Value::List(vec![key, value])).collect();
+            Ok(Rc::new(RefCell::new(Sequence::Items(pairs.into_iter()))))
+        },
+        value => Err(interpreter.raise("TypeError", format!("{:?} is not iterable", value))),
+    }
+}
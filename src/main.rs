@@ -1,16 +1,219 @@
 use std::env::args;
 use std::fs::read_to_string;
+use std::io::{stdin, Read};
+use std::path::Path;
+
+use ast::Spanned;
+use serialize::Format;
 
 mod token;
 mod parser;
 mod ast;
+mod expander;
+mod resolver;
+mod checker;
 mod interpreter;
+mod iterator;
 mod environment;
+mod event_loop;
+mod module;
+mod stdlib;
+mod serialize;
+mod doc;
 
 fn main() {
-   let file = args().nth(1).unwrap();
-   let contents = read_to_string(file).unwrap();
-   let tokens = token::generate(contents.as_str());
-   let ast = parser::parse(tokens).unwrap();
-   interpreter::interpret(ast);
-}
\ No newline at end of file
+   let Options { check_only, test, doc, output, expand_only, emit, format, file, code, script_args } =
+      options(args().skip(1)).unwrap_or_else(|message| fail(&message));
+
+   if doc {
+      let file = file.unwrap_or_else(|| fail("No directory given."));
+      let pages = doc::collect(Path::new(&file)).unwrap_or_else(|error| fail(&error.to_string()));
+      if test {
+         if !doc::test(&pages, Path::new(&file)) {
+            std::process::exit(1);
+         }
+         return;
+      }
+      let output = output.unwrap_or_else(|| "docs".to_string());
+      if let Err(error) = doc::write(&pages, Path::new(&output)) {
+         fail(&error.to_string());
+      }
+      return;
+   }
+
+   // Code given with `-e`, and scripts read from stdin with `-`, are run as if from the current directory.
+   let (file, contents) = match (code, file) {
+      (Some(_), Some(_)) => fail("-e can't be used with a script."),
+      (Some(code), None) => ("<eval>".to_string(), code),
+      (None, Some(file)) if file == "-" => {
+         let mut contents = String::new();
+         stdin().read_to_string(&mut contents).unwrap_or_else(|error| fail(&error.to_string()));
+         ("<stdin>".to_string(), contents)
+      },
+      (None, Some(file)) => {
+         let contents = read_to_string(&file).unwrap_or_else(|error| fail(&format!("{}: {}", file, error)));
+         (file, contents)
+      },
+      (None, None) => fail("No script given."),
+   };
+   // A `.json` file holds an AST written by `--emit ast`, or by another tool.
+   let ast = if file.ends_with(".json") {
+      serialize::from_json(&contents).unwrap_or_else(|error| fail(&error.to_string()))
+   } else {
+      let tokens = token::generate(contents.as_str()).into_iter().collect::<Result<Vec<_>, _>>().unwrap_or_else(|span| {
+         fail(&format!("{}:{}: Unrecognised token {:?}.", file, span, &contents[span.start..span.end]))
+      });
+      if emit == Some(Emit::Tokens) {
+         let tokens: Vec<_> = tokens.into_iter().map(|(token, span)| Spanned::new(token, span)).collect();
+         println!("{}", serialize::tokens(&tokens, format));
+         return;
+      }
+      parser::parse(tokens).unwrap_or_else(|error| fail(&format!("{}:{}: {}", file, error.span, error.node)))
+   };
+   match emit {
+      Some(Emit::Ast) => {
+         println!("{}", serialize::program(&ast, format));
+         return;
+      },
+      Some(Emit::Tokens) => fail("Cannot emit tokens for an AST read from JSON."),
+      None => {},
+   }
+
+   let ast = match expander::expand(ast) {
+      Ok(ast) => ast,
+      Err(errors) => {
+         for error in &errors {
+            eprintln!("{}:{}: {}", file, error.span, error.node);
+         }
+         std::process::exit(1);
+      }
+   };
+   if expand_only {
+      print!("{}", ast::print::print(&ast));
+      return;
+   }
+
+   let ast = match resolver::resolve(ast) {
+      Ok(ast) => ast,
+      Err(errors) => {
+         for error in &errors {
+            eprintln!("{}:{}: {}", file, error.span, error.node);
+         }
+         std::process::exit(1);
+      }
+   };
+
+   let errors = checker::check(&ast);
+   for error in &errors {
+      eprintln!("{}:{}: {}", file, error.span, error.node);
+   }
+   if !errors.is_empty() {
+      std::process::exit(1);
+   }
+   if check_only {
+      return;
+   }
+
+   if test {
+      match interpreter::test(ast, Path::new(&file), script_args) {
+         Ok(true) => return,
+         Ok(false) => std::process::exit(1),
+         Err(error) => fail(&error.to_string()),
+      }
+   }
+
+   if let Err(error) = interpreter::interpret(ast, Path::new(&file), script_args) {
+      eprintln!("{}", error);
+      std::process::exit(1);
+   }
+}
+
+#[derive(Debug, Default)]
+struct Options {
+   check_only: bool,
+   test: bool,
+   doc: bool,
+   output: Option<String>,
+   expand_only: bool,
+   emit: Option<Emit>,
+   format: Format,
+   file: Option<String>,
+   code: Option<String>,
+   script_args: Vec<String>,
+}
+
+/// Reads the command line. Options stop at the script, or `-` for stdin, so
+/// everything after it is for the script, as is everything after `--`.
+fn options(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+   let mut options = Options::default();
+   let mut args = args.into_iter();
+   while let Some(arg) = args.next() {
+      match arg.as_str() {
+         "check" if options.file.is_none() => options.check_only = true,
+         "test" if options.file.is_none() => options.test = true,
+         "doc" if options.file.is_none() => options.doc = true,
+         "--test" if options.doc => options.test = true,
+         "-o" | "--output" if options.doc => options.output = Some(args.next().ok_or("-o takes a directory.")?),
+         "--expand" => options.expand_only = true,
+         "--emit" => options.emit = match args.next().as_deref() {
+            Some("tokens") => Some(Emit::Tokens),
+            Some("ast") => Some(Emit::Ast),
+            _ => return Err("--emit takes tokens or ast.".to_string()),
+         },
+         "--format" => options.format = args.next().ok_or("--format takes json or sexp.")?.parse()?,
+         "-e" => options.code = Some(args.next().ok_or("-e takes the code to run.")?),
+         "--" => options.script_args.extend(args.by_ref()),
+         // `doc` takes a directory rather than a script, and its options can follow it.
+         _ if options.doc => options.file = Some(arg),
+         _ => {
+            options.file = Some(arg);
+            options.script_args.extend(args.by_ref());
+         },
+      }
+   }
+   Ok(options)
+}
+
+#[derive(Debug, PartialEq)]
+enum Emit {
+   Tokens,
+   Ast,
+}
+
+fn fail(message: &str) -> ! {
+   eprintln!("{}", message);
+   std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn parse(args: &str) -> Options {
+      options(args.split_whitespace().map(str::to_string)).unwrap()
+   }
+
+   #[test]
+   fn it_passes_everything_after_the_script_to_it() {
+      let options = parse("--expand s.crs foo --emit bar");
+      assert_eq!(options.file.as_deref(), Some("s.crs"));
+      assert_eq!(options.script_args, ["foo", "--emit", "bar"]);
+      assert!(options.expand_only && options.emit.is_none());
+
+      let options = parse("check - a");
+      assert!(options.check_only);
+      assert_eq!((options.file.as_deref(), options.script_args), (Some("-"), vec!["a".to_string()]));
+
+      let options = parse("-e 1 -- a b");
+      assert_eq!((options.code.as_deref(), options.file, options.script_args), (Some("1"), None, vec!["a".to_string(), "b".to_string()]));
+      assert_eq!(parse("s.crs -- a").script_args, ["--", "a"]);
+   }
+
+   #[test]
+   fn it_reads_doc_options_after_the_directory() {
+      let options = parse("doc src -o out --test");
+      assert!(options.doc && options.test);
+      assert_eq!((options.file.as_deref(), options.output.as_deref()), (Some("src"), Some("out")));
+      assert!(options.script_args.is_empty());
+   }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use crate::ast::Program;
+use crate::environment::Value;
+use crate::interpreter::RuntimeError;
+
+pub const EXTENSION: &str = "crs";
+
+/// Finds, parses, checks and caches modules. Paths starting with `./` or `../` are
+/// resolved against the importing file, anything else against the search path.
+#[derive(Debug, Clone, Default)]
+pub struct ModuleLoader {
+    search_path: Vec<PathBuf>,
+    cache: HashMap<PathBuf, Value>,
+    loading: Vec<PathBuf>,
+}
+
+impl ModuleLoader {
+    pub fn new(search_path: Vec<PathBuf>) -> Self {
+        Self {
+            search_path,
+            ..Self::default()
+        }
+    }
+
+    /// The search path for a script: its own directory, followed by the
+    /// directories listed in `CRUSTACEAN_PATH`.
+    pub fn for_script(script: &Path) -> Self {
+        let mut search_path = vec![directory_of(script)];
+        if let Some(paths) = env::var_os("CRUSTACEAN_PATH") {
+            search_path.extend(env::split_paths(&paths));
+        }
+        Self::new(search_path)
+    }
+
+    pub fn resolve(&self, path: &str, importer: &Path) -> Result<PathBuf, RuntimeError> {
+        let mut file = PathBuf::from(path);
+        if file.extension().is_none() {
+            file.set_extension(EXTENSION);
+        }
+
+        let candidates: Vec<PathBuf> = if path.starts_with("./") || path.starts_with("../") {
+            vec![directory_of(importer).join(&file)]
+        } else {
+            self.search_path.iter().map(|dir| dir.join(&file)).collect()
+        };
+
+        candidates.iter()
+            .find(|candidate| candidate.is_file())
+            .map(|found| found.canonicalize().unwrap_or_else(|_| found.clone()))
+            .ok_or_else(|| RuntimeError::ModuleNotFound {
+                path: path.to_string(),
+                searched: candidates.iter().map(|c| c.display().to_string()).collect::<Vec<_>>().join(", "),
+            })
+    }
+
+    pub fn cached(&self, path: &Path) -> Option<Value> {
+        self.cache.get(path).cloned()
+    }
+
+    /// Marks a module as being evaluated, failing if that would close an import cycle.
+    pub fn enter(&mut self, path: &Path) -> Result<(), RuntimeError> {
+        if let Some(start) = self.loading.iter().position(|p| p == path) {
+            let chain = self.loading[start..].iter()
+                .chain(std::iter::once(&path.to_path_buf()))
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(RuntimeError::ImportCycle(chain))
+        }
+
+        self.loading.push(path.to_path_buf());
+        Ok(())
+    }
+
+    pub fn leave(&mut self, path: &Path, module: Option<Value>) {
+        self.loading.retain(|p| p != path);
+        if let Some(module) = module {
+            self.cache.insert(path.to_path_buf(), module);
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Program, RuntimeError> {
+        let invalid = |message: String| RuntimeError::InvalidModule { path: path.display().to_string(), message };
+        let contents = read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let tokens = crate::token::generate(contents.as_str())
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|span| invalid(format!("{}: Unrecognised token {:?}.", span, &contents[span.start..span.end])))?;
+
+        let program = crate::parser::parse(tokens).map_err(|e| invalid(format!("{}: {}", e.span, e.node)))?;
+        let program = crate::expander::expand(program)
+            .map_err(|errors| invalid(errors.iter().map(|e| format!("{}: {}", e.span, e.node)).collect::<Vec<_>>().join(" ")))?;
+        let program = crate::resolver::resolve(program)
+            .map_err(|errors| invalid(errors.iter().map(|e| format!("{}: {}", e.span, e.node)).collect::<Vec<_>>().join(" ")))?;
+        let errors = crate::checker::check(&program);
+        if !errors.is_empty() {
+            return Err(invalid(errors.iter().map(|e| format!("{}: {}", e.span, e.node)).collect::<Vec<_>>().join(" ")))
+        }
+        Ok(program)
+    }
+}
+
+fn directory_of(file: &Path) -> PathBuf {
+    match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::ops::Deref;
+
+    use super::*;
+
+    /// A directory of files for a test to load, removed when the test ends.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let root = env::temp_dir().join(format!("crustacean-{}-{}", name, std::process::id()));
+            for (file, contents) in files {
+                let file = root.join(file);
+                std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+                std::fs::write(file, contents).unwrap();
+            }
+            std::fs::create_dir_all(&root).unwrap();
+            TempDir(root)
+        }
+    }
+
+    impl Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn it_type_checks_modules_as_it_loads_them() {
+        let root = TempDir::new("checked", &[("a.crs", "fn double(x: Number) { x * 2 }\nexport let two = double(\"one\")")]);
+
+        let error = ModuleLoader::load(&root.join("a.crs")).unwrap_err();
+        assert!(matches!(&error, RuntimeError::InvalidModule { message, .. } if message.starts_with("2:")), "{}", error);
+    }
+
+    #[test]
+    fn it_removes_temporary_directories_when_dropped() {
+        let root = TempDir::new("dropped", &[("a.crs", "")]);
+        let path = root.to_path_buf();
+
+        drop(root);
+        assert!(!path.exists());
+    }
+}
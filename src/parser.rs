@@ -1,16 +1,42 @@
+use std::collections::HashMap;
+use std::slice::Iter;
+use thiserror::Error;
+
 use crate::ast::*;
 use crate::token::Token;
 
-pub fn parse(tokens: Vec<Token>) -> Result<Program, ParseError> {
-    let mut parser = Parser::new(tokens.iter());
+/// Parses tokens along with the spans they were read from. Every node spans
+/// from its first token to its last, and an error spans the token it stopped at.
+pub fn parse(tokens: Vec<(Token, Span)>) -> Result<Program, Spanned<ParseError>> {
+    // Doc comments aren't part of the grammar: each run of them is set aside
+    // for the declaration starting at the token after it.
+    let mut docs = HashMap::new();
+    let mut lines = Vec::new();
+    let tokens: Vec<_> = tokens.into_iter().filter(|(token, span)| match token {
+        Token::DocComment(line) => {
+            lines.push(line.clone());
+            false
+        },
+        _ => {
+            if !lines.is_empty() {
+                docs.insert(span.start, std::mem::take(&mut lines).join("\n"));
+            }
+            true
+        },
+    }).collect();
+    let mut parser = Parser::new(tokens.iter(), docs);
 
     parser.read();
     parser.read();
 
     let mut program: Program = Vec::new();
 
-    while let Some(statement) = parser.next()? {
-        program.push(statement);
+    loop {
+        match parser.next() {
+            Ok(Some(statement)) => program.push(statement),
+            Ok(None) => break,
+            Err(error) => return Err(Spanned::new(error, parser.current_span)),
+        }
     }
 
     Ok(program)
@@ -19,6 +45,10 @@ pub fn parse(tokens: Vec<Token>) -> Result<Program, ParseError> {
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 enum Precedence {
     Lowest,
+    Pipe,
+    Coalesce,
+    Comparison,
+    Range,
     Sum,
     Product,
     Prefix,
@@ -28,40 +58,119 @@ enum Precedence {
 impl Precedence {
     fn token(token: Token) -> Self {
         match token {
-            Token::Asterisk | Token::Slash => Self::Product,
+            Token::Asterisk | Token::Slash | Token::Percent => Self::Product,
             Token::Plus | Token::Minus => Self::Sum,
-            Token::LeftParen => Self::Call,
+            Token::Pipe => Self::Pipe,
+            Token::Equal | Token::NotEqual | Token::LessThan | Token::LessEqual | Token::GreaterThan | Token::GreaterEqual
+            | Token::Implements | Token::In => Self::Comparison,
+            Token::DotDot | Token::DotDotEqual => Self::Range,
+            Token::QuestionQuestion => Self::Coalesce,
+            Token::LeftParen | Token::LeftBracket | Token::Dot | Token::Question | Token::QuestionDot => Self::Call,
             _ => Self::Lowest,
         }
     }
 }
 
 struct Parser<'p> {
-    tokens: Iter<'p, Token>,
+    tokens: Iter<'p, (Token, Span)>,
     current: Token,
     peek: Token,
+    current_span: Span,
+    peek_span: Span,
+    /// The span of the last token read past, where the node being parsed ends.
+    previous_span: Span,
+    function: Option<FunctionKind>,
+    /// Whether `break` would leave a loop of the function being parsed.
+    looping: bool,
+    /// Doc comments, by where the token they were written before starts.
+    docs: HashMap<usize, String>,
 }
 
 impl<'p> Parser<'p> {
-    fn new(tokens: Iter<'p, Token>) -> Self {
+    fn new(tokens: Iter<'p, (Token, Span)>, docs: HashMap<usize, String>) -> Self {
         Self {
             current: Token::Eof,
             peek: Token::Eof,
-            tokens: tokens,
+            current_span: Span::default(),
+            peek_span: Span::default(),
+            previous_span: Span::default(),
+            tokens,
+            function: None,
+            looping: false,
+            docs,
+        }
+    }
+
+    /// The doc comment written before the current token.
+    fn doc(&mut self) -> Option<String> {
+        self.docs.remove(&self.current_span.start)
+    }
+
+    /// Leaves the doc comment written before the current token for the token
+    /// `skip` stops at, like a declaration after its `export` or attributes.
+    fn doc_after<T>(&mut self, skip: impl FnOnce(&mut Self) -> Result<T, ParseError>) -> Result<T, ParseError> {
+        let doc = self.doc();
+        let result = skip(self)?;
+        if let Some(doc) = doc {
+            self.docs.entry(self.current_span.start).or_insert(doc);
         }
+        Ok(result)
     }
 
-    fn parse_statement(&mut self) -> Result<Statement, ParseError> {
+    /// Runs `parse` and wraps what it parsed in the span of the tokens it read.
+    fn spanned<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, ParseError>) -> Result<Spanned<T>, ParseError> {
+        let start = self.current_span;
+        let node = parse(self)?;
+        Ok(Spanned::new(node, start.to(self.previous_span)))
+    }
+
+    fn parse_statement(&mut self) -> Result<Spanned<Statement>, ParseError> {
+        self.spanned(|parser| parser.parse_statement_kind())
+    }
+
+    fn parse_statement_kind(&mut self) -> Result<Statement, ParseError> {
         match self.current {
-            Token::Fn => self.parse_fn(true),
+            Token::Fn | Token::Async => self.parse_fn(true),
+            Token::At => self.parse_decorated_fn(),
             Token::Let => self.parse_let(),
+            Token::Const => self.parse_const(),
             Token::If => self.parse_if(),
+            Token::Import => self.parse_import(),
+            Token::Export => self.parse_export(),
+            Token::For => self.parse_for(),
+            Token::While => self.parse_while(),
+            Token::Yield => self.parse_yield(),
+            Token::Return => self.parse_return(),
+            Token::Break => self.parse_break(),
+            Token::Throw => self.parse_throw(),
+            Token::Try => self.parse_try(),
+            Token::Defer => self.parse_defer(),
+            Token::With => self.parse_with(),
+            Token::Macro => self.parse_macro(),
+            Token::Struct => self.parse_struct(),
+            Token::Impl => self.parse_impl(),
+            Token::Trait => self.parse_trait(),
             _ => Ok(Statement::Expression{expression: self.parse_expression(Precedence::Lowest)?})
         }
     }
 
-    fn parse_expression(&mut self, precedence: Precedence) -> Result<Expression, ParseError> {
-        let mut left = match self.current.clone() {
+    fn parse_expression(&mut self, precedence: Precedence) -> Result<Spanned<Expression>, ParseError> {
+        let mut left = self.spanned(|parser| parser.parse_prefix_expression())?;
+        while !self.current_is(Token::Eof) && precedence < Precedence::token(self.current.clone()) {
+            if let Some(expression) = self.parse_postfix_expression(left.clone())? {
+                left = expression;
+            } else if let Some(expression) = self.parse_infix_expression(left.clone())? {
+                left = expression
+            } else {
+                break
+            }
+        }
+
+        Ok(left)
+    }
+
+    fn parse_prefix_expression(&mut self) -> Result<Expression, ParseError> {
+        Ok(match self.current.clone() {
             Token::String(s) => {
                 self.expect_token_and_read(Token::String("".to_string()))?;
                 Expression::String(s.to_string())
@@ -83,39 +192,91 @@ impl<'p> Parser<'p> {
                 self.expect_token_and_read(Token::False)?;
                 Expression::Bool(false)
             },
+            Token::Nil => {
+                self.expect_token_and_read(Token::Nil)?;
+                Expression::Nil
+            },
+            Token::Identifier(s) if self.peek_is(Token::FatArrow) => {
+                let param = self.spanned(|parser| {
+                    parser.expect_identifier_and_read()?;
+                    Ok(Parameter::new(s))
+                })?;
+                self.expect_token_and_read(Token::FatArrow)?;
+                Expression::Closure(vec![param], self.parse_lambda_body()?)
+            },
+            Token::Identifier(s) if self.peek_is(Token::Bang) => {
+                self.expect_identifier_and_read()?;
+                self.expect_token_and_read(Token::Bang)?;
+                Expression::MacroCall(s, self.parse_token_tree(Token::LeftParen, Token::RightParen)?)
+            },
             Token::Identifier(s) => {
                 self.expect_identifier_and_read()?;
                 Expression::Identifier(s)
             },
+            Token::Bar => {
+                self.expect_token_and_read(Token::Bar)?;
+                let params = self.parse_parameters(Token::Bar)?;
+                Expression::Closure(params, self.parse_lambda_body()?)
+            },
+            Token::LeftParen => {
+                self.expect_token_and_read(Token::LeftParen)?;
+                let expression = self.parse_expression(Precedence::Lowest)?;
+                self.expect_token_and_read(Token::RightParen)?;
+                expression.node
+            },
+            Token::LeftBracket => {
+                self.expect_token_and_read(Token::LeftBracket)?;
+                let mut items = Vec::new();
+
+                while !self.current_is(Token::RightBracket) {
+                    items.push(self.parse_expression(Precedence::Lowest)?);
+
+                    if items.len() == 1 && self.current_is(Token::For) {
+                        let item = items.remove(0).boxed();
+                        return Ok(Expression::ListComprehension(item, self.parse_clauses(Token::RightBracket)?))
+                    }
+                    if self.current_is(Token::Comma) {
+                        self.read();
+                    }
+                }
+
+                self.expect_token_and_read(Token::RightBracket)?;
+                Expression::List(items)
+            },
+            Token::LeftBrace => {
+                self.expect_token_and_read(Token::LeftBrace)?;
+                let key = self.parse_expression(Precedence::Lowest)?;
+                self.expect_token_and_read(Token::Colon)?;
+                let value = self.parse_expression(Precedence::Lowest)?;
+                Expression::MapComprehension(key.boxed(), value.boxed(), self.parse_clauses(Token::RightBrace)?)
+            },
             Token::Fn => {
                 let (params, body) = match self.parse_fn(false)? {
+                    Statement::FunctionDeclaration{kind: FunctionKind::Generator, ..} => return Err(ParseError::AnonymousFunction),
                     Statement::FunctionDeclaration{params, body, ..} => (params, body),
                     _ => unreachable!()
                 };
                 Expression::Closure(params, body)
             },
+            Token::Await => {
+                self.expect_token_and_read(Token::Await)?;
+                // Top-level code may await too, which runs the event loop until the promise settles.
+                if self.function.is_some_and(|kind| kind != FunctionKind::Async) {
+                    return Err(ParseError::AwaitOutsideAsync)
+                }
+                Expression::Await(self.parse_expression(Precedence::Prefix)?.boxed())
+            },
             t @ Token::Minus | t @ Token::Bang => {
                 self.expect_token_and_read(t.clone())?;
                 Expression::Prefix(Op::token(t), self.parse_expression(Precedence::Prefix)?.boxed())
             },
-            _ => todo!("{:?}", self.current.clone())
-        };
-        while !self.current_is(Token::Eof) && precedence::token(self.current.clone()) {
-            if let Some(expression) = self.parse_postfix_expression(left.clone())? {
-                left = expression;
-            } else if let Some(expression) = self.parse_infix_expression(left.clone())? {
-                left = expression
-            } else {
-                break
-            }
-        }
-
-        Ok(left)
+            _ => return Err(ParseError::UnexpectedToken(self.current.clone())),
+        })
     }
 
     fn parse_block(&mut self) -> Result<Block, ParseError> {
         self.expect_token_and_read(Token::LeftBrace)?;
-        let mut block = Vec::New();
+        let mut block = Vec::new();
         while !self.current_is(Token::RightBrace) {
             block.push(self.parse_statement()?);
         }
@@ -123,68 +284,606 @@ impl<'p> Parser<'p> {
         Ok(block)
     }
 
-    fn parse_postfix_expression(&mut self, left: Expression) -> Result<Option<Expression>, ParseError> {
-        Ok(match self.current {
+    fn parse_postfix_expression(&mut self, left: Spanned<Expression>) -> Result<Option<Spanned<Expression>>, ParseError> {
+        let start = left.span;
+        let expression = match self.current {
             Token::LeftParen => {
-                self.expect_token_and_read(Token::LeftParen)?;
-                let mut args = Vec::new();
+                let args = self.parse_arguments()?;
+                Expression::Call(left.boxed(), args)
+            },
+            // A `[` starting a line begins a list rather than indexing the line before.
+            Token::LeftBracket if self.current_span.line == self.previous_span.line => {
+                self.expect_token_and_read(Token::LeftBracket)?;
+                let index = self.parse_expression(Precedence::Lowest)?;
+                self.expect_token_and_read(Token::RightBracket)?;
+                Expression::Index(left.boxed(), index.boxed())
+            },
+            Token::Dot => {
+                self.expect_token_and_read(Token::Dot)?;
+                let name: Identifier = self.expect_identifier_and_read()?.into();
+                Expression::Get(left.boxed(), name)
+            },
+            Token::Question => {
+                self.expect_token_and_read(Token::Question)?;
+                Expression::Propagate(left.boxed())
+            },
+            Token::QuestionDot => {
+                self.expect_token_and_read(Token::QuestionDot)?;
+                match self.current {
+                    Token::LeftParen => Expression::OptionalCall(left.boxed(), self.parse_arguments()?),
+                    Token::LeftBracket => {
+                        self.expect_token_and_read(Token::LeftBracket)?;
+                        let index = self.parse_expression(Precedence::Lowest)?;
+                        self.expect_token_and_read(Token::RightBracket)?;
+                        Expression::OptionalIndex(left.boxed(), index.boxed())
+                    },
+                    _ => Expression::OptionalGet(left.boxed(), self.expect_identifier_and_read()?.into()),
+                }
+            },
+            Token::Pipe => {
+                self.expect_token_and_read(Token::Pipe)?;
 
-                while !self.current_is(Token::RightParen) {
-                    args.push(self.parse_expression(Precedence::Lowest)?);
-                    
-                    if self.current_is(Token::Comma) {
-                        self.read();
-                    }
+                // `x |> f(a)` is sugar for `f(x, a)` and `x |> f` for `f(x)`.
+                let callable = self.parse_expression(Precedence::Pipe)?;
+                match callable.node {
+                    Expression::Call(callable, mut args) => {
+                        args.insert(0, Argument::Positional(left));
+                        Expression::Call(callable, args)
+                    },
+                    node => Expression::Call(Spanned::new(node, callable.span).boxed(), vec![Argument::Positional(left)]),
                 }
+            },
+            _ => return Ok(None)
+        };
 
-                self.expect_token_and_read(Token::RightParen)?;
-                Some(Expression::Call(Box::new(left), args))
+        Ok(Some(Spanned::new(expression, start.to(self.previous_span))))
+    }
+
+    fn parse_infix_expression(&mut self, left: Spanned<Expression>) -> Result<Option<Spanned<Expression>>, ParseError> {
+        Ok(match self.current {
+            Token::Plus | Token::Minus | Token::Asterisk | Token::Slash | Token::Percent
+            | Token::Equal | Token::NotEqual | Token::LessThan | Token::LessEqual | Token::GreaterThan | Token::GreaterEqual
+            | Token::Implements | Token::In | Token::QuestionQuestion => {
+                let token = self.current.clone();
+                let precedence = Precedence::token(token.clone());
+                self.expect_token_and_read(token.clone())?;
+                let right = self.parse_expression(precedence)?;
+                let span = left.span.to(right.span);
+                Some(Spanned::new(Expression::Infix(left.boxed(), Op::token(token), right.boxed()), span))
+            },
+            Token::DotDot | Token::DotDotEqual => {
+                let inclusive = self.current_is(Token::DotDotEqual);
+                self.read();
+                let end = self.parse_expression(Precedence::Range)?;
+                let span = left.span.to(end.span);
+                Some(Spanned::new(Expression::Range(left.boxed(), end.boxed(), inclusive), span))
             },
             _ => None
         })
     }
 
+    fn parse_arguments(&mut self) -> Result<Vec<Argument>, ParseError> {
+        self.expect_token_and_read(Token::LeftParen)?;
+        let mut args = Vec::new();
+
+        while !self.current_is(Token::RightParen) {
+            args.push(if self.current_is(Token::Ellipsis) {
+                self.expect_token_and_read(Token::Ellipsis)?;
+                Argument::Spread(self.parse_expression(Precedence::Lowest)?)
+            } else if self.current_is(Token::Identifier("".to_string())) && self.peek_is(Token::Colon) {
+                let name: Identifier = self.expect_identifier_and_read()?.into();
+                self.expect_token_and_read(Token::Colon)?;
+                Argument::Named(name, self.parse_expression(Precedence::Lowest)?)
+            } else {
+                Argument::Positional(self.parse_expression(Precedence::Lowest)?)
+            });
+
+            if self.current_is(Token::Comma) {
+                self.read();
+            }
+        }
+
+        self.expect_token_and_read(Token::RightParen)?;
+        Ok(args)
+    }
+
     fn parse_let(&mut self) -> Result<Statement, ParseError> {
         self.expect_token_and_read(Token::Let)?;
 
         let name: Identifier = self.expect_identifier_and_read()?.into();
-        let initial: Option<Expression> = if self.current_is(Token::Assign) {
+        let annotation = self.parse_annotation(true)?;
+        let initial = if self.current_is(Token::Assign) {
             self.expect_token_and_read(Token::Assign)?;
-            Some(self.parse_expression(Precedence::Lowest)?)
+            self.parse_expression(Precedence::Lowest)?.some()
         } else {
             None
         };
 
         Ok(Statement::LetDeclaration {
-            name: name,
-            initial: initial,
+            name,
+            annotation,
+            initial,
+        })
+    }
+
+    fn parse_const(&mut self) -> Result<Statement, ParseError> {
+        let doc = self.doc();
+        self.expect_token_and_read(Token::Const)?;
+
+        let name: Identifier = self.expect_identifier_and_read()?.into();
+        let annotation = self.parse_annotation(true)?;
+        self.expect_token_and_read(Token::Assign)?;
+        let value = self.parse_expression(Precedence::Lowest)?;
+
+        Ok(Statement::ConstDeclaration {
+            doc,
+            name,
+            annotation,
+            value,
         })
     }
 
     fn parse_fn(&mut self, with_identifier: bool) -> Result<Statement, ParseError> {
+        let doc = if with_identifier { self.doc() } else { None };
+        let is_async = self.current_is(Token::Async);
+        if is_async {
+            self.expect_token_and_read(Token::Async)?;
+        }
         self.expect_token_and_read(Token::Fn)?;
-        let name: Identifier = if with_identifier { expect_identifier_and_read()?.into()
+        let kind = if is_async {
+            FunctionKind::Async
+        } else if self.current_is(Token::Asterisk) {
+            self.expect_token_and_read(Token::Asterisk)?;
+            FunctionKind::Generator
+        } else {
+            FunctionKind::Normal
+        };
+        let name: Identifier = if with_identifier {
+            self.expect_identifier_and_read()?.into()
         } else {
             String::from("<Closure>")
         };
 
         self.expect_token_and_read(Token::LeftParen)?;
-        let mut params: Vec<Paramater> = Vec::new();    
+        let params = self.parse_parameters(Token::RightParen)?;
+        let return_type = if self.current_is(Token::Arrow) {
+            self.expect_token_and_read(Token::Arrow)?;
+            self.parse_type(true)?.into()
+        } else {
+            None
+        };
+        let body = self.parse_function_body(kind, |parser| parser.parse_block())?;
+        Ok(Statement::FunctionDeclaration {
+            doc,
+            attributes: Vec::new(),
+            name,
+            params,
+            return_type,
+            body,
+            kind,
+        })
+    }
+
+    fn parse_decorated_fn(&mut self) -> Result<Statement, ParseError> {
+        let attributes = self.doc_after(|parser| {
+            let mut attributes = Vec::new();
+            while parser.current_is(Token::At) {
+                attributes.push(parser.spanned(|parser| {
+                    parser.expect_token_and_read(Token::At)?;
+                    let name = parser.expect_identifier_and_read()?.into();
+                    let arguments = if parser.current_is(Token::LeftParen) { parser.parse_arguments()? } else { Vec::new() };
+                    Ok(Attribute { name, arguments })
+                })?);
+            }
+            Ok(attributes)
+        })?;
+
+        match self.current {
+            Token::Fn | Token::Async => match self.parse_fn(true)? {
+                Statement::FunctionDeclaration { doc, name, params, return_type, body, kind, .. } => {
+                    Ok(Statement::FunctionDeclaration { doc, attributes, name, params, return_type, body, kind })
+                },
+                _ => unreachable!(),
+            },
+            _ => Err(ParseError::UnexpectedToken(self.current.clone())),
+        }
+    }
+
+    fn parse_parameters(&mut self, close: Token) -> Result<Vec<Spanned<Parameter>>, ParseError> {
+        let mut params = Vec::new();
+
+        while !self.current_is(close.clone()) {
+            let param = self.spanned(|parser| {
+                let variadic = parser.current_is(Token::Ellipsis);
+                if variadic {
+                    parser.expect_token_and_read(Token::Ellipsis)?;
+                }
+
+                let name: Identifier = parser.expect_identifier_and_read()?.into();
+                // Unions inside `|...|` would be ambiguous with the closing bar, so they need parentheses there.
+                let annotation = parser.parse_annotation(close != Token::Bar)?;
+                let default = if !variadic && parser.current_is(Token::Assign) {
+                    parser.expect_token_and_read(Token::Assign)?;
+                    parser.parse_expression(Precedence::Lowest)?.some()
+                } else {
+                    None
+                };
+
+                Ok(Parameter { name, annotation, default, variadic })
+            })?;
+
+            if param.variadic && !self.current_is(close.clone()) {
+                return Err(ParseError::VariadicParameterNotLast(param.node.name));
+            }
+            params.push(param);
 
-        while !self.current_is(Token::RightParen) {
             if self.current_is(Token::Comma) {
+                self.read();
+            }
+        }
+
+        self.expect_token_and_read(close)?;
+        Ok(params)
+    }
+
+    fn parse_annotation(&mut self, allow_union: bool) -> Result<Option<Type>, ParseError> {
+        if !self.current_is(Token::Colon) {
+            return Ok(None)
+        }
+
+        self.expect_token_and_read(Token::Colon)?;
+        Ok(Some(self.parse_type(allow_union)?))
+    }
+
+    fn parse_type(&mut self, allow_union: bool) -> Result<Type, ParseError> {
+        let mut ty = if self.current_is(Token::LeftParen) {
+            self.expect_token_and_read(Token::LeftParen)?;
+            let ty = self.parse_type(true)?;
+            self.expect_token_and_read(Token::RightParen)?;
+            ty
+        } else {
+            let name: Identifier = self.expect_identifier_and_read()?.into();
+            let mut arguments = Vec::new();
+
+            if self.current_is(Token::LessThan) {
+                self.expect_token_and_read(Token::LessThan)?;
+                while !self.current_is(Token::GreaterThan) {
+                    arguments.push(self.parse_type(true)?);
+
+                    if self.current_is(Token::Comma) {
+                        self.read();
+                    }
+                }
+                self.expect_token_and_read(Token::GreaterThan)?;
+            }
+
+            Type::Named(name, arguments)
+        };
+
+        if allow_union && self.current_is(Token::Bar) {
+            let mut members = vec![ty];
+            while self.current_is(Token::Bar) {
+                self.expect_token_and_read(Token::Bar)?;
+                members.push(self.parse_type(false)?);
+            }
+            ty = Type::Union(members);
+        }
+
+        Ok(ty)
+    }
+
+    fn parse_lambda_body(&mut self) -> Result<Block, ParseError> {
+        self.parse_function_body(FunctionKind::Normal, |parser| if parser.current_is(Token::LeftBrace) {
+            parser.parse_block()
+        } else {
+            Ok(vec![parser.spanned(|parser| Ok(Statement::Expression { expression: parser.parse_expression(Precedence::Lowest)? }))?])
+        })
+    }
+
+    /// Parses a function body, keeping track of the kind of function `yield` would belong to.
+    /// A `break` in the body can't leave a loop outside it.
+    fn parse_function_body(&mut self, kind: FunctionKind, parse: impl FnOnce(&mut Self) -> Result<Block, ParseError>) -> Result<Block, ParseError> {
+        let outer = (self.function.replace(kind), std::mem::replace(&mut self.looping, false));
+        let body = parse(self);
+        (self.function, self.looping) = outer;
+        body
+    }
+
+    fn parse_loop_body(&mut self) -> Result<Block, ParseError> {
+        let outer = std::mem::replace(&mut self.looping, true);
+        let body = self.parse_block();
+        self.looping = outer;
+        body
+    }
+
+    fn parse_import(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::Import)?;
+
+        let (path, binding) = if self.current_is(Token::LeftBrace) {
+            self.expect_token_and_read(Token::LeftBrace)?;
+            let mut names = Vec::new();
+
+            while !self.current_is(Token::RightBrace) {
+                names.push(self.expect_identifier_and_read()?.into());
+
+                if self.current_is(Token::Comma) {
+                    self.read();
+                }
+            }
+
+            self.expect_token_and_read(Token::RightBrace)?;
+            self.expect_token_and_read(Token::From)?;
+            let path: String = self.expect_token_and_read(Token::String("".to_string()))?.into();
+            (path, ImportBinding::Names(names))
+        } else {
+            let path: String = self.expect_token_and_read(Token::String("".to_string()))?.into();
+            self.expect_token_and_read(Token::As)?;
+            (path, ImportBinding::Namespace(self.expect_identifier_and_read()?.into()))
+        };
+
+        Ok(Statement::Import { path, binding })
+    }
+
+    fn parse_export(&mut self) -> Result<Statement, ParseError> {
+        self.doc_after(|parser| parser.expect_token_and_read(Token::Export))?;
+
+        let declaration = self.spanned(|parser| match parser.current {
+            Token::Fn | Token::Async => parser.parse_fn(true),
+            Token::At => parser.parse_decorated_fn(),
+            Token::Let => parser.parse_let(),
+            Token::Const => parser.parse_const(),
+            Token::Struct => parser.parse_struct(),
+            Token::Trait => parser.parse_trait(),
+            _ => Err(ParseError::UnexpectedToken(parser.current.clone())),
+        })?;
+
+        Ok(Statement::Export { declaration: Box::new(declaration) })
+    }
+
+    fn parse_struct(&mut self) -> Result<Statement, ParseError> {
+        let doc = self.doc();
+        self.expect_token_and_read(Token::Struct)?;
+        let name: Identifier = self.expect_identifier_and_read()?.into();
+        self.expect_token_and_read(Token::LeftBrace)?;
+
+        let mut fields = Vec::new();
+        while !self.current_is(Token::RightBrace) {
+            fields.push(self.expect_identifier_and_read()?.into());
+
+            if self.current_is(Token::Comma) {
+                self.read();
+            }
+        }
+        self.expect_token_and_read(Token::RightBrace)?;
+
+        Ok(Statement::Struct { doc, name, fields })
+    }
+
+    fn parse_impl(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::Impl)?;
+        let mut target: Identifier = self.expect_identifier_and_read()?.into();
+        let trait_name = if self.current_is(Token::For) {
+            self.expect_token_and_read(Token::For)?;
+            Some(std::mem::replace(&mut target, self.expect_identifier_and_read()?.into()))
+        } else {
+            None
+        };
+        self.expect_token_and_read(Token::LeftBrace)?;
+
+        let mut methods = Vec::new();
+        while !self.current_is(Token::RightBrace) {
+            methods.push(self.spanned(|parser| match parser.current {
+                Token::Fn | Token::Async => parser.parse_fn(true),
+                Token::At => parser.parse_decorated_fn(),
+                _ => Err(ParseError::UnexpectedToken(parser.current.clone())),
+            })?);
+        }
+        self.expect_token_and_read(Token::RightBrace)?;
+
+        Ok(Statement::Impl { trait_name, target, methods })
+    }
+
+    fn parse_trait(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::Trait)?;
+        let name: Identifier = self.expect_identifier_and_read()?.into();
+        self.expect_token_and_read(Token::LeftBrace)?;
+
+        let mut methods = Vec::new();
+        while !self.current_is(Token::RightBrace) {
+            methods.push(self.spanned(|parser| {
+                parser.expect_token_and_read(Token::Fn)?;
+                let name: Identifier = parser.expect_identifier_and_read()?.into();
+                parser.expect_token_and_read(Token::LeftParen)?;
+                let params = parser.parse_parameters(Token::RightParen)?;
+                let return_type = if parser.current_is(Token::Arrow) {
+                    parser.expect_token_and_read(Token::Arrow)?;
+                    parser.parse_type(true)?.into()
+                } else {
+                    None
+                };
+                let default = if parser.current_is(Token::LeftBrace) {
+                    Some(parser.parse_function_body(FunctionKind::Normal, |parser| parser.parse_block())?)
+                } else {
+                    None
+                };
+                Ok(TraitMethod { name, params, return_type, default })
+            })?);
+        }
+        self.expect_token_and_read(Token::RightBrace)?;
+
+        Ok(Statement::Trait { name, methods })
+    }
+
+    fn parse_for(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::For)?;
+        let binding: Identifier = self.expect_identifier_and_read()?.into();
+        self.expect_token_and_read(Token::In)?;
+        let iterable = self.parse_expression(Precedence::Lowest)?;
+        let body = self.parse_loop_body()?;
+
+        Ok(Statement::For { binding, iterable, body })
+    }
+
+    fn parse_while(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::While)?;
+        let condition = self.parse_expression(Precedence::Lowest)?;
+        let body = self.parse_loop_body()?;
+
+        Ok(Statement::While { condition, body })
+    }
+
+    /// The clauses of a comprehension, which start with a `for`, up to and including the closing token.
+    fn parse_clauses(&mut self, close: Token) -> Result<Vec<Clause>, ParseError> {
+        let mut clauses = Vec::new();
+        loop {
+            clauses.push(if clauses.is_empty() || !self.current_is(Token::If) {
+                self.expect_token_and_read(Token::For)?;
+                let pattern = self.parse_pattern()?;
+                self.expect_token_and_read(Token::In)?;
+                Clause::For(pattern, self.parse_expression(Precedence::Lowest)?)
+            } else {
+                self.expect_token_and_read(Token::If)?;
+                Clause::If(self.parse_expression(Precedence::Lowest)?)
+            });
+
+            if self.current_is(close.clone()) {
+                self.expect_token_and_read(close)?;
+                return Ok(clauses)
+            }
+        }
+    }
+
+    fn parse_pattern(&mut self) -> Result<Pattern, ParseError> {
+        if !self.current_is(Token::LeftParen) {
+            return Ok(Pattern::Name(self.expect_identifier_and_read()?.into()))
+        }
+
+        self.expect_token_and_read(Token::LeftParen)?;
+        let mut patterns = Vec::new();
+        while !self.current_is(Token::RightParen) {
+            patterns.push(self.parse_pattern()?);
+
+            if !self.current_is(Token::RightParen) {
                 self.expect_token_and_read(Token::Comma)?;
             }
-            let param: String = self.expect_identifier_and_read()?.into();
-            params.push(Parameter {name: param})
         }
         self.expect_token_and_read(Token::RightParen)?;
-        let body: Vec<Statement> = self.parse_block()?;
-        Ok(Statement::FunctionDeclaration {
-            name: name,
-            params: params,
-            body: body,
-        })
+        Ok(Pattern::Tuple(patterns))
+    }
+
+    fn parse_yield(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::Yield)?;
+        if self.function != Some(FunctionKind::Generator) {
+            return Err(ParseError::YieldOutsideGenerator)
+        }
+
+        Ok(Statement::Yield { value: self.parse_expression(Precedence::Lowest)? })
+    }
+
+    /// `return` takes a value only when one starts on the same line.
+    fn parse_return(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::Return)?;
+        if self.function.is_none() {
+            return Err(ParseError::ReturnOutsideFunction)
+        }
+
+        let ends = self.current_is(Token::RightBrace) || self.current_is(Token::Eof) || self.current_span.line != self.previous_span.line;
+        let value = if ends { None } else { Some(self.parse_expression(Precedence::Lowest)?) };
+        Ok(Statement::Return { value })
+    }
+
+    fn parse_break(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::Break)?;
+        if !self.looping {
+            return Err(ParseError::BreakOutsideLoop)
+        }
+
+        Ok(Statement::Break)
+    }
+
+    fn parse_throw(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::Throw)?;
+
+        Ok(Statement::Throw { value: self.parse_expression(Precedence::Lowest)? })
+    }
+
+    fn parse_try(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::Try)?;
+        let body = self.parse_block()?;
+
+        let (binding, catch) = if self.current_is(Token::Catch) {
+            self.expect_token_and_read(Token::Catch)?;
+            let binding = if self.current_is(Token::Identifier("".to_string())) {
+                Some(self.expect_identifier_and_read()?.into())
+            } else {
+                None
+            };
+            (binding, Some(self.parse_block()?))
+        } else {
+            (None, None)
+        };
+
+        let finally = if self.current_is(Token::Finally) {
+            self.expect_token_and_read(Token::Finally)?;
+            Some(self.parse_block()?)
+        } else if catch.is_none() {
+            return Err(ParseError::UnexpectedToken(self.current.clone()))
+        } else {
+            None
+        };
+
+        Ok(Statement::Try { body, binding, catch, finally })
+    }
+
+    fn parse_defer(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::Defer)?;
+
+        Ok(Statement::Defer { value: self.parse_expression(Precedence::Lowest)? })
+    }
+
+    fn parse_with(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::With)?;
+        let resource = self.parse_expression(Precedence::Lowest)?;
+        let binding = if self.current_is(Token::As) {
+            self.expect_token_and_read(Token::As)?;
+            Some(self.expect_identifier_and_read()?.into())
+        } else {
+            None
+        };
+
+        Ok(Statement::With { resource, binding, body: self.parse_block()? })
+    }
+
+    fn parse_macro(&mut self) -> Result<Statement, ParseError> {
+        self.expect_token_and_read(Token::Macro)?;
+        let name: Identifier = self.expect_identifier_and_read()?.into();
+        self.expect_token_and_read(Token::Bang)?;
+        let pattern = self.parse_token_tree(Token::LeftParen, Token::RightParen)?;
+        let template = self.parse_token_tree(Token::LeftBrace, Token::RightBrace)?;
+
+        Ok(Statement::Macro { name, pattern, template })
+    }
+
+    /// Reads the tokens between `open` and its matching `close`, which macros
+    /// take as they are rather than parsing.
+    fn parse_token_tree(&mut self, open: Token, close: Token) -> Result<Vec<Spanned<Token>>, ParseError> {
+        self.expect_token_and_read(open)?;
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+
+        while depth > 0 || !self.current_is(close.clone()) {
+            match self.current {
+                Token::Eof => return Err(ParseError::UnexpectedToken(Token::Eof)),
+                Token::LeftParen | Token::LeftBracket | Token::LeftBrace => depth += 1,
+                Token::RightParen | Token::RightBracket | Token::RightBrace => depth -= 1,
+                _ => {},
+            }
+            tokens.push(Spanned::new(self.current.clone(), self.current_span));
+            self.read();
+        }
+
+        self.expect_token_and_read(close)?;
+        Ok(tokens)
     }
 
     fn parse_if(&mut self) -> Result<Statement, ParseError> {
@@ -224,16 +923,22 @@ impl<'p> Parser<'p> {
         std::mem::discriminant(&self.current) == std::mem::discriminant(&token)
     }
 
+    fn peek_is(&self, token: Token) -> bool {
+        std::mem::discriminant(&self.peek) == std::mem::discriminant(&token)
+    }
+
     fn read(&mut self) {
+        self.previous_span = self.current_span;
         self.current = self.peek.clone();
-        self.peek = if let Some(token) = self.tokens.next() {
-            token.clone()
+        self.current_span = self.peek_span;
+        (self.peek, self.peek_span) = if let Some((token, span)) = self.tokens.next() {
+            (token.clone(), *span)
         } else {
-            Token::Eof
+            (Token::Eof, Span { start: self.current_span.end, ..self.current_span })
         };
     }
 
-    fn next(&mut self) -> Result<Option<Statement>, ParseError> {
+    fn next(&mut self) -> Result<Option<Spanned<Statement>>, ParseError> {
         if self.current == Token::Eof {
             return Ok(None)
         }
@@ -246,10 +951,451 @@ impl<'p> Parser<'p> {
 pub enum ParseError {
     #[error("Unexpected token {0:?}.")]
     UnexpectedToken(Token),
+    #[error("Variadic parameter ...{0} must be the last parameter.")]
+    VariadicParameterNotLast(Identifier),
+    #[error("yield can only be used inside a fn* generator.")]
+    YieldOutsideGenerator,
+    #[error("return can only be used inside a function.")]
+    ReturnOutsideFunction,
+    #[error("break can only be used inside a loop.")]
+    BreakOutsideLoop,
+    #[error("await can only be used inside an async fn or at the top level.")]
+    AwaitOutsideAsync,
+    #[error("Generators and async functions must be declared with a name.")]
+    AnonymousFunction,
 }
 
 #[cfg(test)]
-
 mod tests {
     use super::*;
-}
\ No newline at end of file
+
+    fn parse_source(source: &str) -> Program {
+        let tokens = crate::token::generate(source).into_iter().map(|t| t.unwrap()).collect();
+        parse(tokens).unwrap()
+    }
+
+    fn expression(source: &str) -> Expression {
+        match parse_source(source).remove(0).node {
+            Statement::Expression { expression } => expression.node,
+            s => panic!("expected an expression statement, found {:?}", s),
+        }
+    }
+
+    fn ident(name: &str) -> Spanned<Expression> {
+        Expression::Identifier(name.to_string()).into()
+    }
+
+    #[test]
+    fn it_can_parse_infix_expressions_with_precedence() {
+        assert_eq!(expression("1 + 2 * 3"), Expression::Infix(
+            Expression::Number(1.0).boxed(),
+            Op::Add,
+            Expression::Infix(Expression::Number(2.0).boxed(), Op::Multiply, Expression::Number(3.0).boxed()).boxed(),
+        ));
+    }
+
+    #[test]
+    fn it_can_parse_parenthesised_expressions() {
+        assert_eq!(expression("(1 + 2) * 3"), Expression::Infix(
+            Expression::Infix(Expression::Number(1.0).boxed(), Op::Add, Expression::Number(2.0).boxed()).boxed(),
+            Op::Multiply,
+            Expression::Number(3.0).boxed(),
+        ));
+        assert_eq!(expression("(x)?"), Expression::Propagate(ident("x").boxed()));
+    }
+
+    #[test]
+    fn it_can_parse_macro_definitions_and_calls() {
+        let tokens = |source: &str| -> Vec<Spanned<Token>> {
+            crate::token::generate(source).into_iter().map(|t| t.unwrap().0.into()).collect()
+        };
+
+        assert_eq!(parse_source("macro twice!($x:expr) { [$x, ($x)] }")[0].node, Statement::Macro {
+            name: "twice".to_string(),
+            pattern: tokens("$x:expr"),
+            template: tokens("[$x, ($x)]"),
+        });
+        assert_eq!(expression("twice!(f(1), {})"), Expression::MacroCall("twice".to_string(), tokens("f(1), {}")));
+    }
+
+    #[test]
+    fn it_can_parse_attributes_on_function_declarations() {
+        let Statement::Export { declaration } = &parse_source("export @memoize\n@deprecated(\"use g\")\nfn f() {}")[0].node else { panic!() };
+        let Statement::FunctionDeclaration { attributes, name, .. } = &declaration.node else { panic!() };
+
+        assert_eq!(name, "f");
+        assert_eq!(attributes, &vec![
+            Attribute { name: "memoize".to_string(), arguments: vec![] }.into(),
+            Attribute { name: "deprecated".to_string(), arguments: vec![Argument::Positional(Expression::String("use g".to_string()).into())] }.into(),
+        ]);
+        assert!(matches!(parse(crate::token::generate("@trace\nlet x = 1").into_iter().map(|t| t.unwrap()).collect()).map_err(|e| e.node), Err(ParseError::UnexpectedToken(Token::Let))));
+    }
+
+    #[test]
+    fn it_can_parse_structs_impls_comparisons_and_indexing() {
+        let program = parse_source("struct Point { x, y }\nimpl Point {\n    fn add(self, other) { self }\n}");
+        assert_eq!(program[0].node, Statement::Struct { doc: None, name: "Point".to_string(), fields: vec!["x".to_string(), "y".to_string()] });
+        assert!(matches!(&program[1].node, Statement::Impl { trait_name: None, target, methods } if target == "Point" && methods.len() == 1));
+
+        assert_eq!(expression("a + 1 < b == c"), Expression::Infix(
+            Expression::Infix(
+                Expression::Infix(ident("a").boxed(), Op::Add, Expression::Number(1.0).boxed()).boxed(),
+                Op::Less,
+                ident("b").boxed(),
+            ).boxed(),
+            Op::Equal,
+            ident("c").boxed(),
+        ));
+        assert_eq!(expression("xs[i][0]"), Expression::Index(
+            Expression::Index(ident("xs").boxed(), ident("i").boxed()).boxed(),
+            Expression::Number(0.0).boxed(),
+        ));
+        assert_eq!(parse_source("xs\n[0]").len(), 2);
+    }
+
+    #[test]
+    fn it_can_parse_ranges_and_membership() {
+        assert_eq!(expression("x in 1..n + 1"), Expression::Infix(
+            ident("x").boxed(),
+            Op::In,
+            Expression::Range(
+                Expression::Number(1.0).boxed(),
+                Expression::Infix(ident("n").boxed(), Op::Add, Expression::Number(1.0).boxed()).boxed(),
+                false,
+            ).boxed(),
+        ));
+        assert_eq!(expression("xs[0..=i]"), Expression::Index(
+            ident("xs").boxed(),
+            Expression::Range(Expression::Number(0.0).boxed(), ident("i").boxed(), true).boxed(),
+        ));
+        assert!(matches!(expression("(0..10).step(2)"), Expression::Call(callable, _) if matches!(&callable.node, Expression::Get(..))));
+    }
+
+    #[test]
+    fn it_can_parse_comprehensions() {
+        assert_eq!(expression("[x * 2 for x in xs if x > 0]"), Expression::ListComprehension(
+            Expression::Infix(ident("x").boxed(), Op::Multiply, Expression::Number(2.0).boxed()).boxed(),
+            vec![
+                Clause::For(Pattern::Name("x".to_string()), ident("xs")),
+                Clause::If(Expression::Infix(ident("x").boxed(), Op::Greater, Expression::Number(0.0).boxed()).into()),
+            ],
+        ));
+        assert_eq!(expression("{k: v for (k, v) in pairs for w in v}"), Expression::MapComprehension(
+            ident("k").boxed(),
+            ident("v").boxed(),
+            vec![
+                Clause::For(Pattern::Tuple(vec![Pattern::Name("k".to_string()), Pattern::Name("v".to_string())]), ident("pairs")),
+                Clause::For(Pattern::Name("w".to_string()), ident("v")),
+            ],
+        ));
+        assert_eq!(expression("[a, b]"), Expression::List(vec![ident("a"), ident("b")]));
+        assert!(parse(crate::token::generate("[x if x]").into_iter().map(|t| t.unwrap()).collect()).is_err());
+        assert!(parse(crate::token::generate("{k: v}").into_iter().map(|t| t.unwrap()).collect()).is_err());
+    }
+
+    #[test]
+    fn it_can_parse_nil_and_optional_chains() {
+        assert_eq!(expression("a?.b?.[i]?.(1)"), Expression::OptionalCall(
+            Expression::OptionalIndex(Expression::OptionalGet(ident("a").boxed(), "b".to_string()).boxed(), ident("i").boxed()).boxed(),
+            vec![Argument::Positional(Expression::Number(1.0).into())],
+        ));
+        assert_eq!(expression("a ?? b == nil"), Expression::Infix(
+            ident("a").boxed(),
+            Op::Coalesce,
+            Expression::Infix(ident("b").boxed(), Op::Equal, Expression::Nil.boxed()).boxed(),
+        ));
+        assert_eq!(parse_source("let x")[0].node, Statement::LetDeclaration { name: "x".to_string(), annotation: None, initial: None });
+    }
+
+    #[test]
+    fn it_can_parse_traits_and_their_impls() {
+        let program = parse_source("trait Shape {\n    fn area(self) -> Number\n    fn name(self) { \"shape\" }\n}\nimpl Shape for Square {}\nsquare implements Shape");
+
+        let Statement::Trait { name, methods } = &program[0].node else { panic!() };
+        assert_eq!(name, "Shape");
+        assert_eq!(methods[0].node, TraitMethod {
+            name: "area".to_string(),
+            params: vec![Parameter::new("self").into()],
+            return_type: Some(Type::Named("Number".to_string(), vec![])),
+            default: None,
+        });
+        assert_eq!(methods[1].default, Some(vec![Statement::Expression { expression: Expression::String("shape".to_string()).into() }.into()]));
+        assert_eq!(program[1].node, Statement::Impl { trait_name: Some("Shape".to_string()), target: "Square".to_string(), methods: vec![] });
+        assert_eq!(program[2].node, Statement::Expression {
+            expression: Expression::Infix(ident("square").boxed(), Op::Implements, ident("Shape").boxed()).into(),
+        });
+    }
+
+    #[test]
+    fn it_can_parse_pipes_into_calls() {
+        assert_eq!(expression("xs |> filter(f) |> map(g)"), Expression::Call(
+            ident("map").boxed(),
+            vec![
+                Argument::Positional(Expression::Call(
+                    ident("filter").boxed(),
+                    vec![Argument::Positional(ident("xs")), Argument::Positional(ident("f"))],
+                ).into()),
+                Argument::Positional(ident("g")),
+            ],
+        ));
+        assert_eq!(expression("1 + 2 |> println"), Expression::Call(
+            ident("println").boxed(),
+            vec![Argument::Positional(Expression::Infix(Expression::Number(1.0).boxed(), Op::Add, Expression::Number(2.0).boxed()).into())],
+        ));
+    }
+
+    #[test]
+    fn it_can_parse_method_calls() {
+        assert_eq!(expression("xs.map(g)"), Expression::Call(
+            Expression::Get(ident("xs").boxed(), "map".to_string()).boxed(),
+            vec![Argument::Positional(ident("g"))],
+        ));
+    }
+
+    #[test]
+    fn it_can_parse_imports_and_exports() {
+        assert_eq!(parse_source("import \"./util.crs\" as util\nimport { parse, render } from \"lib/format\""), vec![
+            Statement::Import { path: "./util.crs".to_string(), binding: ImportBinding::Namespace("util".to_string()) }.into(),
+            Statement::Import {
+                path: "lib/format".to_string(),
+                binding: ImportBinding::Names(vec!["parse".to_string(), "render".to_string()]),
+            }.into(),
+        ]);
+        assert_eq!(parse_source("export let answer = 42"), vec![Statement::Export {
+            declaration: Box::new(Statement::LetDeclaration {
+                name: "answer".to_string(),
+                annotation: None,
+                initial: Expression::Number(42.0).some(),
+            }.into()),
+        }.into()]);
+    }
+
+    #[test]
+    fn it_can_parse_generators_and_for_loops() {
+        assert_eq!(parse_source("fn* lines(xs) { for x in xs { yield x } }"), vec![Statement::FunctionDeclaration {
+            doc: None,
+            attributes: Vec::new(),
+            name: "lines".to_string(),
+            params: vec![Parameter::new("xs").into()],
+            return_type: None,
+            body: vec![Statement::For {
+                binding: "x".to_string(),
+                iterable: ident("xs"),
+                body: vec![Statement::Yield { value: ident("x") }.into()],
+            }.into()],
+            kind: FunctionKind::Generator,
+        }.into()]);
+    }
+
+    #[test]
+    fn it_rejects_yield_outside_generators() {
+        let parse_error = |source: &str| {
+            let tokens = crate::token::generate(source).into_iter().map(|t| t.unwrap()).collect();
+            parse(tokens).unwrap_err().node
+        };
+
+        assert!(matches!(parse_error("yield 1"), ParseError::YieldOutsideGenerator));
+        assert!(matches!(parse_error("fn f() { yield 1 }"), ParseError::YieldOutsideGenerator));
+        assert!(matches!(parse_error("fn* f() { let g = |x| { yield x } }"), ParseError::YieldOutsideGenerator));
+    }
+
+    #[test]
+    fn it_parses_return_and_break_only_where_they_can_leave() {
+        let parse_error = |source: &str| {
+            let tokens = crate::token::generate(source).into_iter().map(|t| t.unwrap()).collect();
+            parse(tokens).unwrap_err().node
+        };
+
+        assert_eq!(parse_source("fn f() { return\n1 }\nfn g() { return 1 }\nfor x in xs { break }"), vec![
+            Statement::FunctionDeclaration {
+                doc: None, attributes: vec![], name: "f".to_string(), params: vec![], return_type: None, kind: FunctionKind::Normal,
+                body: vec![Statement::Return { value: None }.into(), Statement::Expression { expression: Expression::Number(1.0).into() }.into()],
+            }.into(),
+            Statement::FunctionDeclaration {
+                doc: None, attributes: vec![], name: "g".to_string(), params: vec![], return_type: None, kind: FunctionKind::Normal,
+                body: vec![Statement::Return { value: Expression::Number(1.0).some() }.into()],
+            }.into(),
+            Statement::For { binding: "x".to_string(), iterable: ident("xs"), body: vec![Statement::Break.into()] }.into(),
+        ]);
+        assert!(matches!(parse_error("return 1"), ParseError::ReturnOutsideFunction));
+        assert!(matches!(parse_error("break"), ParseError::BreakOutsideLoop));
+        assert_eq!(parse_source("while x { break }"), vec![Statement::While { condition: ident("x"), body: vec![Statement::Break.into()] }.into()]);
+        assert!(matches!(parse_error("for x in xs { let f = || { break } }"), ParseError::BreakOutsideLoop));
+        assert!(matches!(parse_error("for x in xs { fn f() { break } }"), ParseError::BreakOutsideLoop));
+    }
+
+    #[test]
+    fn it_can_parse_async_functions_and_await() {
+        match parse_source("async fn fetch(ms) { await sleep(ms) }").remove(0).node {
+            Statement::FunctionDeclaration { kind, body, .. } => {
+                assert_eq!(kind, FunctionKind::Async);
+                assert_eq!(body, vec![Statement::Expression {
+                    expression: Expression::Await(
+                        Expression::Call(ident("sleep").boxed(), vec![Argument::Positional(ident("ms"))]).boxed(),
+                    ).into(),
+                }.into()]);
+            },
+            s => panic!("expected a function declaration, found {:?}", s),
+        }
+
+        assert_eq!(expression("await fetch()"), Expression::Await(Expression::Call(ident("fetch").boxed(), vec![]).boxed()));
+    }
+
+    #[test]
+    fn it_rejects_await_outside_async_functions() {
+        let tokens = crate::token::generate("fn f() { await g() }").into_iter().map(|t| t.unwrap()).collect();
+        assert!(matches!(parse(tokens).map_err(|e| e.node), Err(ParseError::AwaitOutsideAsync)));
+    }
+
+    #[test]
+    fn it_can_parse_defer_and_with() {
+        let call = |name: &str| Expression::Call(ident(name).boxed(), vec![]).into();
+
+        assert_eq!(parse_source("with open() as f { defer f.close() }\nwith lock() {}"), vec![
+            Statement::With {
+                resource: call("open"),
+                binding: Some("f".to_string()),
+                body: vec![Statement::Defer { value: Expression::Call(Expression::Get(ident("f").boxed(), "close".to_string()).boxed(), vec![]).into() }.into()],
+            }.into(),
+            Statement::With { resource: call("lock"), binding: None, body: vec![] }.into(),
+        ]);
+    }
+
+    #[test]
+    fn it_can_parse_exceptions() {
+        assert_eq!(parse_source("try { risky()? } catch e { throw e } finally { cleanup() }"), vec![Statement::Try {
+            body: vec![Statement::Expression {
+                expression: Expression::Propagate(Expression::Call(ident("risky").boxed(), vec![]).boxed()).into(),
+            }.into()],
+            binding: Some("e".to_string()),
+            catch: Some(vec![Statement::Throw { value: ident("e") }.into()]),
+            finally: Some(vec![Statement::Expression { expression: Expression::Call(ident("cleanup").boxed(), vec![]).into() }.into()]),
+        }.into()]);
+
+        let tokens = crate::token::generate("try { risky() } let x = 1").into_iter().map(|t| t.unwrap()).collect();
+        let error = parse(tokens).unwrap_err();
+
+        assert!(matches!(error.node, ParseError::UnexpectedToken(Token::Let)));
+        assert_eq!(error.span.to_string(), "1:17");
+    }
+
+    #[test]
+    fn it_attaches_doc_comments_to_the_declaration_after_them() {
+        let docs: Vec<Option<String>> = parse_source(
+            "/// Adds.\n/// Twice.\nexport @memo\nfn add(a, b) {}\n/// A point.\nstruct P {}\n/// Ignored.\nlet x = 1\nconst Y = 2"
+        ).into_iter().map(|statement| match statement.node {
+            Statement::Export { declaration } => match declaration.node {
+                Statement::FunctionDeclaration { doc, .. } => doc,
+                s => panic!("expected a function declaration, found {:?}", s),
+            },
+            Statement::Struct { doc, .. } | Statement::ConstDeclaration { doc, .. } => doc,
+            _ => None,
+        }).collect();
+
+        assert_eq!(docs, vec![Some("Adds.\nTwice.".to_string()), Some("A point.".to_string()), None, None]);
+    }
+
+    #[test]
+    fn it_can_parse_short_lambdas() {
+        let doubled = vec![Statement::Expression {
+            expression: Expression::Infix(ident("x").boxed(), Op::Multiply, Expression::Number(2.0).boxed()).into(),
+        }.into()];
+
+        assert_eq!(expression("|x| x * 2"), Expression::Closure(vec![Parameter::new("x").into()], doubled.clone()));
+        assert_eq!(expression("x => x * 2"), Expression::Closure(vec![Parameter::new("x").into()], doubled));
+    }
+
+    #[test]
+    fn it_can_parse_default_and_variadic_parameters() {
+        let params = match parse_source("fn greet(name, greeting = \"Hello\", ...rest) {}").remove(0).node {
+            Statement::FunctionDeclaration { params, .. } => params,
+            s => panic!("expected a function declaration, found {:?}", s),
+        };
+
+        assert_eq!(params, vec![
+            Parameter::new("name").into(),
+            Parameter { name: "greeting".to_string(), annotation: None, default: Expression::String("Hello".to_string()).some(), variadic: false }.into(),
+            Parameter { name: "rest".to_string(), annotation: None, default: None, variadic: true }.into(),
+        ]);
+    }
+
+    #[test]
+    fn it_can_parse_type_annotations() {
+        let number = || Type::Named("Number".to_string(), Vec::new());
+        let string = || Type::Named("String".to_string(), Vec::new());
+
+        match parse_source("fn add(a: Number, b: Number) -> Number { a + b }").remove(0).node {
+            Statement::FunctionDeclaration { params, return_type, .. } => {
+                assert_eq!(params[0].annotation, Some(number()));
+                assert_eq!(params[1].annotation, Some(number()));
+                assert_eq!(return_type, Some(number()));
+            },
+            s => panic!("expected a function declaration, found {:?}", s),
+        }
+
+        match parse_source("let scores: Map<String, List<Number | String>> = x").remove(0).node {
+            Statement::LetDeclaration { annotation, .. } => assert_eq!(annotation, Some(Type::Named(
+                "Map".to_string(),
+                vec![string(), Type::Named("List".to_string(), vec![Type::Union(vec![number(), string()])])],
+            ))),
+            s => panic!("expected a let declaration, found {:?}", s),
+        }
+
+        assert_eq!(expression("|x: (Number | String)| x"), Expression::Closure(
+            vec![Parameter { annotation: Some(Type::Union(vec![number(), string()])), ..Parameter::new("x") }.into()],
+            vec![Statement::Expression { expression: ident("x") }.into()],
+        ));
+    }
+
+    #[test]
+    fn it_rejects_variadic_parameters_that_are_not_last() {
+        let tokens = crate::token::generate("fn sum(...nums, last) {}").into_iter().map(|t| t.unwrap()).collect();
+
+        assert!(matches!(parse(tokens).map_err(|e| e.node), Err(ParseError::VariadicParameterNotLast(name)) if name == "nums"));
+    }
+
+    #[test]
+    fn it_can_parse_named_and_spread_arguments() {
+        assert_eq!(expression("connect(...opts, host: \"x\", port: 1)"), Expression::Call(
+            ident("connect").boxed(),
+            vec![
+                Argument::Spread(ident("opts")),
+                Argument::Named("host".to_string(), Expression::String("x".to_string()).into()),
+                Argument::Named("port".to_string(), Expression::Number(1.0).into()),
+            ],
+        ));
+    }
+
+    #[test]
+    fn it_records_the_span_of_every_node() {
+        let program = parse_source("let x = 1\nfn double(n) {\n  n * 2\n}");
+        let span = |start, end, line, column| Span { start, end, line, column };
+
+        assert_eq!(program[0].span, span(0, 9, 1, 1));
+        assert_eq!(program[1].span, span(10, 34, 2, 1));
+
+        match &program[1].node {
+            Statement::FunctionDeclaration { params, body, .. } => {
+                assert_eq!(params[0].span, span(20, 21, 2, 11));
+                assert_eq!(body[0].span, span(27, 32, 3, 3));
+                match &body[0].node {
+                    Statement::Expression { expression: Spanned { node: Expression::Infix(left, _, right), .. } } => {
+                        assert_eq!(left.span, span(27, 28, 3, 3));
+                        assert_eq!(right.span, span(31, 32, 3, 7));
+                    },
+                    s => panic!("expected an infix expression, found {:?}", s),
+                }
+            },
+            s => panic!("expected a function declaration, found {:?}", s),
+        }
+
+        let call = &parse_source("xs |> f(1)")[0];
+        assert_eq!(call.span, span(0, 10, 1, 1));
+    }
+
+    #[test]
+    fn it_ignores_spans_when_comparing_nodes() {
+        assert_eq!(parse_source("f(1)"), parse_source("\n\n   f(  1  )"));
+        assert_ne!(parse_source("f(1)"), parse_source("f(2)"));
+    }
+}
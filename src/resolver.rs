@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use thiserror::Error;
+
+use crate::ast::*;
+use crate::ast::fold::{self, Folder};
+
+/// Resolves constants before a program runs. A constant can't be redeclared in
+/// the scope that declared it, and a constant whose value is a literal
+/// expression is folded into every place that reads it.
+pub fn resolve(program: Program) -> Result<Program, Vec<Spanned<ResolveError>>> {
+    let mut resolver = Resolver::new();
+    let program = resolver.fold_block(program);
+
+    if resolver.errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(resolver.errors)
+    }
+}
+
+enum Binding {
+    Variable,
+    /// A constant, along with its literal value when it has one.
+    Constant(Option<Expression>),
+}
+
+struct Resolver {
+    scopes: Vec<HashMap<Identifier, Binding>>,
+    /// The span of the statement being resolved, where its errors are reported.
+    span: Span,
+    errors: Vec<Spanned<ResolveError>>,
+}
+
+impl Resolver {
+    fn new() -> Self {
+        Self {
+            scopes: vec![HashMap::new()],
+            span: Span::default(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn scoped<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        self.scopes.push(HashMap::new());
+        let result = f(self);
+        self.scopes.pop();
+        result
+    }
+
+    fn declare(&mut self, name: &Identifier, binding: Binding) {
+        let scope = self.scopes.last_mut().unwrap();
+        if let Some(Binding::Constant(_)) = scope.get(name) {
+            self.errors.push(Spanned::new(ResolveError::ConstantRedeclared(name.clone()), self.span));
+            return
+        }
+        scope.insert(name.clone(), binding);
+    }
+
+    fn lookup(&self, name: &Identifier) -> Option<&Binding> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn resolve_statement(&mut self, statement: Spanned<Statement>) -> Spanned<Statement> {
+        let Spanned { node, span } = statement;
+        let node = match node {
+            Statement::FunctionDeclaration { doc, attributes, name, params, return_type, body, kind } => {
+                let attributes = attributes.into_iter().map(|attribute| self.fold_attribute(attribute)).collect();
+                self.declare(&name, Binding::Variable);
+                let (params, body) = self.scoped(|resolver| {
+                    let params = params.into_iter().map(|param| resolver.fold_parameter(param)).collect();
+                    (params, resolver.fold_block(body))
+                });
+                Statement::FunctionDeclaration { doc, attributes, name, params, return_type, body, kind }
+            },
+            Statement::LetDeclaration { name, annotation, initial } => {
+                let initial = initial.map(|e| self.fold_expression(e));
+                self.declare(&name, Binding::Variable);
+                Statement::LetDeclaration { name, annotation, initial }
+            },
+            Statement::ConstDeclaration { doc, name, annotation, value } => {
+                let value = self.fold_expression(value);
+                let literal = Some(value.node.clone()).filter(is_literal);
+                self.declare(&name, Binding::Constant(literal));
+                Statement::ConstDeclaration { doc, name, annotation, value }
+            },
+            Statement::Import { path, binding } => {
+                match &binding {
+                    ImportBinding::Namespace(alias) => self.declare(alias, Binding::Variable),
+                    ImportBinding::Names(names) => for name in names {
+                        self.declare(name, Binding::Variable)
+                    },
+                }
+                Statement::Import { path, binding }
+            },
+            Statement::Struct { doc, name, fields } => {
+                self.declare(&name, Binding::Variable);
+                Statement::Struct { doc, name, fields }
+            },
+            Statement::Trait { name, methods } => {
+                self.declare(&name, Binding::Variable);
+                let methods = methods.into_iter().map(|method| self.scoped(|resolver| resolver.fold_trait_method(method))).collect();
+                Statement::Trait { name, methods }
+            },
+            Statement::For { binding, iterable, body } => {
+                let iterable = self.fold_expression(iterable);
+                let body = self.scoped(|resolver| {
+                    resolver.declare(&binding, Binding::Variable);
+                    resolver.fold_block(body)
+                });
+                Statement::For { binding, iterable, body }
+            },
+            Statement::With { resource, binding, body } => {
+                let resource = self.fold_expression(resource);
+                let body = self.scoped(|resolver| {
+                    if let Some(binding) = &binding {
+                        resolver.declare(binding, Binding::Variable);
+                    }
+                    resolver.fold_block(body)
+                });
+                Statement::With { resource, binding, body }
+            },
+            Statement::Try { body, binding, catch, finally } => {
+                let body = self.fold_block(body);
+                let catch = catch.map(|catch| self.scoped(|resolver| {
+                    if let Some(binding) = &binding {
+                        resolver.declare(binding, Binding::Variable);
+                    }
+                    resolver.fold_block(catch)
+                }));
+                let finally = finally.map(|finally| self.fold_block(finally));
+                Statement::Try { body, binding, catch, finally }
+            },
+            node => return fold::walk_statement(self, Spanned::new(node, span)),
+        };
+
+        Spanned::new(node, span)
+    }
+}
+
+impl Folder for Resolver {
+    /// Every block is its own scope.
+    fn fold_block(&mut self, block: Block) -> Block {
+        self.scoped(|resolver| fold::walk_block(resolver, block))
+    }
+
+    fn fold_statement(&mut self, statement: Spanned<Statement>) -> Spanned<Statement> {
+        let outer = std::mem::replace(&mut self.span, statement.span);
+        let statement = self.resolve_statement(statement);
+        self.span = outer;
+        statement
+    }
+
+    fn fold_expression(&mut self, expression: Spanned<Expression>) -> Spanned<Expression> {
+        let Spanned { node, span } = expression;
+        let node = match node {
+            Expression::Identifier(name) => match self.lookup(&name) {
+                Some(Binding::Constant(Some(literal))) => literal.clone(),
+                _ => Expression::Identifier(name),
+            },
+            Expression::Infix(left, op, right) => {
+                let left = self.fold_expression(*left);
+                let right = self.fold_expression(*right);
+                fold_infix(left, op, right)
+            },
+            Expression::Prefix(op, right) => match (op, self.fold_expression(*right)) {
+                (Op::Subtract, Spanned { node: Expression::Number(n), .. }) => Expression::Number(-n),
+                (op, right) => Expression::Prefix(op, right.boxed()),
+            },
+            Expression::Closure(params, body) => self.scoped(|resolver| {
+                let params = params.into_iter().map(|param| resolver.fold_parameter(param)).collect();
+                Expression::Closure(params, resolver.fold_block(body))
+            }),
+            node @ (Expression::ListComprehension(..) | Expression::MapComprehension(..)) => {
+                return self.scoped(|resolver| fold::walk_expression(resolver, Spanned::new(node, span)))
+            },
+            node => return fold::walk_expression(self, Spanned::new(node, span)),
+        };
+
+        Spanned::new(node, span)
+    }
+
+    /// A `for` clause's names are declared once its iterable has been resolved,
+    /// for the clauses after it and the comprehension's item.
+    fn fold_clause(&mut self, clause: Clause) -> Clause {
+        let clause = fold::walk_clause(self, clause);
+        if let Clause::For(pattern, _) = &clause {
+            for name in pattern.names() {
+                self.declare(name, Binding::Variable);
+            }
+        }
+        clause
+    }
+
+    /// Parameters are declared once their default has been resolved, since
+    /// defaults can only refer to earlier parameters.
+    fn fold_parameter(&mut self, parameter: Spanned<Parameter>) -> Spanned<Parameter> {
+        let parameter = fold::walk_parameter(self, parameter);
+        self.declare(&parameter.name, Binding::Variable);
+        parameter
+    }
+}
+
+fn is_literal(expression: &Expression) -> bool {
+    matches!(expression, Expression::Number(_) | Expression::String(_) | Expression::Bool(_) | Expression::Nil)
+}
+
+/// Computes arithmetic on two number literals, the same way the interpreter would.
+fn fold_infix(left: Spanned<Expression>, op: Op, right: Spanned<Expression>) -> Expression {
+    match (&left.node, op, &right.node) {
+        (Expression::Number(l), Op::Add, Expression::Number(r)) => Expression::Number(l + r),
+        (Expression::Number(l), Op::Subtract, Expression::Number(r)) => Expression::Number(l - r),
+        (Expression::Number(l), Op::Multiply, Expression::Number(r)) => Expression::Number(l * r),
+        (Expression::Number(l), Op::Divide, Expression::Number(r)) => Expression::Number(l / r),
+        (Expression::Number(l), Op::Modulo, Expression::Number(r)) => Expression::Number(l % r),
+        (_, op, _) => Expression::Infix(left.boxed(), op, right.boxed()),
+    }
+}
+
+#[derive(Debug, Clone, Error, PartialEq)]
+pub enum ResolveError {
+    #[error("Cannot redeclare constant {0} in the scope that declared it.")]
+    ConstantRedeclared(Identifier),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve_source(source: &str) -> Result<Program, Vec<ResolveError>> {
+        let tokens = crate::token::generate(source).into_iter().map(|t| t.unwrap()).collect();
+        resolve(crate::parser::parse(tokens).unwrap()).map_err(|errors| errors.into_iter().map(|e| e.node).collect())
+    }
+
+    #[test]
+    fn it_rejects_redeclaring_constants_in_the_same_scope() {
+        assert_eq!(
+            resolve_source("const LIMIT = 10\nlet LIMIT = 20\nconst LIMIT = 30\nfn LIMIT() { 1 }"),
+            Err(vec![ResolveError::ConstantRedeclared("LIMIT".to_string()); 3])
+        );
+        assert_eq!(
+            resolve_source("export const LIMIT = 10\nimport { LIMIT } from \"./other\""),
+            Err(vec![ResolveError::ConstantRedeclared("LIMIT".to_string())])
+        );
+    }
+
+    #[test]
+    fn it_reports_the_span_of_the_redeclaration() {
+        let tokens = crate::token::generate("const LIMIT = 10\nfn f() {\n    const X = |x| { let y = x }\n    let X = 2\n}").into_iter().map(|t| t.unwrap()).collect();
+        let errors = resolve(crate::parser::parse(tokens).unwrap()).unwrap_err();
+
+        assert_eq!(errors.iter().map(|e| e.span.to_string()).collect::<Vec<_>>(), vec!["4:5"]);
+    }
+
+    #[test]
+    fn it_allows_shadowing_constants_in_inner_scopes() {
+        assert!(resolve_source("const LIMIT = 10\nfn f(LIMIT) { let LIMIT = 2 }\nfor LIMIT in [1] { LIMIT }").is_ok());
+        assert!(resolve_source("let limit = 1\nconst limit = 2").is_ok());
+    }
+
+    #[test]
+    fn it_folds_constants_with_literal_values() {
+        let program = resolve_source("const MINUTE = 60\nconst HOUR = MINUTE * 60\nlet x = [HOUR, -MINUTE]").unwrap();
+
+        assert_eq!(program[1], Statement::ConstDeclaration {
+            doc: None,
+            name: "HOUR".to_string(),
+            annotation: None,
+            value: Expression::Number(3600.0).into(),
+        }.into());
+        assert_eq!(program[2], Statement::LetDeclaration {
+            name: "x".to_string(),
+            annotation: None,
+            initial: Expression::List(vec![Expression::Number(3600.0).into(), Expression::Number(-60.0).into()]).some(),
+        }.into());
+    }
+
+    #[test]
+    fn it_folds_every_arithmetic_operator_on_number_literals() {
+        let program = resolve_source("const N = 5\n[1 + 2, N % 2, 7 - 2 * 3, 1 / 4, -N % 3, N + x]").unwrap();
+
+        assert_eq!(program[1], Statement::Expression {
+            expression: Expression::List(vec![
+                Expression::Number(3.0).into(),
+                Expression::Number(1.0).into(),
+                Expression::Number(1.0).into(),
+                Expression::Number(0.25).into(),
+                Expression::Number(-2.0).into(),
+                Expression::Infix(Expression::Number(5.0).boxed(), Op::Add, Expression::Identifier("x".to_string()).boxed()).into(),
+            ]).into(),
+        }.into());
+    }
+
+    #[test]
+    fn it_only_folds_constants_that_are_in_scope() {
+        let program = resolve_source("const N = 1\nconst ITEMS = [N]\nfn f(N) { [N, ITEMS] }").unwrap();
+
+        let expected = Expression::List(vec![
+            Expression::Identifier("N".to_string()).into(),
+            Expression::Identifier("ITEMS".to_string()).into(),
+        ]);
+        match &program[2].node {
+            Statement::FunctionDeclaration { body, .. } => assert_eq!(body[0], Statement::Expression { expression: expected.into() }.into()),
+            statement => panic!("expected a function, found {:?}", statement),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Machine-readable output of tokens and ASTs, for tools written in other
+//! languages. Every document carries the schema version it was written with,
+//! which goes up whenever the shape of the tokens or the AST changes.
+
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::ast::{Program, Spanned};
+use crate::token::Token;
+
+pub mod sexp;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Format {
+    #[default]
+    Json,
+    Sexp,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "json" => Ok(Format::Json),
+            "sexp" => Ok(Format::Sexp),
+            _ => Err(format!("Unknown format {}, expected json or sexp.", s)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Tokens<'a> {
+    version: u32,
+    tokens: &'a [Spanned<Token>],
+}
+
+#[derive(Serialize, Deserialize)]
+struct Ast<P> {
+    version: u32,
+    program: P,
+}
+
+/// Read before the rest of a document, so an old or newer document is
+/// reported by version rather than by whichever node no longer fits.
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+pub fn tokens(tokens: &[Spanned<Token>], format: Format) -> String {
+    write(&Tokens { version: SCHEMA_VERSION, tokens }, format)
+}
+
+pub fn program(program: &Program, format: Format) -> String {
+    write(&Ast { version: SCHEMA_VERSION, program }, format)
+}
+
+/// Reads a program written by `program` in the JSON format. Spans may be left out.
+pub fn from_json(json: &str) -> Result<Program, SerializeError> {
+    let Version { version } = serde_json::from_str(json)?;
+    if version != SCHEMA_VERSION {
+        return Err(SerializeError::UnsupportedVersion(version))
+    }
+
+    let ast: Ast<Program> = serde_json::from_str(json)?;
+    Ok(ast.program)
+}
+
+fn write(document: &impl Serialize, format: Format) -> String {
+    match format {
+        Format::Json => serde_json::to_string_pretty(document).expect("tokens and ASTs can always be written as JSON"),
+        Format::Sexp => sexp::to_string(document).expect("tokens and ASTs can always be written as S-expressions"),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SerializeError {
+    #[error("Invalid AST: {0}")]
+    Invalid(#[from] serde_json::Error),
+    #[error("Unsupported schema version {0}, expected {SCHEMA_VERSION}.")]
+    UnsupportedVersion(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Expression, Statement};
+
+    fn lex(source: &str) -> Vec<Spanned<Token>> {
+        crate::token::generate(source).into_iter().map(|t| t.unwrap()).map(|(token, span)| Spanned::new(token, span)).collect()
+    }
+
+    fn parse_source(source: &str) -> Program {
+        crate::parser::parse(lex(source).into_iter().map(|t| (t.node, t.span)).collect()).unwrap()
+    }
+
+    #[test]
+    fn it_writes_compact_s_expressions() {
+        assert_eq!(
+            tokens(&lex("let x = \"a\\n\""), Format::Sexp),
+            r#"(Tokens :version 1 :tokens [Let (Identifier "x") Assign (String "a\n")])"#
+        );
+        assert_eq!(
+            program(&parse_source("fn f(a, ...b) { f(a + 1, n: b)? }"), Format::Sexp),
+            "(Ast :version 1 :program [(FunctionDeclaration :attributes [] :name \"f\" \
+                :params [(Parameter :name \"a\" :variadic false) (Parameter :name \"b\" :variadic true)] \
+                :body [(Expression :expression (Propagate (Call (Identifier \"f\") \
+                    [(Positional (Infix (Identifier \"a\") Add (Number 1))) (Named \"n\" (Identifier \"b\"))])))] \
+                :kind Normal)])"
+        );
+    }
+
+    #[test]
+    fn it_round_trips_programs_through_json() {
+        let original = parse_source("async fn f(x: List<Number> = [1]) -> Any {\n    try { await g(...x) } catch e { throw e }\n}");
+        let json = program(&original, Format::Json);
+        let read = from_json(&json).unwrap();
+
+        assert_eq!(read, original);
+        assert_eq!(read[0].span, original[0].span);
+        assert!(json.contains("\"version\": 1"));
+    }
+
+    #[test]
+    fn it_reads_programs_without_spans() {
+        let json = r#"{"version": 1, "program": [{"node": {"Expression": {"expression": {"node": {"Number": 2.0}}}}}]}"#;
+
+        assert_eq!(from_json(json).unwrap(), vec![Statement::Expression { expression: Expression::Number(2.0).into() }.into()]);
+    }
+
+    #[test]
+    fn it_rejects_other_schema_versions() {
+        assert!(matches!(from_json(r#"{"version": 0, "program": {"anything": 1}}"#), Err(SerializeError::UnsupportedVersion(0))));
+        assert!(matches!(from_json(r#"{"version": 1, "program": [{"node": 1}]}"#), Err(SerializeError::Invalid(_))));
+    }
+}
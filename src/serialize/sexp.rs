@@ -0,0 +1,314 @@
+//! A compact S-expression form of anything serde can serialize, for snapshot
+//! tests. Enum variants become `(Variant fields...)`, struct fields become
+//! `:name value` pairs and sequences become `[...]`. Fields without a value
+//! are left out, and so are spans, so snapshots don't change when code moves.
+
+use std::fmt::{Display, Formatter};
+
+use serde::ser::{self, Serialize};
+use thiserror::Error;
+
+pub fn to_string<T: Serialize + ?Sized>(value: &T) -> Result<String, Error> {
+    Ok(value.serialize(Serializer)?.to_string())
+}
+
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct Error(String);
+
+impl ser::Error for Error {
+    fn custom<T: Display>(message: T) -> Self {
+        Error(message.to_string())
+    }
+}
+
+enum Sexp {
+    Nil,
+    Atom(String),
+    List(Vec<Sexp>),
+    Vector(Vec<Sexp>),
+}
+
+impl Display for Sexp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let join = |items: &[Sexp]| items.iter().map(|item| item.to_string()).collect::<Vec<_>>().join(" ");
+        match self {
+            Sexp::Nil => write!(f, "nil"),
+            Sexp::Atom(atom) => write!(f, "{}", atom),
+            Sexp::List(items) => write!(f, "({})", join(items)),
+            Sexp::Vector(items) => write!(f, "[{}]", join(items)),
+        }
+    }
+}
+
+struct Serializer;
+
+/// A sequence, tuple, map or struct being built. `head` names the variant or
+/// struct, and a `Spanned` struct collapses into the node it wraps.
+struct Compound {
+    head: Option<&'static str>,
+    items: Vec<Sexp>,
+    vector: bool,
+    spanned: bool,
+}
+
+impl Compound {
+    fn list(head: Option<&'static str>) -> Self {
+        Self { head, items: Vec::new(), vector: false, spanned: false }
+    }
+
+    fn vector() -> Self {
+        Self { vector: true, ..Self::list(None) }
+    }
+
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.items.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        match value.serialize(Serializer)? {
+            value if self.spanned => if key == "node" {
+                self.items.push(value)
+            },
+            Sexp::Nil => {},
+            value => {
+                self.items.push(Sexp::Atom(format!(":{}", key)));
+                self.items.push(value);
+            },
+        }
+        Ok(())
+    }
+
+    fn end(mut self) -> Result<Sexp, Error> {
+        Ok(if self.spanned {
+            self.items.pop().unwrap_or(Sexp::Nil)
+        } else if self.vector {
+            Sexp::Vector(self.items)
+        } else {
+            Sexp::List(self.head.map(|head| Sexp::Atom(head.to_string())).into_iter().chain(self.items).collect())
+        })
+    }
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Sexp;
+    type Error = Error;
+    type SerializeSeq = Compound;
+    type SerializeTuple = Compound;
+    type SerializeTupleStruct = Compound;
+    type SerializeTupleVariant = Compound;
+    type SerializeMap = Compound;
+    type SerializeStruct = Compound;
+    type SerializeStructVariant = Compound;
+
+    fn serialize_bool(self, v: bool) -> Result<Sexp, Error> {
+        Ok(Sexp::Atom(v.to_string()))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Sexp, Error> {
+        Ok(Sexp::Atom(v.to_string()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Sexp, Error> {
+        Ok(Sexp::Atom(v.to_string()))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Sexp, Error> {
+        Ok(Sexp::Atom(v.to_string()))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Sexp, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Sexp, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Sexp, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Sexp, Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Sexp, Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Sexp, Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Sexp, Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_char(self, v: char) -> Result<Sexp, Error> {
+        self.serialize_str(&v.to_string())
+    }
+
+    /// Strings are quoted and escaped the same way JSON does it.
+    fn serialize_str(self, v: &str) -> Result<Sexp, Error> {
+        serde_json::to_string(v).map(Sexp::Atom).map_err(ser::Error::custom)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Sexp, Error> {
+        Ok(Sexp::Vector(v.iter().map(|b| Sexp::Atom(b.to_string())).collect()))
+    }
+
+    fn serialize_none(self) -> Result<Sexp, Error> {
+        Ok(Sexp::Nil)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Sexp, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Sexp, Error> {
+        Ok(Sexp::Nil)
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Sexp, Error> {
+        Ok(Sexp::Atom(name.to_string()))
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<Sexp, Error> {
+        Ok(Sexp::Atom(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<Sexp, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32, variant: &'static str, value: &T) -> Result<Sexp, Error> {
+        let mut compound = Compound::list(Some(variant));
+        compound.push(value)?;
+        compound.end()
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Compound, Error> {
+        Ok(Compound::vector())
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Compound, Error> {
+        Ok(Compound::vector())
+    }
+
+    fn serialize_tuple_struct(self, name: &'static str, _: usize) -> Result<Compound, Error> {
+        Ok(Compound::list(Some(name)))
+    }
+
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize) -> Result<Compound, Error> {
+        Ok(Compound::list(Some(variant)))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Compound, Error> {
+        Ok(Compound::list(None))
+    }
+
+    fn serialize_struct(self, name: &'static str, _: usize) -> Result<Compound, Error> {
+        Ok(Compound { spanned: name == "Spanned", ..Compound::list(Some(name)) })
+    }
+
+    fn serialize_struct_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize) -> Result<Compound, Error> {
+        Ok(Compound::list(Some(variant)))
+    }
+}
+
+impl ser::SerializeSeq for Compound {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTuple for Compound {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeMap for Compound {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.push(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStruct for Compound {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for Compound {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        Compound::end(self)
+    }
+}
@@ -1,8 +1,387 @@
-use crate::interpreter::Interpreter;
-use crate::environment::Value;
-
-pub fn println(_: &mut Interpreter, args: Vec<Value>) -> Option<Value> {
-    let arg = args.get(0).unwrap().clone();
-    println!("{}", arg.to_string());
-    None
-}
\ No newline at end of file
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::process::Command;
+use std::rc::Rc;
+
+use crate::interpreter::{Interpreter, RuntimeError};
+use crate::environment::{Documentation, StructType, Value};
+use crate::iterator::{advance, iterate, Range, Sequence};
+
+pub fn print(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let text = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().join(" ");
+    print!("{}", text);
+    std::io::stdout().flush().map_err(|error| interpreter.raise("IOError", error.to_string()))?;
+    Ok(Value::Nil)
+}
+
+pub fn println(_: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [arg] = args.as_slice() else {
+        return Err(RuntimeError::ArityMismatch { function: "println".to_string(), params: "value".to_string(), given: args.len() })
+    };
+    println!("{}", arg);
+    Ok(Value::Nil)
+}
+
+pub fn error(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut args = args.into_iter();
+    let message = args.next().map(|m| m.to_string()).unwrap_or_default();
+    let kind = args.next().map(|k| k.to_string()).unwrap_or_else(|| "Error".to_string());
+    Ok(interpreter.error_value(kind, message))
+}
+
+pub fn read_file(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let path = match args.first() {
+        Some(Value::String(path)) => path.clone(),
+        _ => return Err(interpreter.raise("TypeError", "read_file expects a path string")),
+    };
+
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => Ok(Value::String(contents)),
+        Err(error) => Err(interpreter.raise("IOError", format!("{}: {}", path, error))),
+    }
+}
+
+/// Opens a file to read, or with mode "w" to write over it or "a" to append
+/// to it. The file has `read`, `write` and `close` methods, so it can be used
+/// with `with`, and can't be read or written once it's closed.
+pub fn open(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let (path, mode) = match (args.first(), args.get(1)) {
+        (Some(Value::String(path)), None) => (path.clone(), "r"),
+        (Some(Value::String(path)), Some(Value::String(mode))) => (path.clone(), mode.as_str()),
+        _ => return Err(interpreter.raise("TypeError", "open expects a path string and an optional mode")),
+    };
+
+    let mut options = std::fs::OpenOptions::new();
+    match mode {
+        "r" => options.read(true),
+        "w" => options.write(true).create(true).truncate(true),
+        "a" => options.append(true).create(true),
+        mode => return Err(interpreter.raise("ValueError", format!("unknown file mode {:?}, expected \"r\", \"w\" or \"a\"", mode))),
+    };
+    let file = match options.open(&path) {
+        Ok(file) => Rc::new(RefCell::new(Some(file))),
+        Err(error) => return Err(interpreter.raise("IOError", format!("{}: {}", path, error))),
+    };
+
+    let operation = |name: &str, documentation: Documentation, operation: fn(&mut File, Vec<Value>) -> std::io::Result<Value>| {
+        let (file, path) = (file.clone(), path.clone());
+        (name.to_string(), Value::NativeClosure {
+            name: name.to_string(),
+            documentation: Rc::new(documentation),
+            callback: Rc::new(move |interpreter, args, _| match file.borrow_mut().as_mut().map(|file| operation(file, args)) {
+                Some(Ok(value)) => Ok(value),
+                Some(Err(error)) => Err(interpreter.raise("IOError", format!("{}: {}", path, error))),
+                None => Err(interpreter.raise("ValueError", format!("{} is closed", path))),
+            }),
+        })
+    };
+    let mut methods = HashMap::from([
+        operation("read", Documentation::new("self", "The rest of the file, as a string."), |file, _| {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            Ok(Value::String(contents))
+        }),
+        operation("write", Documentation::new("self, text", "Writes a value to the file as text."), |file, args| {
+            let text = args.get(1).map(|text| text.to_string()).unwrap_or_default();
+            file.write_all(text.as_bytes())?;
+            Ok(Value::Nil)
+        }),
+    ]);
+    methods.insert("close".to_string(), Value::NativeClosure {
+        name: "close".to_string(),
+        documentation: Rc::new(Documentation::new("self", "Closes the file. Closing it again does nothing.")),
+        callback: Rc::new(move |_, _, _| {
+            file.borrow_mut().take();
+            Ok(Value::Nil)
+        }),
+    });
+
+    let ty = StructType { name: "File".to_string(), doc: Some("A file opened with `open`.".to_string()), fields: vec!["path".to_string()], methods: RefCell::new(methods), traits: RefCell::default() };
+    Ok(Value::Instance { ty: Rc::new(ty), fields: vec![Value::String(path)] })
+}
+
+pub fn len(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    match args.first() {
+        Some(Value::List(items)) => Ok(Value::Number(items.len() as f64)),
+        Some(Value::Map(entries)) => Ok(Value::Number(entries.len() as f64)),
+        Some(Value::String(string)) => Ok(Value::Number(string.chars().count() as f64)),
+        _ => Err(interpreter.raise("TypeError", "len expects a list, map or string")),
+    }
+}
+
+pub fn split(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    match (args.first(), args.get(1)) {
+        (Some(Value::String(string)), Some(Value::String(separator))) => Ok(Value::List(
+            string.split(separator.as_str()).map(|s| Value::String(s.to_string())).collect()
+        )),
+        _ => Err(interpreter.raise("TypeError", "split expects a string and a separator")),
+    }
+}
+
+/// Maps over a list eagerly, or over an iterator or range lazily.
+pub fn map(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut args = args.into_iter();
+    match (args.next(), args.next()) {
+        (Some(Value::List(items)), Some(callback)) => {
+            let mut mapped = Vec::new();
+            for item in items {
+                mapped.push(interpreter.call(callback.clone(), vec![item], Vec::new())?);
+            }
+            Ok(Value::List(mapped))
+        },
+        (Some(source @ (Value::Iterator(_) | Value::Range(_))), Some(callback)) => {
+            let source = iterate(source, interpreter)?;
+            Ok(Value::Iterator(Rc::new(RefCell::new(Sequence::Map(source, callback)))))
+        },
+        _ => Err(interpreter.raise("TypeError", "map expects a list or iterator and a function")),
+    }
+}
+
+/// Takes the first `n` values of a list eagerly, or of an iterator or range lazily.
+pub fn take(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut args = args.into_iter();
+    match (args.next(), args.next()) {
+        (Some(Value::List(items)), Some(Value::Number(n))) => {
+            Ok(Value::List(items.into_iter().take(n as usize).collect()))
+        },
+        (Some(source @ (Value::Iterator(_) | Value::Range(_))), Some(Value::Number(n))) => {
+            let source = iterate(source, interpreter)?;
+            Ok(Value::Iterator(Rc::new(RefCell::new(Sequence::Take(source, n as usize)))))
+        },
+        _ => Err(interpreter.raise("TypeError", "take expects a list or iterator and a number")),
+    }
+}
+
+pub fn collect(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let iterable = match args.into_iter().next() {
+        Some(iterable) => iterable,
+        None => return Err(interpreter.raise("TypeError", "collect expects a list or iterator")),
+    };
+
+    let sequence = iterate(iterable, interpreter)?;
+    let mut items = Vec::new();
+    while let Some(item) = advance(&sequence, interpreter)? {
+        items.push(item);
+    }
+    Ok(Value::List(items))
+}
+
+/// `(a..b).step(k)` counts through a range `k` at a time. A range counting
+/// down, such as `(10..0).step(-2)`, needs a negative step.
+pub fn step(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    match (args.first(), args.get(1)) {
+        (Some(Value::Range(_)), Some(Value::Number(step))) if *step == 0.0 || step.is_nan() => {
+            Err(interpreter.raise("ValueError", "step must be a non-zero number"))
+        },
+        (Some(Value::Range(range)), Some(Value::Number(step))) => Ok(Value::Range(Range { step: *step, ..*range })),
+        _ => Err(interpreter.raise("TypeError", "step expects a range and a number")),
+    }
+}
+
+/// Reverses a list or string, or a range without producing its numbers.
+pub fn reverse(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    match args.into_iter().next() {
+        Some(Value::Range(range)) => Ok(Value::Range(range.reverse())),
+        Some(Value::List(mut items)) => {
+            items.reverse();
+            Ok(Value::List(items))
+        },
+        Some(Value::String(string)) => Ok(Value::String(string.chars().rev().collect())),
+        _ => Err(interpreter.raise("TypeError", "reverse expects a list, string or range")),
+    }
+}
+
+/// A promise that resolves to `ms` once that many milliseconds have passed.
+pub fn sleep(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    match args.first() {
+        Some(Value::Number(ms)) => Ok(Value::Promise(interpreter.events().borrow_mut().sleep(*ms as u64, Value::Number(*ms)))),
+        _ => Err(interpreter.raise("TypeError", "sleep expects a number of milliseconds")),
+    }
+}
+
+/// Calls a function after `ms` milliseconds, returning a promise of its result.
+pub fn set_timeout(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut args = args.into_iter();
+    match (args.next(), args.next()) {
+        (Some(callback), Some(Value::Number(ms))) => {
+            let task = interpreter.coroutine(None, move |interpreter| interpreter.call(callback, Vec::new(), Vec::new()));
+            Ok(Value::Promise(interpreter.events().borrow_mut().schedule(task, ms as u64)))
+        },
+        _ => Err(interpreter.raise("TypeError", "set_timeout expects a function and a number of milliseconds")),
+    }
+}
+
+/// A promise of the values of every promise in a list, rejected as soon as any of them is.
+pub fn all(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let promises = match args.into_iter().next() {
+        Some(Value::List(promises)) => promises,
+        _ => return Err(interpreter.raise("TypeError", "all expects a list of promises")),
+    };
+
+    let task = interpreter.coroutine(None, move |interpreter| {
+        let mut values = Vec::new();
+        for promise in promises {
+            values.push(interpreter.await_value(promise)?);
+        }
+        Ok(Value::List(values))
+    });
+    Ok(Value::Promise(interpreter.events().borrow_mut().spawn(task)))
+}
+
+/// Runs a program with a list of arguments, returning a promise of a map of its
+/// exit `status`, `stdout` and `stderr`. Other tasks keep running meanwhile.
+pub fn exec(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let (program, arguments) = match (args.first(), args.get(1)) {
+        (Some(Value::String(program)), None) => (program, &[][..]),
+        (Some(Value::String(program)), Some(Value::List(arguments))) => (program, arguments.as_slice()),
+        _ => return Err(interpreter.raise("TypeError", "exec expects a program and a list of arguments")),
+    };
+
+    let mut command = Command::new(program);
+    command.args(arguments.iter().map(|argument| argument.to_string()));
+    Ok(Value::Promise(interpreter.events().borrow_mut().exec(command)))
+}
+
+pub fn now(interpreter: &mut Interpreter, _: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(interpreter.events().borrow().now() as f64))
+}
+
+/// `@memoize`: remembers what a function returned for each list of arguments,
+/// calling it again only for arguments it hasn't seen.
+pub fn memoize(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let function = decorated(interpreter, "memoize", args)?;
+    let cache: RefCell<HashMap<String, Value>> = RefCell::default();
+
+    Ok(Value::NativeClosure {
+        name: function.function_name().unwrap_or_default().to_string(),
+        documentation: Rc::new(function.documentation().unwrap_or_default()),
+        callback: Rc::new(move |interpreter, args, named| {
+            let key = args.iter().map(cache_key)
+                .chain(named.iter().map(|(name, value)| cache_key(value).map(|key| format!("{}={}", name, key))))
+                .collect::<Option<Vec<_>>>()
+                .map(|keys| keys.join(","));
+            if let Some(value) = key.as_ref().and_then(|key| cache.borrow().get(key).cloned()) {
+                return Ok(value)
+            }
+
+            let value = interpreter.call(function.clone(), args, named)?;
+            if let Some(key) = key {
+                cache.borrow_mut().insert(key, value.clone());
+            }
+            Ok(value)
+        }),
+    })
+}
+
+/// `@trace`: reports every call of a function and what it returned on stderr.
+pub fn trace(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let function = decorated(interpreter, "trace", args)?;
+    let name = function.function_name().unwrap_or_default().to_string();
+
+    Ok(Value::NativeClosure {
+        name: name.clone(),
+        documentation: Rc::new(function.documentation().unwrap_or_default()),
+        callback: Rc::new(move |interpreter, args, named| {
+            let arguments = args.iter().map(|a| a.to_string())
+                .chain(named.iter().map(|(name, value)| format!("{}: {}", name, value)))
+                .collect::<Vec<_>>();
+            eprintln!("-> {}({})", name, arguments.join(", "));
+            let value = interpreter.call(function.clone(), args, named);
+            match &value {
+                Ok(Value::Nil) => eprintln!("<- {} returned", name),
+                Ok(value) => eprintln!("<- {} returned {}", name, value),
+                Err(error) => eprintln!("<- {} failed: {}", name, error),
+            }
+            value
+        }),
+    })
+}
+
+/// `@deprecated` or `@deprecated("use x")`: warns on stderr the first time a function is called.
+pub fn deprecated(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    match args.first() {
+        Some(Value::String(message)) => {
+            let message = message.clone();
+            Ok(Value::NativeClosure {
+                name: String::from("deprecated"),
+                documentation: Rc::new(Documentation::new("f", "Decorator warning on stderr the first time a function is called.")),
+                callback: Rc::new(move |interpreter, args, _| Ok(warn_once(decorated(interpreter, "deprecated", args)?, Some(message.clone())))),
+            })
+        },
+        _ => Ok(warn_once(decorated(interpreter, "deprecated", args)?, None)),
+    }
+}
+
+fn warn_once(function: Value, message: Option<String>) -> Value {
+    let name = function.function_name().unwrap_or_default().to_string();
+    let warned = Cell::new(false);
+
+    Value::NativeClosure {
+        name: name.clone(),
+        documentation: Rc::new(function.documentation().unwrap_or_default()),
+        callback: Rc::new(move |interpreter, args, named| {
+            if !warned.replace(true) {
+                match &message {
+                    Some(message) => eprintln!("warning: {} is deprecated: {}", name, message),
+                    None => eprintln!("warning: {} is deprecated", name),
+                }
+            }
+            interpreter.call(function.clone(), args, named)
+        }),
+    }
+}
+
+/// `@test`: marks a function to be run by `crustacean test`, leaving it unchanged.
+pub fn test(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let function = decorated(interpreter, "test", args)?;
+    interpreter.register_test(function.clone());
+    Ok(function)
+}
+
+/// Prints how a function is called, or what fields a struct has, followed by its doc comment.
+pub fn help(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let value = args.into_iter().next().unwrap_or(Value::Nil);
+    let (header, documentation) = match (&value, value.documentation()) {
+        (Value::Struct(ty), Some(documentation)) if ty.fields.is_empty() => (format!("struct {} {{}}", ty.name), documentation),
+        (Value::Struct(ty), Some(documentation)) => (format!("struct {} {{ {} }}", ty.name, documentation.signature), documentation),
+        (_, Some(documentation)) => (format!("fn {}({})", value.function_name().unwrap_or_default(), documentation.signature), documentation),
+        (_, None) => return Err(interpreter.raise("TypeError", format!("help expects a function or struct, not {}", value.type_name()))),
+    };
+
+    println!("{}", header);
+    for line in documentation.doc.iter().flat_map(|doc| doc.lines()) {
+        println!("    {}", line);
+    }
+    Ok(Value::Nil)
+}
+
+/// The doc comment of a function or struct, or `nil` when it wasn't given one.
+pub fn doc(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let value = args.into_iter().next().unwrap_or(Value::Nil);
+    match value.documentation() {
+        Some(documentation) => Ok(documentation.doc.map(Value::String).unwrap_or(Value::Nil)),
+        None => Err(interpreter.raise("TypeError", format!("doc expects a function or struct, not {}", value.type_name()))),
+    }
+}
+
+/// The function a decorator was applied to.
+fn decorated(interpreter: &mut Interpreter, decorator: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    match args.into_iter().next() {
+        Some(function) if function.function_name().is_some() => Ok(function),
+        _ => Err(interpreter.raise("TypeError", format!("{} expects a function", decorator))),
+    }
+}
+
+/// Identifies a value by its contents and type, so `1` and `"1"` are cached apart.
+/// Values without a stable identity, like iterators and functions, can't be cached.
+fn cache_key(value: &Value) -> Option<String> {
+    match value {
+        Value::Number(n) => Some(format!("n{}", n)),
+        Value::String(s) => Some(format!("s{:?}", s)),
+        Value::Nil => Some(String::from("nil")),
+        Value::List(items) => items.iter().map(cache_key).collect::<Option<Vec<_>>>().map(|keys| format!("[{}]", keys.join(","))),
+        _ => None,
+    }
+}
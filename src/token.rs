@@ -1,7 +1,26 @@
+use std::fmt::{Display, Formatter};
+
 use logos::{Lexer, Logos};
+use serde::{Deserialize, Serialize};
+
+use crate::ast::Span;
 
-pub fn generate(input: &str) -> Vec<Result<Token, ()>> {
-    Token::lexer(input).collect()
+/// Lexes the source into tokens, along with the span each one was read from.
+/// A `#!` line at the very start, which lets a script run as an executable, is skipped.
+/// Text that isn't a token is an error spanning it.
+pub fn generate(input: &str) -> Vec<Result<(Token, Span), Span>> {
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(input.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let shebang = if input.starts_with("#!") { input.find('\n').unwrap_or(input.len()) } else { 0 };
+
+    Token::lexer(&input[shebang..]).spanned().map(|(token, range)| {
+        let range = range.start + shebang..range.end + shebang;
+        let line = line_starts.partition_point(|&start| start <= range.start);
+        let column = input[line_starts[line - 1]..range.start].chars().count() + 1;
+        let span = Span { start: range.start, end: range.end, line, column };
+        token.map(|token| (token, span)).map_err(|_| span)
+    }).collect()
 }
 
 fn to_string(lex: &mut Lexer<Token>) -> Option<String> {
@@ -14,26 +33,110 @@ fn to_string(lex: &mut Lexer<Token>) -> Option<String> {
     Some(string)
 }
 
+/// Reads a string literal, turning `\n`, `\t`, `\r`, `\"` and `\\` into the
+/// characters they stand for. Any other escaped character is kept as written.
+fn to_unescaped(lex: &mut Lexer<Token>) -> Option<String> {
+    let slice = lex.slice();
+    let mut string = String::new();
+    let mut chars = slice[1..slice.len() - 1].chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => string.push('\n'),
+            Some('t') => string.push('\t'),
+            Some('r') => string.push('\r'),
+            Some(c @ ('"' | '\\')) => string.push(c),
+            Some(c) => {
+                string.push('\\');
+                string.push(c);
+            },
+            None => string.push('\\'),
+        }
+    }
+    Some(string)
+}
+
+/// Reads a `///` comment, without the slashes and the space after them.
+fn to_doc(lex: &mut Lexer<Token>) -> Option<String> {
+    let text = &lex.slice()[3..];
+    Some(text.strip_prefix(' ').unwrap_or(text).to_string())
+}
+
 fn to_float(lex:&mut Lexer<Token>) -> Option<f64> {
-    Some(lex.slice().parse().ok()?)
+    lex.slice().parse().ok()
 }
 
-#[derive(Debug, Clone, Logos, PartialEq)]
+#[derive(Debug, Clone, Logos, PartialEq, Serialize, Deserialize)]
 #[logos(skip r"[ \t\n\f]+")]
 pub enum Token {
     #[token("fn")]
     Fn,
     #[token("let")]
     Let,
+    #[token("const")]
+    Const,
     #[token("if")]
     If,
     #[token("else")]
     Else,
     #[token("while")]
     While,
-    #[regex(r"[a-zA-Z_?]+", to_string)]
+    #[token("return")]
+    Return,
+    #[token("break")]
+    Break,
+    #[token("import")]
+    Import,
+    #[token("export")]
+    Export,
+    #[token("as")]
+    As,
+    #[token("from")]
+    From,
+    #[token("for")]
+    For,
+    #[token("in")]
+    In,
+    #[token("yield")]
+    Yield,
+    #[token("async")]
+    Async,
+    #[token("await")]
+    Await,
+    #[token("throw")]
+    Throw,
+    #[token("try")]
+    Try,
+    #[token("catch")]
+    Catch,
+    #[token("finally")]
+    Finally,
+    #[token("defer")]
+    Defer,
+    #[token("with")]
+    With,
+    #[token("macro")]
+    Macro,
+    #[token("struct")]
+    Struct,
+    #[token("impl")]
+    Impl,
+    #[token("trait")]
+    Trait,
+    #[token("implements")]
+    Implements,
+    #[token("nil")]
+    Nil,
+    #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*", to_string)]
     Identifier(String),
-    #[regex(r##""(?:[^"\\]|\\.)*""##, to_string)]
+    /// A line of a `///` comment, documenting the declaration after it.
+    #[regex(r"///[^\n]*", to_doc)]
+    DocComment(String),
+    #[regex(r##""(?:[^"\\]|\\.)*""##, to_unescaped)]
     String(String),
     #[regex(r"([0-9]+[.])?[0-9]+", to_float)]
     Number(f64),
@@ -45,6 +148,10 @@ pub enum Token {
     LeftBrace,
     #[token("}")]
     RightBrace,
+    #[token("[")]
+    LeftBracket,
+    #[token("]")]
+    RightBracket,
     #[token("true")]
     True,
     #[token("false")]
@@ -59,31 +166,179 @@ pub enum Token {
     Asterisk,
     #[token("/")]
     Slash,
+    #[token("%")]
+    Percent,
+    #[token("!")]
+    Bang,
+    #[token(",")]
+    Comma,
+    #[token(".")]
+    Dot,
+    #[token("|>")]
+    Pipe,
+    #[token("|")]
+    Bar,
+    #[token("=>")]
+    FatArrow,
+    #[token("...")]
+    Ellipsis,
+    #[token("..")]
+    DotDot,
+    #[token("..=")]
+    DotDotEqual,
+    #[token(":")]
+    Colon,
+    #[token("->")]
+    Arrow,
+    #[token("?")]
+    Question,
+    #[token("?.")]
+    QuestionDot,
+    #[token("??")]
+    QuestionQuestion,
+    #[token("<")]
+    LessThan,
+    #[token(">")]
+    GreaterThan,
+    #[token("<=")]
+    LessEqual,
+    #[token(">=")]
+    GreaterEqual,
+    #[token("==")]
+    Equal,
+    #[token("!=")]
+    NotEqual,
+    #[token("$")]
+    Dollar,
+    #[token("@")]
+    At,
+    InterpolatedString(String),
+    Eof,
     // #[error]
     // #[regex(r"[\t\n\f]+", logos::skip)]
     // Error,
 }
 
+/// Writes a token the way it would appear in source.
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let source = match self {
+            Token::Identifier(name) => name,
+            Token::DocComment(text) => return write!(f, "/// {}", text),
+            Token::String(s) | Token::InterpolatedString(s) => return write!(f, "{}", crate::ast::print::quote(s)),
+            Token::Number(n) => return write!(f, "{}", n),
+            Token::Fn => "fn",
+            Token::Let => "let",
+            Token::Const => "const",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::While => "while",
+            Token::Return => "return",
+            Token::Break => "break",
+            Token::Import => "import",
+            Token::Export => "export",
+            Token::As => "as",
+            Token::From => "from",
+            Token::For => "for",
+            Token::In => "in",
+            Token::Yield => "yield",
+            Token::Async => "async",
+            Token::Await => "await",
+            Token::Throw => "throw",
+            Token::Try => "try",
+            Token::Catch => "catch",
+            Token::Finally => "finally",
+            Token::Defer => "defer",
+            Token::With => "with",
+            Token::Macro => "macro",
+            Token::Struct => "struct",
+            Token::Impl => "impl",
+            Token::Trait => "trait",
+            Token::Implements => "implements",
+            Token::Nil => "nil",
+            Token::LeftParen => "(",
+            Token::RightParen => ")",
+            Token::LeftBrace => "{",
+            Token::RightBrace => "}",
+            Token::LeftBracket => "[",
+            Token::RightBracket => "]",
+            Token::True => "true",
+            Token::False => "false",
+            Token::Assign => "=",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Asterisk => "*",
+            Token::Slash => "/",
+            Token::Percent => "%",
+            Token::Bang => "!",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Pipe => "|>",
+            Token::Bar => "|",
+            Token::FatArrow => "=>",
+            Token::Ellipsis => "...",
+            Token::DotDot => "..",
+            Token::DotDotEqual => "..=",
+            Token::Colon => ":",
+            Token::Arrow => "->",
+            Token::Question => "?",
+            Token::QuestionDot => "?.",
+            Token::QuestionQuestion => "??",
+            Token::LessThan => "<",
+            Token::GreaterThan => ">",
+            Token::LessEqual => "<=",
+            Token::GreaterEqual => ">=",
+            Token::Equal => "==",
+            Token::NotEqual => "!=",
+            Token::Dollar => "$",
+            Token::At => "@",
+            Token::Eof => "",
+        };
+        write!(f, "{}", source)
+    }
+}
+
+impl From<Token> for String {
+    fn from(token: Token) -> Self {
+        match token {
+            Token::Identifier(s) | Token::String(s) | Token::InterpolatedString(s) => s,
+            _ => unreachable!()
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn it_can_recognise_reserved_keywords() {
-        let mut lexer = Token::lexer("fn let true false if else while");
+        let mut lexer = Token::lexer("fn let const true false if else while return break");
         assert_eq!(lexer.next(), Some(Ok(Token::Fn)));
         assert_eq!(lexer.next(), Some(Ok(Token::Let)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Const)));
         assert_eq!(lexer.next(), Some(Ok(Token::True)));
         assert_eq!(lexer.next(), Some(Ok(Token::False)));
         assert_eq!(lexer.next(), Some(Ok(Token::If)));
         assert_eq!(lexer.next(), Some(Ok(Token::Else)));
         assert_eq!(lexer.next(), Some(Ok(Token::While)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Return)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Break)));
+    }
+
+    #[test]
+    fn it_can_recognise_module_keywords() {
+        let mut lexer = Token::lexer("import export as from");
+        assert_eq!(lexer.next(), Some(Ok(Token::Import)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Export)));
+        assert_eq!(lexer.next(), Some(Ok(Token::As)));
+        assert_eq!(lexer.next(), Some(Ok(Token::From)));
     }
 
     #[test]
 
     fn it_can_recognise_symbols() {
-        let mut lexer = Token::lexer("( ) { } +-*/=");
+        let mut lexer = Token::lexer("( ) { } +-*/= % ! , . |>");
 
         assert_eq!(lexer.next(), Some(Ok(Token::LeftParen)));
         assert_eq!(lexer.next(), Some(Ok(Token::RightParen)));
@@ -94,16 +349,80 @@ mod tests {
         assert_eq!(lexer.next(), Some(Ok(Token::Asterisk)));
         assert_eq!(lexer.next(), Some(Ok(Token::Slash)));
         assert_eq!(lexer.next(), Some(Ok(Token::Assign)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Percent)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Bang)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Comma)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Dot)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Pipe)));
+    }
+
+    #[test]
+    fn it_can_recognise_parameter_symbols() {
+        let mut lexer = Token::lexer("|x| => ...xs : [ ]");
+
+        assert_eq!(lexer.next(), Some(Ok(Token::Bar)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Identifier("x".to_owned()))));
+        assert_eq!(lexer.next(), Some(Ok(Token::Bar)));
+        assert_eq!(lexer.next(), Some(Ok(Token::FatArrow)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Ellipsis)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Identifier("xs".to_owned()))));
+        assert_eq!(lexer.next(), Some(Ok(Token::Colon)));
+        assert_eq!(lexer.next(), Some(Ok(Token::LeftBracket)));
+        assert_eq!(lexer.next(), Some(Ok(Token::RightBracket)));
+    }
+
+    #[test]
+    fn it_can_recognise_generator_keywords() {
+        let mut lexer = Token::lexer("fn* for in yield");
+        assert_eq!(lexer.next(), Some(Ok(Token::Fn)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Asterisk)));
+        assert_eq!(lexer.next(), Some(Ok(Token::For)));
+        assert_eq!(lexer.next(), Some(Ok(Token::In)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Yield)));
+    }
+
+    #[test]
+    fn it_can_recognise_async_keywords() {
+        let mut lexer = Token::lexer("async fn await");
+        assert_eq!(lexer.next(), Some(Ok(Token::Async)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Fn)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Await)));
+    }
+
+    #[test]
+    fn it_can_recognise_exception_keywords() {
+        let mut lexer = Token::lexer("throw try catch finally f()?");
+        assert_eq!(lexer.next(), Some(Ok(Token::Throw)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Try)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Catch)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Finally)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Identifier("f".to_owned()))));
+        assert_eq!(lexer.next(), Some(Ok(Token::LeftParen)));
+        assert_eq!(lexer.next(), Some(Ok(Token::RightParen)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Question)));
+    }
+
+    #[test]
+    fn it_can_recognise_type_annotation_symbols() {
+        let mut lexer = Token::lexer("-> < > -");
+
+        assert_eq!(lexer.next(), Some(Ok(Token::Arrow)));
+        assert_eq!(lexer.next(), Some(Ok(Token::LessThan)));
+        assert_eq!(lexer.next(), Some(Ok(Token::GreaterThan)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Minus)));
     }
 
     #[test]
     fn it_can_recognise_identifiers() {
-        let mut lexer = Token::lexer("hello_world HelloWorld hello_world? helloWorld");
+        let mut lexer = Token::lexer("hello_world HelloWorld _x2 helloWorld? 2x");
 
         assert_eq!(lexer.next(), Some(Ok(Token::Identifier("hello_world".to_owned()))));
         assert_eq!(lexer.next(), Some(Ok(Token::Identifier("HelloWorld".to_owned()))));
-        assert_eq!(lexer.next(), Some(Ok(Token::Identifier("hello_world?".to_owned()))));
+        assert_eq!(lexer.next(), Some(Ok(Token::Identifier("_x2".to_owned()))));
         assert_eq!(lexer.next(), Some(Ok(Token::Identifier("helloWorld".to_owned()))));
+        assert_eq!(lexer.next(), Some(Ok(Token::Question)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Number(2.0))));
+        assert_eq!(lexer.next(), Some(Ok(Token::Identifier("x".to_owned()))));
     }
 
     #[test]
@@ -114,12 +433,66 @@ mod tests {
         assert_eq!(lexer.next(), Some(Ok(Token::Number(6789.01))))
     }
 
+    #[test]
+    fn it_can_recognise_ranges_between_numbers() {
+        let mut lexer = Token::lexer("1..10 0.5..=2 ...");
+
+        assert_eq!(lexer.next(), Some(Ok(Token::Number(1.0))));
+        assert_eq!(lexer.next(), Some(Ok(Token::DotDot)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Number(10.0))));
+        assert_eq!(lexer.next(), Some(Ok(Token::Number(0.5))));
+        assert_eq!(lexer.next(), Some(Ok(Token::DotDotEqual)));
+        assert_eq!(lexer.next(), Some(Ok(Token::Number(2.0))));
+        assert_eq!(lexer.next(), Some(Ok(Token::Ellipsis)));
+    }
+
+    #[test]
+    fn it_strips_one_space_from_doc_comments() {
+        let tokens: Vec<Token> = generate("///  Doc.\n///\nfn").into_iter().map(|t| t.unwrap().0).collect();
+
+        assert_eq!(tokens, vec![Token::DocComment(" Doc.".to_owned()), Token::DocComment(String::new()), Token::Fn]);
+    }
+
+    #[test]
+    fn it_can_recognise_optional_chains() {
+        let tokens: Vec<Token> = generate("a?.b ok? .c nil?.d f()?.[0] x ?? y").into_iter().map(|t| t.unwrap().0).collect();
+
+        assert_eq!(tokens, vec![
+            Token::Identifier("a".to_owned()), Token::QuestionDot, Token::Identifier("b".to_owned()),
+            Token::Identifier("ok".to_owned()), Token::Question, Token::Dot, Token::Identifier("c".to_owned()),
+            Token::Nil, Token::QuestionDot, Token::Identifier("d".to_owned()),
+            Token::Identifier("f".to_owned()), Token::LeftParen, Token::RightParen, Token::QuestionDot,
+            Token::LeftBracket, Token::Number(0.0), Token::RightBracket,
+            Token::Identifier("x".to_owned()), Token::QuestionQuestion, Token::Identifier("y".to_owned()),
+        ]);
+        assert_eq!(generate("ab?.c")[1], Ok((Token::QuestionDot, Span { start: 2, end: 4, line: 1, column: 3 })));
+    }
+
     #[test]
     fn it_can_recognise_strings() {
         let mut lexer = Token::lexer(r##""testing" "testing with \"" "testing\n""##);
 
         assert_eq!(lexer.next(), Some(Ok(Token::String(r##"testing"##.to_owned()))));
-        assert_eq!(lexer.next(), Some(Ok(Token::String(r##"testing with \""##.to_owned()))));
-        assert_eq!(lexer.next(), Some(Ok(Token::String(r##"testing\n"##.to_owned()))));
+        assert_eq!(lexer.next(), Some(Ok(Token::String("testing with \"".to_owned()))));
+        assert_eq!(lexer.next(), Some(Ok(Token::String("testing\n".to_owned()))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn it_skips_a_shebang_on_the_first_line_only() {
+        let tokens = generate("#!/usr/bin/env crustacean\nx");
+
+        assert_eq!(tokens, vec![Ok((Token::Identifier("x".to_owned()), Span { start: 26, end: 27, line: 2, column: 1 }))]);
+        assert!(generate("x\n#!/bin/sh").iter().any(Result::is_err));
+    }
+
+    #[test]
+    fn it_records_the_span_of_each_token() {
+        let spans: Vec<Span> = generate("\"é\" x\n  y").into_iter().map(|t| t.unwrap().1).collect();
+
+        assert_eq!(spans, vec![
+            Span { start: 0, end: 4, line: 1, column: 1 },
+            Span { start: 5, end: 6, line: 1, column: 5 },
+            Span { start: 9, end: 10, line: 2, column: 3 },
+        ]);
+    }
+}